@@ -1,12 +1,15 @@
-use std::env::Args;
-
-use crate::{compiler, vm};
+use crate::{compiler, coverage, debugger, devices, machine, snapshot, testing, trace, vm};
 
 use std::io::prelude::*;
 use std::fs::OpenOptions;
 use compiler::Program;
 use compiler::lexer;
 use compiler::parser;
+use compiler::cfg;
+use compiler::lint;
+use compiler::stack;
+use compiler::optimizer;
+use compiler::isa::Isa;
 use std::path;
 use std::fs;
 
@@ -23,6 +26,27 @@ pub enum CommandType{
     Strict,              // strict mode to not allow registers are labels and also becomes case sensitive
     Analyze,
     Interrupt,
+    Isa,                // load an isa extension file
+    Run,                // run a ttpasm file or an assembled image without writing anything
+    Registers,          // initial register values for run
+    Ram,                // initial ram contents for run
+    Debug,              // step through a program in the interactive debugger
+    Memory,             // print a memory dump and/or diff after a run
+    Devices,            // map io devices into ram
+    TraceFile,          // write a machine readable trace to a file
+    TraceDiff,          // compare the run against a reference trace
+    Budget,             // instruction budget before a run is considered hung
+    Profile,            // print execution counts after a run
+    Coverage,           // record and merge coverage into a file
+    SaveState,          // write the vm state to a file after the run
+    LoadState,          // start the run from a saved vm state
+    FlagProfile,        // which flags the alu operations update
+    Test,               // run the test cases declared in ttpasm files
+    Set,                // set registers, flags and ram before the run
+    Cfg,                // write the control flow graph as graphviz dot
+    Stack,              // print the static stack depth of the program
+    CodeGuard,          // warn or stop when the program writes over its code
+    Optimize,           // remove instructions that do nothing before encoding
 }
 
 
@@ -40,6 +64,27 @@ impl CommandType{
             "-s" | "--strict"   =>{Some(CommandType::Strict)}
             "-a" | "--analyze"  =>{Some(CommandType::Analyze)}
             "-i" | "--interrupt"=>{Some(CommandType::Interrupt)}
+            "-x" | "--isa"      =>{Some(CommandType::Isa)}
+            "-r" | "--run"      =>{Some(CommandType::Run)}
+            "-g" | "--registers"=>{Some(CommandType::Registers)}
+            "-m" | "--ram"      =>{Some(CommandType::Ram)}
+            "-D" | "--debug"    =>{Some(CommandType::Debug)}
+            "-M" | "--memory"   =>{Some(CommandType::Memory)}
+            "-e" | "--devices"  =>{Some(CommandType::Devices)}
+            "-T" | "--trace"    =>{Some(CommandType::TraceFile)}
+            "-R" | "--trace-diff"=>{Some(CommandType::TraceDiff)}
+            "-B" | "--budget"   =>{Some(CommandType::Budget)}
+            "-P" | "--profile"  =>{Some(CommandType::Profile)}
+            "-C" | "--coverage" =>{Some(CommandType::Coverage)}
+            "-S" | "--save-state"=>{Some(CommandType::SaveState)}
+            "-L" | "--load-state"=>{Some(CommandType::LoadState)}
+            "-F" | "--flags"    =>{Some(CommandType::FlagProfile)}
+            "-u" | "--test"     =>{Some(CommandType::Test)}
+            "-I" | "--set"      =>{Some(CommandType::Set)}
+            "-G" | "--cfg"      =>{Some(CommandType::Cfg)}
+            "-K" | "--stack"    =>{Some(CommandType::Stack)}
+            "-W" | "--code-writes"=>{Some(CommandType::CodeGuard)}
+            "-O" | "--optimize" =>{Some(CommandType::Optimize)}
             _=>{
                 if !require_prefix{
                     CommandType::get_type_without_prefix(command)
@@ -57,6 +102,24 @@ impl CommandType{
             CommandType::Dump      |
             CommandType::Tree      |
             CommandType::Interrupt |
+            CommandType::Isa       |
+            CommandType::Registers |
+            CommandType::Ram       |
+            CommandType::Memory    |
+            CommandType::Devices   |
+            CommandType::TraceFile |
+            CommandType::TraceDiff |
+            CommandType::Budget    |
+            CommandType::Profile   |
+            CommandType::Coverage  |
+            CommandType::SaveState |
+            CommandType::LoadState |
+            CommandType::FlagProfile |
+            CommandType::Set       |
+            CommandType::Cfg       |
+            CommandType::Stack     |
+            CommandType::CodeGuard |
+            CommandType::Optimize  |
             CommandType::Strict => {40}
             _=>{100}
         }
@@ -68,8 +131,11 @@ impl CommandType{
             CommandType::Binary     |
             CommandType::Dump       |
             CommandType::Strict     |
+            CommandType::Stack      |
+            CommandType::Optimize   |
             CommandType::Tree        =>{0},
             CommandType::Analyze    |
+            CommandType::Profile    |
             CommandType::Help        =>{-1} //variable size
             _=>{1}
         }
@@ -78,13 +144,33 @@ impl CommandType{
     /// get the dependencies for each command
     /// for example -output depends on the compile command
     /// but compile does not depend on output
+    ///
+    /// at least one of the dependencies must be present
     pub fn get_dependencies(&self)->Option<&[CommandType]>{
         match self{
             CommandType::Output | CommandType::Binary |
-            CommandType::Tree   | CommandType::Dump   |
+            CommandType::Tree   | CommandType::Dump  => {Some(&[CommandType::Compile])},
             CommandType::Analyze|
-            CommandType::Strict => {Some(&[CommandType::Compile])},
-            CommandType::Interrupt =>{Some(&[CommandType::Analyze])}
+            CommandType::Isa    |
+            CommandType::Optimize |
+            CommandType::Strict => {Some(&[CommandType::Compile,CommandType::Run,CommandType::Debug,CommandType::Test])},
+            CommandType::Registers |
+            CommandType::Ram    => {Some(&[CommandType::Run,CommandType::Debug])},
+            CommandType::TraceDiff |
+            CommandType::SaveState |
+            CommandType::Coverage => {Some(&[CommandType::Run])},
+            CommandType::Stack   |
+            CommandType::Cfg     => {Some(&[CommandType::Compile,CommandType::Run])},
+            CommandType::LoadState => {Some(&[CommandType::Run,CommandType::Debug])},
+            CommandType::FlagProfile => {Some(&[CommandType::Analyze,CommandType::Run,CommandType::Debug,CommandType::Test])},
+            CommandType::Set     |
+            CommandType::CodeGuard |
+            CommandType::Devices => {Some(&[CommandType::Analyze,CommandType::Run,CommandType::Debug])},
+            CommandType::Budget => {Some(&[CommandType::Analyze,CommandType::Run,CommandType::Test])},
+            CommandType::Interrupt |
+            CommandType::TraceFile |
+            CommandType::Profile   |
+            CommandType::Memory    =>{Some(&[CommandType::Analyze,CommandType::Run])}
             _=>{None}
         }
     }
@@ -102,6 +188,27 @@ impl CommandType{
             "s" | "strict"     =>{Some(CommandType::Strict)},
             "a" | "analyze"     =>{Some(CommandType::Analyze)},
             "i" | "interrupt"     =>{Some(CommandType::Interrupt)},
+            "x" | "isa"     =>{Some(CommandType::Isa)},
+            "r" | "run"     =>{Some(CommandType::Run)},
+            "g" | "registers"     =>{Some(CommandType::Registers)},
+            "m" | "ram"     =>{Some(CommandType::Ram)},
+            "D" | "debug"     =>{Some(CommandType::Debug)},
+            "M" | "memory"     =>{Some(CommandType::Memory)},
+            "e" | "devices"     =>{Some(CommandType::Devices)},
+            "T" | "trace"     =>{Some(CommandType::TraceFile)},
+            "R" | "trace-diff"     =>{Some(CommandType::TraceDiff)},
+            "B" | "budget"     =>{Some(CommandType::Budget)},
+            "P" | "profile"     =>{Some(CommandType::Profile)},
+            "C" | "coverage"     =>{Some(CommandType::Coverage)},
+            "S" | "save-state"     =>{Some(CommandType::SaveState)},
+            "L" | "load-state"     =>{Some(CommandType::LoadState)},
+            "F" | "flags"     =>{Some(CommandType::FlagProfile)},
+            "u" | "test"     =>{Some(CommandType::Test)},
+            "I" | "set"     =>{Some(CommandType::Set)},
+            "G" | "cfg"     =>{Some(CommandType::Cfg)},
+            "K" | "stack"     =>{Some(CommandType::Stack)},
+            "W" | "code-writes"     =>{Some(CommandType::CodeGuard)},
+            "O" | "optimize"     =>{Some(CommandType::Optimize)},
             _=>{None}
         }
     }
@@ -118,6 +225,27 @@ impl CommandType{
         println!("{}\n",CommandType::Strict.get_help_string());
         println!("{}\n",CommandType::Analyze.get_help_string());
         println!("{}\n",CommandType::Interrupt.get_help_string());
+        println!("{}\n",CommandType::Isa.get_help_string());
+        println!("{}\n",CommandType::Run.get_help_string());
+        println!("{}\n",CommandType::Registers.get_help_string());
+        println!("{}\n",CommandType::Ram.get_help_string());
+        println!("{}\n",CommandType::Debug.get_help_string());
+        println!("{}\n",CommandType::Memory.get_help_string());
+        println!("{}\n",CommandType::Devices.get_help_string());
+        println!("{}\n",CommandType::TraceFile.get_help_string());
+        println!("{}\n",CommandType::TraceDiff.get_help_string());
+        println!("{}\n",CommandType::Budget.get_help_string());
+        println!("{}\n",CommandType::Profile.get_help_string());
+        println!("{}\n",CommandType::Coverage.get_help_string());
+        println!("{}\n",CommandType::SaveState.get_help_string());
+        println!("{}\n",CommandType::LoadState.get_help_string());
+        println!("{}\n",CommandType::FlagProfile.get_help_string());
+        println!("{}\n",CommandType::Test.get_help_string());
+        println!("{}\n",CommandType::Set.get_help_string());
+        println!("{}\n",CommandType::Cfg.get_help_string());
+        println!("{}\n",CommandType::Stack.get_help_string());
+        println!("{}\n",CommandType::CodeGuard.get_help_string());
+        println!("{}\n",CommandType::Optimize.get_help_string());
     }
 
     /// get a formated help string for the CommandType
@@ -133,6 +261,27 @@ impl CommandType{
             CommandType::Strict  =>{format!("{:<25} {}\n{:<25}{}","[-s | --strict]", "Strict flag | no register identifiers as labels and",""," everything is case sensitive.")},
             CommandType::Analyze =>{format!("{:<25} {}\n{:<25}{}","[-a | --analyze] <flags>", "Run trace analysis on the compiled program.","","<-a 1> to enable formatting or <-a 2> to add flag colors. \n\t\t\tFLAGS:[X|X|X|X|X|X|COLOR_FLAGS|ENABLE]")},
             CommandType::Interrupt =>{format!("{:<25} {}\n{:<25}{}","[-i | --interrupt]<count>", "Interrupts an analysis after <count>",""," instruction.")},
+            CommandType::Isa     =>{format!("{:<25} {}\n{:<25}{}","[-x | --isa] <file>", "Load extra instructions from an isa extension file",""," for the assembler and the virtual machine.")},
+            CommandType::Run     =>{format!("{:<25} {}\n{:<25}{}","[run | -r | --run] <file>", "Run a ttpasm file or an assembled image (logisim or",""," binary) in the virtual machine without writing any file.")},
+            CommandType::Registers =>{format!("{:<25} {}","[-g | --registers] <a=1,..>", "Initial register values for the run, a short --set. ex: a=5,d=0xff")},
+            CommandType::Ram     =>{format!("{:<25} {}\n{:<25}{}","[-m | --ram] <file>", "Initial ram contents for the run from an image",""," file. The program is loaded on top of it.")},
+            CommandType::Debug   =>{format!("{:<25} {}\n{:<25}{}","[debug | -D | --debug] <file>", "Step through a ttpasm file or an assembled image",""," in the interactive debugger.")},
+            CommandType::Memory  =>{format!("{:<25} {}\n{:<25}{}","[-M | --memory] <view>", "Print the ram after the run. <dump> for a hexdump,",""," <diff> for the bytes changed since load or <both>.")},
+            CommandType::Devices =>{format!("{:<25} {}\n{:<25}{}","[-e | --devices] <out=255,..>", "Map io devices to ram addresses. devices: out (console",""," output), in (console input), random and cycles (instruction counter).")},
+            CommandType::TraceFile =>{format!("{:<25} {}\n{:<25}{}","[-T | --trace] <file>", "Write a trace entry per instruction to the file. CSV",""," if the file ends in .csv else JSON lines.")},
+            CommandType::TraceDiff =>{format!("{:<25} {}\n{:<25}{}","[-R | --trace-diff] <file>", "Compare the run with a reference log (ex: logisim) of",""," pc, registers and flags and report the first difference.\n                          or: trace-diff <program> <log>")},
+            CommandType::Budget  =>{format!("{:<25} {}\n{:<25}{}","[-B | --budget] <count>", "Give up on a run that has not halted after <count>",""," instructions. 0 for no limit. default 1000000 for -r and test,\n                          no limit for -c -a.")},
+            CommandType::Profile =>{format!("{:<25} {}\n{:<25}{}","[-P | --profile] <count>", "Print instruction counts per label, the <count> (default",""," 10) hottest instructions and loop repeats after the run.")},
+            CommandType::Coverage =>{format!("{:<25} {}\n{:<25}{}","[-C | --coverage] <file>", "Add the coverage of the run to the file and print the",""," source annotated with the coverage of all runs so far.")},
+            CommandType::SaveState =>{format!("{:<25} {}\n{:<25}{}","[-S | --save-state] <file>", "Save the complete vm state (pc, registers, flags, ram,",""," halt, instruction count and mode) after the run.")},
+            CommandType::LoadState =>{format!("{:<25} {}\n{:<25}{}","[-L | --load-state] <file>", "Start from a saved vm state instead of a fresh vm.",""," The program still provides the labels.")},
+            CommandType::FlagProfile =>{format!("{:<25} {}\n{:<25}{}","[-F | --flags] <profile>", "Which flags the alu operations update. <manual> (default)",""," follows the assembler manual, <logisim-2021> the 2021 circuit.")},
+            CommandType::Set     =>{format!("{:<25} {}\n{:<25}{}","[-I | --set] <a=5 ..>", "Set registers, flags, pc or ram before the run. ex: zero=1",""," ram[0x80..0x84]=1,2,3,4 ram[list+1]=7 or @file to read them from a file.")},
+            CommandType::Cfg     =>{format!("{:<25} {}\n{:<25}{}","[-G | --cfg] <file>", "Write the control flow graph of the program as graphviz",""," dot to the file, - for the console. ex: dot -Tpng")},
+            CommandType::Stack   =>{format!("{:<25} {}\n{:<25}{}","[-K | --stack]", "Print how deep the stack (register D) can grow without",""," running the program.")},
+            CommandType::CodeGuard =>{format!("{:<25} {}\n{:<25}{}","[-W | --code-writes] <mode>", "What to do when the program writes over its instructions",""," or runs a byte it wrote: <off>, <warn> (default) or <error>.")},
+            CommandType::Optimize =>{format!("{:<25} {}\n{:<25}{}","[-O | --optimize]", "Remove instructions that do nothing (cpr a,a, jumps to",""," the next line, ..) before the program is assembled.")},
+            CommandType::Test    =>{format!("{:<25} {}\n{:<25}{}","[test | -u | --test] <path>", "Run the //! input and //! expect cases of a ttpasm file",""," or of every ttpasm file in a directory. Fails if any case fails.")},
         }
     }
 
@@ -170,6 +319,7 @@ pub fn handle_commands(commands : &[Command])->Result<(),String>{
     // }
 
     let mut lexer = lexer::Lexer::create();
+    let mut isa = Isa::create();
     let mut parser : Option<parser::Parser> = None;
     let mut program : Option<Program> = None;
     let mut output : Option<path::PathBuf> = None;
@@ -181,6 +331,26 @@ pub fn handle_commands(commands : &[Command])->Result<(),String>{
     let mut analyze_mode : u8 = 0;
     let mut interrupt_analysis : bool  = false;
     let mut interrupt_after : isize = -1;
+    let mut run_path : Option<path::PathBuf> = None;
+    let mut debug_path : Option<path::PathBuf> = None;
+    let mut initial_ram : Option<Program> = None;
+    let mut memory_view : Option<MemoryView> = None;
+    let mut device_map : Vec<(String,u8)> = Vec::new();
+    let mut trace_file : Option<path::PathBuf> = None;
+    let mut trace_reference : Option<trace::ReferenceTrace> = None;
+    let mut budget : Option<usize> = Some(vm::DEFAULT_BUDGET);
+    let mut budget_given : bool = false;
+    let mut profile : Option<usize> = None;
+    let mut coverage_file : Option<path::PathBuf> = None;
+    let mut save_state : Option<path::PathBuf> = None;
+    let mut initial_state : Option<snapshot::Snapshot> = None;
+    let mut flag_profile = vm::FlagProfile::MANUAL;
+    let mut test_path : Option<path::PathBuf> = None;
+    let mut assignments : Vec<String> = Vec::new();
+    let mut cfg_file : Option<path::PathBuf> = None;
+    let mut show_stack : bool = false;
+    let mut code_guard = vm::CodeGuard::Warn;
+    let mut optimize : bool = false;
 
     while next_command != None{
 
@@ -188,10 +358,8 @@ pub fn handle_commands(commands : &[Command])->Result<(),String>{
 
         // check dependencies
         if let Some(dependencies) = command.command_type.get_dependencies(){
-            for dep in dependencies{
-                if let None = find_command(*dep,commands){
-                    return Err(format!("Dependency missing for [{:?}] command. required:{:?}.",command.command_type,dep));
-                }
+            if !dependencies.iter().any(|dep| find_command(*dep,commands).is_some()){
+                return Err(format!("Dependency missing for [{:?}] command. required one of:{:?}.",command.command_type,dependencies));
             }
         }
 
@@ -209,9 +377,11 @@ pub fn handle_commands(commands : &[Command])->Result<(),String>{
                         return Err(format!("Unable to read file."));
                     }
 
+                    lexer.set_isa(isa.clone());
                     let tokens = lexer.tokenize(strict,source.as_str())?;
 
                     let mut inner_parser = parser::Parser::create(tokens);
+                    inner_parser.set_isa(isa.clone());
 
 
                     let root = inner_parser.generate();
@@ -226,7 +396,11 @@ pub fn handle_commands(commands : &[Command])->Result<(),String>{
                     }
 
 
-                    let inner_program = compiler::Compiler::compile(strict,root.unwrap());
+                    if optimize{
+                        optimize_statements(strict,&isa,&mut inner_parser.root)?;
+                    }
+
+                    let inner_program = compiler::Compiler::compile(strict,&isa,&inner_parser.root);
                     if let Err(some) = inner_program{
                         if dump_tokens {
                             println!("Tokens:\n");
@@ -322,6 +496,108 @@ pub fn handle_commands(commands : &[Command])->Result<(),String>{
                 // apply strict rules
                 strict = true;
             },
+            CommandType::Run | CommandType::Debug =>{
+                // the run happens after all the options have been read
+                let in_path = path::PathBuf::from(command.arg.as_ref().unwrap());
+                if !in_path.is_file(){
+                    return Err(format!("{} is not a valid file.",command.arg.as_ref().unwrap()))
+                }
+                if command.command_type == CommandType::Run{
+                    run_path = Some(in_path);
+                }else{
+                    debug_path = Some(in_path);
+                }
+            },
+            CommandType::Set =>{
+                // labels are only known once the program is compiled so keep the text
+                let arg = command.arg.as_ref().unwrap();
+                match arg.strip_prefix('@'){
+                    Some(file) =>{
+                        let source = swap_e(fs::read_to_string(file))?;
+                        assignments.extend(source.lines().filter(|l| !l.trim_start().starts_with('#')).map(String::from));
+                    },
+                    None => assignments.push(arg.clone())
+                }
+            },
+            CommandType::Stack =>{
+                show_stack = true;
+            },
+            CommandType::Optimize =>{
+                optimize = true;
+            },
+            CommandType::CodeGuard =>{
+                let arg = command.arg.as_ref().unwrap();
+                code_guard = vm::CodeGuard::from_name(arg).ok_or(format!("[{}] is not a code write mode. use off, warn or error.",arg))?;
+            },
+            CommandType::Cfg =>{
+                cfg_file = Some(path::PathBuf::from(command.arg.as_ref().unwrap()));
+            },
+            CommandType::Test =>{
+                let in_path = path::PathBuf::from(command.arg.as_ref().unwrap());
+                if !in_path.exists(){
+                    return Err(format!("{} is not a valid file or directory.",in_path.display()));
+                }
+                test_path = Some(in_path);
+            },
+            CommandType::FlagProfile =>{
+                let arg = command.arg.as_ref().unwrap();
+                flag_profile = vm::FlagProfile::from_name(arg).ok_or_else(||{
+                    let names : Vec<&str> = vm::FlagProfile::ALL.iter().map(|p| p.name).collect();
+                    format!("[{}] is not a flag profile. use one of: {}",arg,names.join(", "))
+                })?;
+            },
+            CommandType::SaveState =>{
+                save_state = Some(path::PathBuf::from(command.arg.as_ref().unwrap()));
+            },
+            CommandType::LoadState =>{
+                let source = swap_e(fs::read_to_string(command.arg.as_ref().unwrap()))?;
+                initial_state = Some(snapshot::Snapshot::parse(source.as_str())?);
+            },
+            CommandType::Coverage =>{
+                coverage_file = Some(path::PathBuf::from(command.arg.as_ref().unwrap()));
+            },
+            CommandType::Profile =>{
+                profile = Some(match &command.arg{
+                    Some(arg) => arg.parse::<usize>().map_err(|_| format!("[{}] is not a valid number of instructions to list.",arg))?,
+                    None => 10
+                });
+            },
+            CommandType::Budget =>{
+                let arg = command.arg.as_ref().unwrap();
+                let count = arg.parse::<usize>().map_err(|_| format!("[{}] is not a valid instruction budget.",arg))?;
+                budget = if count == 0 {None} else {Some(count)};
+                budget_given = true;
+            },
+            CommandType::TraceDiff =>{
+                let source = swap_e(fs::read_to_string(command.arg.as_ref().unwrap()))?;
+                trace_reference = Some(trace::ReferenceTrace::parse(source.as_str())?);
+            },
+            CommandType::TraceFile =>{
+                trace_file = Some(path::PathBuf::from(command.arg.as_ref().unwrap()));
+            },
+            CommandType::Devices =>{
+                device_map.extend(parse_device_map(command.arg.as_ref().unwrap())?);
+            },
+            CommandType::Memory =>{
+                memory_view = Some(MemoryView::parse(command.arg.as_ref().unwrap())?);
+            },
+            CommandType::Registers =>{
+                // same as --set with commas between the registers, ex: a=5,b=0x10
+                assignments.push(command.arg.as_ref().unwrap().replace(','," "));
+            },
+            CommandType::Ram =>{
+                let bytes = swap_e(fs::read(command.arg.as_ref().unwrap()))?;
+                initial_ram = Some(Program::from_image(&bytes)?);
+            },
+            CommandType::Isa =>{
+                // load the isa extensions before compiling
+                let mut source = String::new();
+                let mut file = swap_e(fs::File::open(command.arg.as_ref().unwrap()))?;
+                if file.read_to_string(&mut source).is_err(){
+                    return Err(String::from("Unable to read isa file."));
+                }
+                isa = Isa::parse(source.as_str())?;
+            },
             CommandType::Analyze =>{
                 analyze = true;
                 if let Some(arg) = &command.arg{
@@ -333,6 +609,26 @@ pub fn handle_commands(commands : &[Command])->Result<(),String>{
         next_command = iter.next();
     }
 
+    let vm_options = VmOptions{
+        isa,
+        mode      : analyze_mode,
+        trace     : analyze,
+        assignments,
+        ram       : initial_ram,
+        devices   : device_map,
+        trace_file,
+        state     : initial_state,
+        flag_profile,
+        code_guard,
+        optimize,
+        // an explicit interrupt count replaces the budget
+        budget    : if interrupt_analysis {None} else {budget}
+    };
+
+    if let Some(path) = test_path{
+        return run_tests(&path,strict,&vm_options);
+    }
+
     if let Some(p) = program{
         let mut options = OpenOptions::new();
         let mut file = swap_e(options.write(true).create(true).truncate(true).append(false).open(output.as_ref().unwrap()))?;
@@ -364,24 +660,29 @@ pub fn handle_commands(commands : &[Command])->Result<(),String>{
             return Err(format!("unable to write to file!"))
         }
 
+        print_warnings(&p,&vm_options);
+        if let Some(path) = &cfg_file{
+            write_cfg(&p,&vm_options.isa,path)?;
+        }
+        if show_stack{
+            println!("{}",stack_report(&p,&vm_options.isa));
+        }
+
         if analyze{
 
-            let vm = vm::VirtualMachine::create();
-            vm.mode.set(analyze_mode);
-            vm.load(&p)?;
-            vm.run(interrupt_analysis,interrupt_after);
+            let vm = vm_options.create_vm(&p)?;
+            // -c -a ran without a limit before there was a budget, only -B sets one here
+            if !budget_given{
+                vm.set_budget(None);
+            }
+            let status = vm.run(interrupt_analysis,interrupt_after);
 
-            println!("\nRegisters[A:{:0>3},B:{:0>3},C:{:0>3},D:{:0>3}] \nFlags[C:{}, L:{}, Z:{}, O:{}, S:{}]",
-                     vm.get_register_data(compiler::Register::A),
-                     vm.get_register_data(compiler::Register::B),
-                     vm.get_register_data(compiler::Register::C),
-                     vm.get_register_data(compiler::Register::D),
-                     vm.flags.carry.get(),
-                     vm.flags.less_than.get(),
-                     vm.flags.zero.get(),
-                     vm.flags.overflow.get(),
-                     vm.flags.sign.get(),
-            );
+            print_vm_state(&vm);
+            print_memory(&vm,memory_view);
+            if let Some(top) = profile{
+                println!("\n{}",vm.profile_report(top));
+            }
+            check_run_status(&status)?;
 
 
         }else{
@@ -391,23 +692,423 @@ pub fn handle_commands(commands : &[Command])->Result<(),String>{
 
     }
 
+    if let Some(in_path) = run_path{
+        let p = load_program(&in_path,strict,vm_options.optimize,&vm_options.isa)?;
+        print_warnings(&p,&vm_options);
+        if let Some(path) = &cfg_file{
+            write_cfg(&p,&vm_options.isa,path)?;
+        }
+        if show_stack{
+            println!("{}",stack_report(&p,&vm_options.isa));
+        }
+        let vm = vm_options.create_vm(&p)?;
+        if let Some(reference) = &trace_reference{
+            return compare_trace(&vm,reference);
+        }
+        let status = vm.run(interrupt_analysis,interrupt_after);
+
+        print_vm_state(&vm);
+        print_memory(&vm,memory_view);
+        if let Some(top) = profile{
+            println!("\n{}",vm.profile_report(top));
+        }
+        if let Some(path) = &coverage_file{
+            println!("\n{}",record_coverage(&vm,&p,&vm_options.isa,&in_path,path)?);
+        }
+        if let Some(path) = &save_state{
+            swap_e(fs::write(path,vm.save_state().serialize()))?;
+        }
+        check_run_status(&status)?;
+    }
+
+    if let Some(in_path) = debug_path{
+        let p = load_program(&in_path,strict,vm_options.optimize,&vm_options.isa)?;
+        print_warnings(&p,&vm_options);
+        let vm = vm_options.create_vm(&p)?;
+        let mut debugger = debugger::Debugger::create(vm,&p,vm_options.isa.clone());
+        debugger.run();
+    }
+
 
     Ok(())
 }
 
-pub fn parse_commands(commands :&mut Args)->Result<Vec<Command>,String>{
+/// settings applied to the vm for run and debug
+struct VmOptions{
+    isa       : Isa,
+    mode      : u8,
+    trace     : bool,
+    assignments : Vec<String>,     // --set text, resolved against the labels of the program
+    ram       : Option<Program>,
+    devices   : Vec<(String,u8)>,
+    trace_file: Option<path::PathBuf>,
+    state     : Option<snapshot::Snapshot>,
+    flag_profile : vm::FlagProfile,
+    code_guard : vm::CodeGuard,
+    optimize  : bool,              // -O for programs compiled from source
+    budget    : Option<usize>
+}
+
+impl VmOptions{
+
+    /// ram addresses that get a value from outside the program
+    fn inputs(&self, program : &Program)->Vec<u8>{
+        if self.state.is_some(){
+            return (0..=255).collect();
+        }
+        let mut inputs : Vec<u8> = self.devices.iter().map(|(_,addr)| *addr).collect();
+        if let Some(ram) = &self.ram{
+            inputs.extend((0..ram.instructions.len().min(256)).map(|addr| addr as u8));
+        }
+        for text in &self.assignments{
+            for (field,_) in snapshot::parse_assignments(text,&program.labels).unwrap_or_default(){
+                if let snapshot::Field::Ram(addr) = field{
+                    inputs.push(addr);
+                }
+            }
+        }
+        inputs
+    }
+
+    /// create a vm with the program and initial state loaded
+    fn create_vm(&self, program : &Program)->Result<vm::VirtualMachine,String>{
+        let vm = vm::VirtualMachine::create();
+        vm.mode.set(self.mode);
+        vm.trace.set(self.trace);
+        vm.set_budget(self.budget);
+        vm.set_flag_profile(self.flag_profile);
+        vm.set_code_guard(self.code_guard);
+        vm.set_isa(self.isa.clone());
+        if let Some(ram) = &self.ram{
+            vm.load(ram)?;
+        }
+        vm.load(program)?;
+        if let Some(state) = &self.state{
+            vm.restore_state(state);
+            // the trace options of this run win over the saved mode
+            if self.trace{
+                vm.mode.set(self.mode);
+            }
+        }
+        for text in &self.assignments{
+            for (field,value) in snapshot::parse_assignments(text,&program.labels)?{
+                vm.set_field(field,value);
+            }
+        }
+        for (name,addr) in &self.devices{
+            vm.map_device(*addr,devices::create(name).unwrap())?;
+        }
+        if let Some(path) = &self.trace_file{
+            let file = swap_e(fs::File::create(path))?;
+            let format = trace::TraceFormat::from_path(path);
+            vm.set_trace_writer(trace::TraceWriter::create(format,Box::new(std::io::BufWriter::new(file)))?);
+        }
+        Ok(vm)
+    }
+}
+
+/// run the test cases of a ttpasm file or of every ttpasm file in a directory
+/// and print a summary, any failing case is an error
+fn run_tests(path : &path::Path, strict : bool, options : &VmOptions)->Result<(),String>{
+    let files = if path.is_dir(){
+        let mut files : Vec<path::PathBuf> = swap_e(fs::read_dir(path))?.filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|p| p.extension().map(|e| e == "ttpasm").unwrap_or(false)).collect();
+        files.sort();
+        files
+    }else{
+        vec![path.to_path_buf()]
+    };
+    if files.is_empty(){
+        return Err(format!("no ttpasm files in {}.",path.display()));
+    }
+
+    let mut passed = 0;
+    let mut failed = 0;
+    for file in &files{
+        println!("{}",file.display());
+        let results = match test_file(file,strict,options){
+            Ok(results) => results,
+            Err(e) =>{
+                println!("  error: {}",e);
+                failed+=1;
+                continue;
+            }
+        };
+        for (number,(case,result)) in results.iter().enumerate(){
+            let name = if case.input.is_empty() {format!("case {}",number + 1)} else {format!("case {} ({})",number + 1,case.input)};
+            if result.passed(){
+                passed+=1;
+                println!("  {} ok, {} steps",name,result.steps);
+            }else{
+                failed+=1;
+                println!("  {} FAILED, {} steps",name,result.steps);
+                for failure in &result.failures{
+                    println!("      {}",failure);
+                }
+            }
+        }
+    }
+
+    println!("\nTEST RESULT: {} passed, {} failed",passed,failed);
+    if failed > 0{
+        Err(format!("{} of {} test cases failed.",failed,passed + failed))
+    }else{
+        Ok(())
+    }
+}
+
+/// the test cases of one file from its //! comments and its .expect sidecar file
+fn test_file(file : &path::Path, strict : bool, options : &VmOptions)->Result<Vec<(testing::TestCase,testing::CaseResult)>,String>{
+    let program = load_program(file,strict,options.optimize,&options.isa)?;
+    let mut cases = Vec::new();
+    if !program.lines.is_empty(){
+        cases.extend(testing::TestCase::parse(swap_e(fs::read_to_string(file))?.as_str(),true,&program.labels)?);
+    }
+    let sidecar = file.with_extension("expect");
+    if sidecar.is_file(){
+        let source = swap_e(fs::read_to_string(&sidecar))?;
+        cases.extend(testing::TestCase::parse(source.as_str(),false,&program.labels).map_err(|e| format!("{} in {}",e,sidecar.display()))?);
+    }
+    if cases.is_empty(){
+        return Err(format!("no test cases. add {} expect lines or a {} file.",testing::DIRECTIVE,sidecar.display()));
+    }
+
+    let mut machine = machine::Machine::create();
+    machine.set_isa(options.isa.clone());
+    machine.set_flag_profile(options.flag_profile);
+    machine.load(&program)?;
+    Ok(cases.into_iter().map(|case|{
+        let result = case.run(&machine,options.budget);
+        (case,result)
+    }).collect())
+}
+
+/// merge the coverage of the run into the coverage file
+/// @return the annotated source, or disassembly for images
+fn record_coverage(vm : &vm::VirtualMachine, program : &Program, isa : &Isa, source_path : &path::Path, path : &path::Path)->Result<String,String>{
+    let mut total = vm.get_coverage();
+    if path.is_file(){
+        let previous = coverage::Coverage::parse(swap_e(fs::read_to_string(path))?.as_str())?;
+        total.merge(&previous).map_err(|e| format!("{} delete {} to start over.",e,path.display()))?;
+    }
+    swap_e(fs::write(path,total.serialize()))?;
+
+    if program.lines.is_empty(){
+        Ok(total.disassembly(isa,program))
+    }else{
+        let source = swap_e(fs::read_to_string(source_path))?;
+        Ok(total.listing(isa,source.as_str(),&program.lines))
+    }
+}
+
+/// a run that hangs, faults or can not write its trace is an error so scripts see a failing exit code
+fn check_run_status(status : &vm::RunStatus)->Result<(),String>{
+    if status.is_hang() || matches!(status,vm::RunStatus::CodeFault{..} | vm::RunStatus::TraceFailed{..}){
+        Err(format!("{}.",status))
+    }else{
+        Ok(())
+    }
+}
+
+/// step the vm along the reference trace and report the first divergent step
+fn compare_trace(vm : &vm::VirtualMachine, reference : &trace::ReferenceTrace)->Result<(),String>{
+    match reference.diff(vm){
+        trace::DiffResult::Match{steps} =>{
+            let columns : Vec<String> = reference.columns.iter().map(|c| c.name()).collect();
+            println!("traces match for {} steps. compared: {}",steps,columns.join(","));
+            Ok(())
+        },
+        trace::DiffResult::Diverged{step,differences} =>{
+            println!("traces diverge at step {}",step);
+            if let Some(last) = vm.last_trace(){
+                println!("after {:0>3} : PC[{:02X}] {} {}",last.count,last.pc,last.mnemonic,last.operands);
+            }
+            println!("{:<10}{:>10}{:>10}","","expected","actual");
+            for d in &differences{
+                println!("{:<10}{:>10}{:>10}",d.column.name(),format!("{:02X}",d.expected),format!("{:02X}",d.actual));
+            }
+            println!("\nPC[{:02X}]",vm.get_program_counter());
+            print_vm_state(vm);
+            Err(format!("traces diverge at step {}.",step))
+        }
+    }
+}
+
+/// which memory views to print after a run
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum MemoryView{
+    Dump,
+    Diff,
+    Both
+}
+
+impl MemoryView{
+    fn parse(arg : &str)->Result<MemoryView,String>{
+        match arg{
+            "dump" =>{Ok(MemoryView::Dump)},
+            "diff" =>{Ok(MemoryView::Diff)},
+            "both" =>{Ok(MemoryView::Both)},
+            _=>{Err(format!("[{}] is not a valid memory view. expected dump, diff or both.",arg))}
+        }
+    }
+}
+
+/// print the requested memory views of the vm
+fn print_memory(vm : &vm::VirtualMachine, view : Option<MemoryView>){
+    if let Some(view) = view{
+        if view != MemoryView::Diff{
+            println!("\n{}",vm.dump_memory());
+        }
+        if view != MemoryView::Dump{
+            println!("\n{}",vm.diff_memory());
+        }
+    }
+}
+
+/// compile a ttpasm file or read an assembled image
+fn load_program(in_path : &path::Path, strict : bool, optimize : bool, isa : &Isa)->Result<Program,String>{
+    if in_path.extension().map(|e| e == "ttpasm").unwrap_or(false){
+        let source = swap_e(fs::read_to_string(in_path))?;
+        compile_source(strict,optimize,isa,source.as_str())
+    }else{
+        Program::from_image(&swap_e(fs::read(in_path))?)
+    }
+}
+
+/// report likely mistakes in the control flow before the program is used
+fn print_warnings(program : &Program, options : &VmOptions){
+    for warning in lint::check(&options.isa,program,&options.inputs(program)){
+        compiler::warning(warning.to_string());
+    }
+}
+
+/// how deep the stack can grow, ex: Stack: at most 3 bytes (0xFD..0xFF)
+fn stack_report(program : &Program, isa : &Isa)->String{
+    let cfg = cfg::Cfg::build(isa,program);
+    match stack::analyze(isa,program,&cfg).map(|report| report.max_depth){
+        None => String::from("Stack: register D is never used as a stack pointer."),
+        Some(None) => String::from("Stack: no bound, a subroutine is recursive."),
+        Some(Some(0)) => String::from("Stack: never pushed to."),
+        Some(Some(depth)) => format!("Stack: at most {} bytes (0x{:02X}..0xFF)",depth,256 - depth.min(256))
+    }
+}
+
+/// write the control flow graph as dot, a path of - prints it instead
+fn write_cfg(program : &Program, isa : &Isa, path : &path::Path)->Result<(),String>{
+    let dot = cfg::Cfg::build(isa,program).to_dot(isa);
+    if path.as_os_str() == "-"{
+        print!("{}",dot);
+    }else{
+        swap_e(fs::write(path,dot))?;
+    }
+    Ok(())
+}
+
+/// print the registers and flags of the vm after a run
+fn print_vm_state(vm : &vm::VirtualMachine){
+    println!("\nRegisters[A:{:0>3},B:{:0>3},C:{:0>3},D:{:0>3}] \nFlags[C:{}, L:{}, Z:{}, O:{}, S:{}]",
+             vm.get_register_data(compiler::Register::A),
+             vm.get_register_data(compiler::Register::B),
+             vm.get_register_data(compiler::Register::C),
+             vm.get_register_data(compiler::Register::D),
+             vm.flags.carry.get(),
+             vm.flags.less_than.get(),
+             vm.flags.zero.get(),
+             vm.flags.overflow.get(),
+             vm.flags.sign.get(),
+    );
+}
+
+/// lex, parse and compile a ttpasm source without writing anything
+fn compile_source(strict : bool, optimize : bool, isa : &Isa, source : &str)->Result<Program,String>{
+    let mut lexer = lexer::Lexer::create();
+    lexer.set_isa(isa.clone());
+    let tokens = lexer.tokenize(strict,source)?;
+
+    let mut parser = parser::Parser::create(tokens);
+    parser.set_isa(isa.clone());
+    parser.generate()?;
+    if optimize{
+        optimize_statements(strict,isa,&mut parser.root)?;
+    }
+
+    compiler::Compiler::compile(strict,isa,&parser.root)
+}
+
+/// run the peephole optimizer and tell what it took out
+fn optimize_statements(strict : bool, isa : &Isa, root : &mut parser::RootNode)->Result<(),String>{
+    let removed = optimizer::optimize(strict,isa,root)?;
+    if !removed.is_empty(){
+        let bytes : usize = removed.iter().map(|r| r.bytes as usize).sum();
+        println!("Optimized: removed {} instructions ({} bytes)",removed.len(),bytes);
+        for removal in &removed{
+            println!("    line:{:<4} {:<12} {}",removal.line,removal.text,removal.reason);
+        }
+    }
+    Ok(())
+}
+
+/// parse device mappings in the form out=0xff,in=254
+pub fn parse_device_map(arg : &str)->Result<Vec<(String,u8)>,String>{
+    let mut map = Vec::new();
+    for assignment in arg.split(',').map(str::trim).filter(|a| !a.is_empty()){
+        let mut parts = assignment.splitn(2,'=');
+        let name  = parts.next().unwrap().trim();
+        let value = parts.next().map(str::trim).unwrap_or("");
+
+        if devices::create(name).is_none(){
+            return Err(format!("[{}] is not a device. use out, in, random or cycles.",name));
+        }
+        let addr = compiler::isa::parse_number(value).filter(|a| (0..256).contains(a))
+            .ok_or(format!("[{}] is not a valid address for device {}.",value,name))?;
+        map.push((String::from(name),addr as u8));
+    }
+    Ok(map)
+}
+
+/// subcommands that read better than a flag, ex: `ttpc run prog.ttpasm`
+/// each stands for the flags listed, every flag takes the next argument in order
+const SUBCOMMANDS : [(&str,&[&str]);4] = [
+    ("test",        &["--test"]),
+    ("run",         &["--run"]),
+    ("debug",       &["--debug"]),
+    ("trace-diff",  &["--run","--trace-diff"]),   // ttpc trace-diff prog.ttpasm logisim.txt
+];
+
+/// replace a leading subcommand with its flags, the rest of the arguments are kept as they are
+pub fn expand_subcommand(args : Vec<String>)->Vec<String>{
+    let flags = match args.first().and_then(|first| SUBCOMMANDS.iter().find(|(name,_)| name == first)){
+        Some((_,flags)) => flags,
+        None => return args
+    };
+    let mut rest = args.into_iter().skip(1).peekable();
+    let mut expanded = Vec::new();
+    for flag in flags.iter(){
+        expanded.push(String::from(*flag));
+        if let Some(arg) = rest.next_if(|arg| CommandType::get_type(arg,true).is_none()){
+            expanded.push(arg);
+        }
+    }
+    expanded.extend(rest);
+    expanded
+}
+
+pub fn parse_commands<I : Iterator<Item=String>>(commands : I)->Result<Vec<Command>,String>{
 
     let mut ret_commands: Vec<Command> = Vec::new();
 
-    let mut current = commands.next();
+    let mut args : Vec<String> = commands.collect();
 
-    if current != None && CommandType::get_type(current.as_ref().unwrap(),true)==None{
+    if !args.is_empty() && CommandType::get_type(&args[0],true).is_none(){
         // this means we probably have an invalid command
         // or we have the program call as the 0th argument
 
-        current = commands.next();
+        args.remove(0);
     }
 
+    let mut commands = expand_subcommand(args).into_iter();
+    let mut current = commands.next();
+
     while current!=None{
         let command_str : String = current.unwrap();
         let mut next = commands.next();
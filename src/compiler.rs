@@ -1,7 +1,17 @@
 pub mod lexer;
 pub mod parser;
+pub mod isa;
+pub mod disassembler;
+pub mod cfg;
+pub mod constants;
+pub mod lint;
+pub mod stack;
+pub mod optimizer;
+pub mod branch;
+pub mod structured;
 
 use parser::*;
+use isa::Isa;
 
 pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
@@ -46,11 +56,27 @@ pub enum Ops{
     Byte,
     //
     Increment, // inc x - increment x register by 1 (no flag change)
-    Decrement  // dec x - decrement x register by 1 (no flag change)
+    Decrement, // dec x - decrement x register by 1 (no flag change)
+
+    Custom{id : u8, bytes : u8, params : u8}, // instruction defined by an isa extension file
+
+    // pseudo-branches, assembled as jmpi/jzi/.. or as jmp/jz/.. when a register already holds the target
+    Branch,         // br label
+    BranchLess,     // bl label
+    BranchOverflow, // bo label
+    BranchSign,     // bs label
+    BranchCarry,    // bc label
+    BranchZero,     // bz label
 
 }
 
 impl Ops{
+    /// jumps that may or may not be taken depending on the flags
+    pub fn is_conditional_jump(&self)->bool{
+        matches!(self,Ops::JumpLessi | Ops::JumpOverflowi | Ops::JumpSigni | Ops::JumpCarryi | Ops::JumpZeroi |
+                      Ops::JumpLess | Ops::JumpOverflow | Ops::JumpSign | Ops::JumpCarry | Ops::JumpZero)
+    }
+
     pub fn get_byte_count(&self)->u8{
         match self {
             Ops::Jumpi |
@@ -60,6 +86,14 @@ impl Ops{
             Ops::JumpCarryi |
             Ops::JumpZeroi |
             Ops::Loadi =>{2},
+            // as long as the immediate jump until the branches are lowered
+            Ops::Branch |
+            Ops::BranchLess |
+            Ops::BranchOverflow |
+            Ops::BranchSign |
+            Ops::BranchCarry |
+            Ops::BranchZero =>{2},
+            Ops::Custom{bytes,..} =>{*bytes},
             _=>{1}
         }
     }
@@ -81,13 +115,62 @@ impl Ops{
             Ops::Not |
             Ops::Jump |
             Ops::JumpCarry |
-            Ops::JumpZero =>{1},
+            Ops::JumpZero |
+            Ops::Branch |
+            Ops::BranchLess |
+            Ops::BranchOverflow |
+            Ops::BranchSign |
+            Ops::BranchCarry |
+            Ops::BranchZero =>{1},
             Ops::NoOp |
             Ops::Halt =>{0},
+            Ops::Custom{params,..} =>{*params as usize},
             _=>{2}
         }
     }
 
+    /// the mnemonic used in ttpasm for this operation
+    /// custom operations are named by their isa extension
+    pub fn get_mnemonic(&self)->&'static str{
+        match self{
+            Ops::NoOp=>{"nop"},
+            Ops::Halt=>{"halt"},
+            Ops::Jumpi=>{"jmpi"},
+            Ops::JumpLessi=>{"jli"},
+            Ops::JumpOverflowi=>{"joi"},
+            Ops::JumpSigni=>{"jsi"},
+            Ops::JumpCarryi=>{"jci"},
+            Ops::JumpZeroi=>{"jzi"},
+            Ops::CopyReg=>{"cpr"},
+            Ops::JumpLess=>{"jl"},
+            Ops::JumpOverflow=>{"jo"},
+            Ops::JumpSign=>{"js"},
+            Ops::Loadi=>{"ldi"},
+            Ops::Load=>{"ld"},
+            Ops::Add=>{"add"},
+            Ops::Sub=>{"sub"},
+            Ops::RightShift=>{"rsh"},
+            Ops::Not=>{"not"},
+            Ops::Jump=>{"jmp"},
+            Ops::JumpCarry=>{"jc"},
+            Ops::JumpZero=>{"jz"},
+            Ops::And=>{"and"},
+            Ops::Or=>{"or"},
+            Ops::Compare=>{"cmp"},
+            Ops::Store=>{"st"},
+            Ops::Increment=>{"inc"},
+            Ops::Decrement=>{"dec"},
+            Ops::Byte=>{"byte"},
+            Ops::Custom{..}=>{""},
+            Ops::Branch=>{"br"},
+            Ops::BranchLess=>{"bl"},
+            Ops::BranchOverflow=>{"bo"},
+            Ops::BranchSign=>{"bs"},
+            Ops::BranchCarry=>{"bc"},
+            Ops::BranchZero=>{"bz"}
+        }
+    }
+
     pub fn get_op(s : &str)->Option<Ops>{
         match s{
             "nop"=>{Some(Ops::NoOp)},
@@ -118,6 +201,12 @@ impl Ops{
             "inc"=>{Some(Ops::Increment)},
             "dec"=>{Some(Ops::Decrement)},
             "byte"=>{Some(Ops::Byte)},
+            "br"=>{Some(Ops::Branch)},
+            "bl"=>{Some(Ops::BranchLess)},
+            "bo"=>{Some(Ops::BranchOverflow)},
+            "bs"=>{Some(Ops::BranchSign)},
+            "bc"=>{Some(Ops::BranchCarry)},
+            "bz"=>{Some(Ops::BranchZero)},
             _=>{
                 None
             }
@@ -184,12 +273,75 @@ struct LabelInfo<'a>{
     expression : Option<&'a Expression>
 }
 
+/// where the bytes of a source line ended up
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct LineInfo{
+    pub line : u32,
+    pub addr : u8,
+    pub size : u8,
+    pub data : bool     // a byte statement rather than an instruction
+}
+
+/// the closest label at or before the address, ex: loop+2, None past the program edge
+/// every address shown to the user is named this way
+pub fn label_at(labels : &[(String,u8)], program_edge : u8, addr : u8)->Option<String>{
+    if addr > program_edge{
+        return None;
+    }
+    labels.iter().filter(|(_,a)| *a <= addr).max_by_key(|(_,a)| *a).map(|(label,a)|{
+        if *a == addr {label.clone()} else {format!("{}+{}",label,addr - a)}
+    })
+}
+
 pub struct Program{
-    pub instructions : Vec<Instruction>
+    pub instructions : Vec<Instruction>,
+    pub labels       : Vec<(String,u8)>,    // address labels without the trailing ':'
+    pub lines        : Vec<LineInfo>        // empty for assembled images
 }
 
 impl Program{
 
+    /// read an assembled image, either a logisim "v2.0 raw" file or plain binary
+    pub fn from_image(bytes : &[u8])->Result<Program,String>{
+        let mut program = Program{instructions:Vec::new(),labels:Vec::new(),lines:Vec::new()};
+
+        if !bytes.starts_with(b"v2.0 raw"){
+            for byte in bytes{
+                program.instructions.push(Instruction::create(Ops::Byte,*byte));
+            }
+            return Ok(program);
+        }
+
+        let text = String::from_utf8_lossy(&bytes[8..]);
+        for word in text.split_whitespace(){
+            // logisim compresses runs of the same value as count*value
+            let (count,value) = match word.find('*'){
+                Some(star) => (word[..star].parse::<usize>().ok(),&word[star+1..]),
+                None => (Some(1),word)
+            };
+            let data = u8::from_str_radix(value,16).ok();
+            if let (Some(count),Some(data)) = (count,data){
+                for _ in 0..count{
+                    program.instructions.push(Instruction::create(Ops::Byte,data));
+                }
+            }else{
+                return Err(format!("Invalid value [{}] in logisim image.",word));
+            }
+        }
+
+        Ok(program)
+    }
+
+    /// the last address of the program
+    pub fn edge(&self)->u8{
+        self.instructions.len().saturating_sub(1) as u8
+    }
+
+    /// the closest label at or before the address within the program, ex: loop+2
+    pub fn label_at(&self, addr : u8)->Option<String>{
+        label_at(&self.labels,self.edge(),addr)
+    }
+
     pub fn dump(&self)->String{
         let mut out = String::new();
 
@@ -208,9 +360,13 @@ impl Program{
 pub struct Compiler{}
 impl Compiler{
 
-    pub fn compile(strict: bool,root: &RootNode)->Result<Program, String>{
+    pub fn compile(strict: bool,isa: &Isa,root: &RootNode)->Result<Program, String>{
+        if root.statements.iter().any(|s| s.statement_type == StatementType::Operation && isa.get_op(s.raw()).map(branch::is_branch).unwrap_or(false)){
+            return branch::relax(strict,isa,&root.statements);
+        }
+
         let mut labels : Vec<LabelInfo> = Vec::new();
-        let mut program = Program{instructions:Vec::new()};
+        let mut program = Program{instructions:Vec::new(),labels:Vec::new(),lines:Vec::new()};
 
         // gather all labels on first pass of parse tree
         Compiler::gather_labels(strict,&root.statements,&mut labels)?;
@@ -218,7 +374,23 @@ impl Compiler{
         // go through all statements and convert them to instructions in second pass
         for statement in &root.statements{
             // compile the satement but return early if an error occur
-            Compiler::compile_statement(strict,&statement,&mut program, &mut labels)?;
+            let start = program.instructions.len();
+            Compiler::compile_statement(strict,isa,&statement,&mut program, &mut labels)?;
+            if program.instructions.len() > start{
+                program.lines.push(LineInfo{
+                    line : statement.line(),
+                    addr : start as u8,
+                    size : (program.instructions.len() - start) as u8,
+                    data : isa.get_op(statement.raw()) == Some(Ops::Byte)
+                });
+            }
+        }
+
+        // keep the labels that point to an address for debugging
+        for info in &labels{
+            if info.expression.is_none(){
+                program.labels.push((String::from(info.label.trim_end_matches(':')),info.addr));
+            }
         }
         Ok(program)
     }
@@ -226,11 +398,11 @@ impl Compiler{
     /// compile a statement into an instruction if it is an operation
     /// otherwise it is a label and we submit to the labels list
     /// @return an error if unable to compile statement
-    fn compile_statement(strict : bool,statement : &parser::Statement,program : &mut Program, labels : &mut Vec<LabelInfo>)->Result<(),String>{
+    fn compile_statement(strict : bool,isa : &Isa,statement : &parser::Statement,program : &mut Program, labels : &mut Vec<LabelInfo>)->Result<(),String>{
 
         if statement.statement_type == parser::StatementType::Operation{
             //TODO: create a getBitPatten function for operations to lower code reuse
-            let op = Ops::get_op(statement.raw()).unwrap();
+            let op = isa.get_op(statement.raw()).unwrap();
             match op{
                 Ops::NoOp =>{
                     program.instructions.push(Instruction::create(op,0));
@@ -416,6 +588,31 @@ impl Compiler{
 
                     program.instructions.push(Instruction::create(op,op_code));
 
+                },
+                Ops::Custom{..} =>{
+                    let ext = isa.get_extension(op).unwrap();
+                    let mut x = 0;
+                    let mut y = 0;
+                    let mut imm = None;
+
+                    // operands are evaluated in the order they were declared in the isa file
+                    for (operand,expression) in ext.operands.iter().zip(&statement.expressions){
+                        let value = Compiler::evaluate_expression(strict,statement.byte_addr,expression,labels)?;
+                        match operand{
+                            isa::Var::X => {x = value;},
+                            isa::Var::Y => {y = value;},
+                            _=> {imm = Some(value);}
+                        }
+                    }
+
+                    program.instructions.push(Instruction::create(op,ext.encode(x,y)));
+                    if let Some(value) = imm{
+                        program.instructions.push(Instruction::create(Ops::Byte,value));
+                    }
+                },
+                Ops::Branch | Ops::BranchLess | Ops::BranchOverflow | Ops::BranchSign | Ops::BranchCarry | Ops::BranchZero =>{
+                    // lowered by branch::relax before the statements get here
+                    return Err(format!("pseudo-branch [{}] at line:{} was not lowered to a jump.",statement.raw(),statement.line()));
                 }
             }
         }
@@ -535,6 +732,6 @@ pub fn error(message:String){
     eprintln!("Error: \n{}",message);
 }
 
-// pub fn warning(message:String){
-//     println!("Warning: \n{}",message);
-// }
+pub fn warning(message:String){
+    eprintln!("Warning: \n{}",message);
+}
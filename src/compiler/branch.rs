@@ -0,0 +1,111 @@
+use super::{Compiler, Ops, Program, Register};
+use super::cfg::Cfg;
+use super::constants::Registers;
+use super::isa::Isa;
+use super::parser::{self, DebugInfo, ExpressionType, RootNode, Statement, StatementType};
+
+const REGISTERS : [Register;4] = [Register::A,Register::B,Register::C,Register::D];
+
+/// the jumps a pseudo-branch can become, to an address or through a register
+fn jumps(op : Ops)->Option<(Ops,Ops)>{
+    match op{
+        Ops::Branch         => Some((Ops::Jumpi,Ops::Jump)),
+        Ops::BranchLess     => Some((Ops::JumpLessi,Ops::JumpLess)),
+        Ops::BranchOverflow => Some((Ops::JumpOverflowi,Ops::JumpOverflow)),
+        Ops::BranchSign     => Some((Ops::JumpSigni,Ops::JumpSign)),
+        Ops::BranchCarry    => Some((Ops::JumpCarryi,Ops::JumpCarry)),
+        Ops::BranchZero     => Some((Ops::JumpZeroi,Ops::JumpZero)),
+        _=> None
+    }
+}
+
+pub fn is_branch(op : Ops)->bool{
+    jumps(op).is_some()
+}
+
+/// turn the pseudo-branches into jumps and assemble the program
+/// every branch starts as the 2 byte immediate jump and becomes the 1 byte register jump
+/// when the constants of the cfg show a register holding the target on every path,
+/// nothing is known where an unknown jump can land so a branch there keeps the immediate jump
+/// shrinking a branch moves the code after it so the layout is redone until nothing changes,
+/// a branch whose register stops matching goes back to the immediate jump for good so this ends
+pub fn relax(strict : bool, isa : &Isa, statements : &[Statement])->Result<Program,String>{
+    let branches : Vec<usize> = statements.iter().enumerate()
+        .filter(|(_,s)| s.statement_type == StatementType::Operation && isa.get_op(s.raw()).map(is_branch).unwrap_or(false))
+        .map(|(i,_)| i)
+        .collect();
+    for statement in branches.iter().map(|i| &statements[*i]){
+        if statement.expressions[0].expression_type == ExpressionType::Register{
+            let (_,indirect) = jumps(isa.get_op(statement.raw()).unwrap()).unwrap();
+            return Err(format!("[{}] at line:{} branches to an address, use {} to jump through a register.",statement.raw(),statement.line(),indirect.get_mnemonic()));
+        }
+    }
+
+    let mut through : Vec<Option<Register>> = vec![None;branches.len()];
+    let mut pinned = vec![false;branches.len()];
+    loop{
+        let root = RootNode{statements:lower(isa,statements,&branches,&through)};
+        let program = Compiler::compile(strict,isa,&root)?;
+        let cfg = Cfg::build(isa,&program);
+        let mut labels = Vec::new();
+        Compiler::gather_labels(strict,&root.statements,&mut labels)?;
+
+        let mut changed = false;
+        for (n,index) in branches.iter().enumerate(){
+            let addr = root.statements[*index].byte_addr;
+            let target = Compiler::evaluate_expression(strict,addr,&statements[*index].expressions[0],&labels)?;
+            let registers = registers_at(isa,&cfg,addr);
+            let holds = |register : &Register| registers[register.bits() as usize] == Some(target);
+
+            match through[n]{
+                Some(register) if !holds(&register) =>{
+                    through[n] = None;
+                    pinned[n] = true;
+                    changed = true;
+                },
+                None if !pinned[n] =>{
+                    if let Some(register) = REGISTERS.iter().find(|r| holds(r)){
+                        through[n] = Some(*register);
+                        changed = true;
+                    }
+                },
+                _=>{}
+            }
+        }
+        if !changed{
+            return Ok(program);
+        }
+    }
+}
+
+/// the statements with each branch replaced by the jump chosen for it
+/// @param through the register to jump through for each branch, None for the immediate jump
+fn lower(isa : &Isa, statements : &[Statement], branches : &[usize], through : &[Option<Register>])->Vec<Statement>{
+    let mut lowered = statements.to_vec();
+    for (index,register) in branches.iter().zip(through){
+        let statement = &mut lowered[*index];
+        let (immediate,indirect) = jumps(isa.get_op(statement.raw()).unwrap()).unwrap();
+        match register{
+            Some(register) =>{
+                statement.value = String::from(indirect.get_mnemonic());
+                let expression = &mut statement.expressions[0];
+                expression.expression_type = ExpressionType::Register;
+                expression.value = format!("{:?}",register).to_lowercase();
+                expression.expressions.clear();
+            },
+            None => statement.value = String::from(immediate.get_mnemonic())
+        }
+    }
+    parser::assign_addresses(isa,&mut lowered);
+    lowered
+}
+
+/// registers known before the instruction at the address, nothing if it is never reached
+fn registers_at(isa : &Isa, cfg : &Cfg, addr : u8)->Registers{
+    for block in &cfg.blocks{
+        if let Some(i) = block.instructions.iter().position(|d| d.addr == addr){
+            return cfg.constants.trace(isa,block)[i];
+        }
+    }
+    [None;4]
+}
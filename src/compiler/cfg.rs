@@ -0,0 +1,258 @@
+use super::{label_at, Ops, Program};
+use super::disassembler::{self, Decoded};
+use super::constants::{self, Constants};
+use super::isa::{Isa, Target, Var};
+
+/// how control gets from one block to the next
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum EdgeKind{
+    Next,       // falls through, also a conditional jump that is not taken
+    Jump,       // unconditional jump
+    Taken       // conditional jump that is taken
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Edge{
+    pub target : u8,
+    pub kind   : EdgeKind
+}
+
+/// instructions that always run one after the other
+#[derive(Debug,Clone,PartialEq)]
+pub struct BasicBlock{
+    pub start        : u8,
+    pub instructions : Vec<Decoded>,
+    pub successors   : Vec<Edge>,
+    pub indirect     : bool     // ends in a jump whose target is not known
+}
+
+impl BasicBlock{
+    pub fn last(&self)->&Decoded{
+        self.instructions.last().unwrap()
+    }
+
+    /// address right after the block, 256 if it runs to the end of ram
+    pub fn end(&self)->usize{
+        self.last().addr as usize + self.last().size() as usize
+    }
+
+    pub fn halts(&self)->bool{
+        self.last().op == Ops::Halt
+    }
+}
+
+/// control flow graph of a program loaded at address 0
+pub struct Cfg{
+    pub blocks    : Vec<BasicBlock>,    // in address order, the entry block first
+    pub constants : Constants,          // register values known along the edges
+    labels        : Vec<(String,u8)>,
+    edge          : u8                  // the last address of the program
+}
+
+impl Cfg{
+
+    /// split the program into basic blocks at labels, jump targets and after jumps
+    /// source programs use their instruction lines, images are decoded from the start
+    pub fn build(isa : &Isa, program : &Program)->Cfg{
+        let memory : Vec<u8> = program.instructions.iter().map(|i| i.data).collect();
+        let instructions : Vec<Decoded> = if program.lines.is_empty(){
+            disassembler::decode_all(isa,&memory,memory.len())
+        }else{
+            program.lines.iter().filter(|l| !l.data).map(|l| disassembler::decode(isa,&memory,l.addr)).collect()
+        };
+
+        let mut leaders = [false;256];
+        leaders[0] = true;
+        for (_,addr) in &program.labels{
+            leaders[*addr as usize] = true;
+        }
+        for d in &instructions{
+            if ends_block(isa,d){
+                leaders[d.next_addr() as usize] = true;
+            }
+            if let Some(target) = d.immediate.filter(|_| is_jump(d.op)){
+                leaders[target as usize] = true;
+            }
+        }
+
+        // register jump targets split blocks and add edges, which changes what is
+        // known about the registers at other jumps so repeat until nothing new shows up
+        let mut targets : Vec<(u8,u8)> = Vec::new();
+        let mut blocks = split(isa,&instructions,&leaders,&targets);
+        let mut constants = Constants::empty();
+        for _ in 0..256{
+            constants = Constants::analyze(isa,program,&blocks);
+            let mut found : Vec<(u8,u8)> = Vec::new();
+            for block in &blocks{
+                let before = constants.trace(isa,block);
+                found.extend(block.instructions.iter().zip(before.iter()).filter_map(|(d,r)| constants::jump_target(d,r).map(|t| (d.addr,t))));
+            }
+            if found == targets{
+                break;
+            }
+            for (_,target) in &found{
+                leaders[*target as usize] = true;
+            }
+            targets = found;
+            blocks = split(isa,&instructions,&leaders,&targets);
+        }
+
+        Cfg{blocks,constants,labels:program.labels.clone(),edge:program.edge()}
+    }
+
+    /// the block that starts at the address
+    pub fn block_at(&self, addr : u8)->Option<&BasicBlock>{
+        self.blocks.iter().find(|b| b.start == addr)
+    }
+
+    /// which blocks can be reached from the entry, in the order of blocks
+    /// an unknown jump is taken to go to any block whose address is loaded with
+    /// ldi somewhere reached, ex: a return address kept in a register or the stack
+    pub fn reachable(&self)->Vec<bool>{
+        let mut reached = vec![false;self.blocks.len()];
+        let mut work = vec![0u8];
+        loop{
+            while let Some(addr) = work.pop(){
+                if let Some(index) = self.blocks.iter().position(|b| b.start == addr){
+                    if !reached[index]{
+                        reached[index] = true;
+                        work.extend(self.blocks[index].successors.iter().map(|e| e.target));
+                    }
+                }
+            }
+            let blocks = || self.blocks.iter().zip(reached.iter()).filter(|(_,r)| **r).map(|(b,_)| b);
+            if !blocks().any(|b| b.indirect){
+                break;
+            }
+            work = blocks().flat_map(|b| b.instructions.iter())
+                .filter(|d| d.op == Ops::Loadi)
+                .filter_map(|d| d.immediate)
+                .filter(|addr| self.blocks.iter().zip(reached.iter()).any(|(b,r)| b.start == *addr && !*r))
+                .collect();
+            if work.is_empty(){
+                break;
+            }
+        }
+        reached
+    }
+
+    fn name(&self, addr : u8)->String{
+        label_at(&self.labels,self.edge,addr).unwrap_or(format!("0x{:02X}",addr))
+    }
+
+    /// graphviz dot with a node per block, named by its labels
+    pub fn to_dot(&self, isa : &Isa)->String{
+        let escape = |text : String| text.replace('\\',"\\\\").replace('"',"\\\"");
+        let mut out = String::from("digraph cfg {\n    node [shape=box fontname=\"monospace\"];\n");
+        let mut outside : Vec<u8> = Vec::new();
+
+        for block in &self.blocks{
+            let mut text = format!("{}:\\l",escape(self.name(block.start)));
+            for d in &block.instructions{
+                text.push_str(format!("{:02X}: {}\\l",d.addr,escape(d.format(isa,&self.labels))).as_str());
+            }
+            let style = if block.start == 0 {" style=bold"} else {""};
+            out.push_str(format!("    b{:02X} [label=\"{}\"{}];\n",block.start,text,style).as_str());
+
+            let flag = condition(block.last().op);
+            for edge in &block.successors{
+                if self.block_at(edge.target).is_none() && !outside.contains(&edge.target){
+                    outside.push(edge.target);
+                }
+                let node = if self.block_at(edge.target).is_some() {format!("b{:02X}",edge.target)} else {format!("x{:02X}",edge.target)};
+                let attributes = match (edge.kind,flag){
+                    (EdgeKind::Taken,Some(flag)) => format!(" [label=\"{}\"]",flag),
+                    (EdgeKind::Next,Some(flag)) => format!(" [label=\"!{}\" style=dashed]",flag),
+                    _=> String::new()
+                };
+                out.push_str(format!("    b{:02X} -> {}{};\n",block.start,node,attributes).as_str());
+            }
+            if block.indirect{
+                out.push_str(format!("    b{:02X} -> unknown [style=dotted];\n",block.start).as_str());
+            }
+        }
+
+        for addr in outside{
+            out.push_str(format!("    x{:02X} [label=\"{} (not code)\" shape=note];\n",addr,escape(self.name(addr))).as_str());
+        }
+        if self.blocks.iter().any(|b| b.indirect){
+            out.push_str("    unknown [label=\"?\" shape=circle];\n");
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+fn is_jump(op : Ops)->bool{
+    op == Ops::Jumpi || op == Ops::Jump || op.is_conditional_jump()
+}
+
+/// isa extension that sets the pc in one of its micro-ops
+fn writes_pc(isa : &Isa, d : &Decoded)->bool{
+    isa.get_extension(d.op).map(|ext| ext.micro_ops.iter().any(|m| m.target == Target::Var(Var::Pc))).unwrap_or(false)
+}
+
+/// control does not simply continue with the next instruction
+fn ends_block(isa : &Isa, d : &Decoded)->bool{
+    d.op == Ops::Halt || is_jump(d.op) || writes_pc(isa,d)
+}
+
+/// the flag a conditional jump tests
+fn condition(op : Ops)->Option<&'static str>{
+    match op{
+        Ops::JumpLessi | Ops::JumpLess => Some("less"),
+        Ops::JumpOverflowi | Ops::JumpOverflow => Some("overflow"),
+        Ops::JumpSigni | Ops::JumpSign => Some("sign"),
+        Ops::JumpCarryi | Ops::JumpCarry => Some("carry"),
+        Ops::JumpZeroi | Ops::JumpZero => Some("zero"),
+        _=> None
+    }
+}
+
+/// cut the instructions into blocks at the leaders and link them
+/// @param targets the address of every register jump whose target is known
+fn split(isa : &Isa, instructions : &[Decoded], leaders : &[bool;256], targets : &[(u8,u8)])->Vec<BasicBlock>{
+    let mut blocks : Vec<BasicBlock> = Vec::new();
+    for (i,d) in instructions.iter().enumerate(){
+        let starts_block = match blocks.last(){
+            Some(block) => leaders[d.addr as usize] || ends_block(isa,block.last()) || block.end() != d.addr as usize,
+            None => true
+        };
+        if starts_block{
+            blocks.push(BasicBlock{start:d.addr,instructions:Vec::new(),successors:Vec::new(),indirect:false});
+        }
+        let block = blocks.last_mut().unwrap();
+        block.instructions.push(d.clone());
+
+        let next = Edge{target:d.next_addr(),kind:EdgeKind::Next};
+        let target = targets.iter().find(|(addr,_)| *addr == d.addr).map(|(_,target)| *target);
+        let ends = instructions.get(i + 1).map(|n| leaders[n.addr as usize] || n.addr as usize != block.end()).unwrap_or(true);
+        match d.op{
+            Ops::Halt =>{},
+            Ops::Jumpi => block.successors.push(Edge{target:d.immediate.unwrap(),kind:EdgeKind::Jump}),
+            Ops::Jump =>{
+                match target{
+                    Some(target) => block.successors.push(Edge{target,kind:EdgeKind::Jump}),
+                    None => block.indirect = true
+                }
+            },
+            op if op.is_conditional_jump() =>{
+                match d.immediate.or(target){
+                    Some(target) => block.successors.push(Edge{target,kind:EdgeKind::Taken}),
+                    None => block.indirect = true
+                }
+                block.successors.push(next);
+            },
+            Ops::Custom{..} if writes_pc(isa,d) =>{
+                block.indirect = true;
+                block.successors.push(next);
+            },
+            _=>{
+                if ends{
+                    block.successors.push(next);
+                }
+            }
+        }
+    }
+    blocks
+}
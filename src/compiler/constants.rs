@@ -0,0 +1,207 @@
+use super::{Ops, Program};
+use super::cfg::BasicBlock;
+use super::disassembler::Decoded;
+use super::isa::{Isa, Target};
+
+/// what is known about the registers at one point, None where the value is not a constant
+pub type Registers = [Option<u8>;4];
+
+/// register values that are the same on every path, found by running the program
+/// over the cfg with unknown inputs
+#[derive(Debug,Clone,PartialEq)]
+pub struct Constants{
+    entries : Vec<(u8,Registers)>,      // registers at the start of each reached block
+    memory  : [Option<u8>;256],         // program bytes that are never written
+    stores  : Vec<Option<u8>>           // address written by each reached store, None if not known
+}
+
+impl Constants{
+
+    /// nothing known, used before the blocks exist
+    pub fn empty()->Constants{
+        Constants{entries:Vec::new(),memory:[None;256],stores:Vec::new()}
+    }
+
+    /// @param blocks the blocks of the program with every jump target found so far
+    pub fn analyze(isa : &Isa, program : &Program, blocks : &[BasicBlock])->Constants{
+        // start by assuming every byte of the program keeps its value and drop the
+        // ones a store can write until the stores stop changing
+        let mut memory = [None;256];
+        for (i,instruction) in program.instructions.iter().enumerate().take(256){
+            memory[i] = Some(instruction.data);
+        }
+        loop{
+            let mut constants = Constants{entries:Vec::new(),memory,stores:Vec::new()};
+            constants.propagate(isa,blocks);
+            if constants.stores.iter().any(|s| s.is_none()){
+                memory = [None;256];
+            }
+            for addr in constants.stores.iter().flatten(){
+                memory[*addr as usize] = None;
+            }
+            if memory == constants.memory{
+                return constants;
+            }
+        }
+    }
+
+    fn propagate(&mut self, isa : &Isa, blocks : &[BasicBlock]){
+        let mut work : Vec<(u8,Registers)> = vec![(0,[None;4])];
+        let mut seeded : Vec<u8> = Vec::new();
+        loop{
+            while let Some((addr,registers)) = work.pop(){
+                let block = match blocks.iter().find(|b| b.start == addr){
+                    Some(block) => block,
+                    None => continue
+                };
+                let registers = match self.entries.iter_mut().find(|(a,_)| *a == addr){
+                    Some((_,known)) =>{
+                        let joined = join(known,&registers);
+                        if joined == *known{
+                            continue;
+                        }
+                        *known = joined;
+                        joined
+                    },
+                    None =>{
+                        self.entries.push((addr,registers));
+                        registers
+                    }
+                };
+                let after = block.instructions.iter().fold(registers,|r,d| self.step(isa,d,&r));
+                work.extend(block.successors.iter().map(|e| (e.target,after)));
+            }
+
+            // an unknown jump can go to any address loaded with ldi, same as Cfg::reachable,
+            // and nothing is known there even if the block was already reached another way.
+            // a register jump the cfg has no target for yet is not unknown once its register is
+            let reached = |addr : u8, entries : &[(u8,Registers)]| entries.iter().any(|(a,_)| *a == addr);
+            let unknown = |b : &BasicBlock|{
+                let before = self.trace(isa,b);
+                let last = b.instructions.len() - 1;
+                b.indirect && jump_target(&b.instructions[last],&before[last]).is_none()
+            };
+            if !blocks.iter().any(|b| reached(b.start,&self.entries) && unknown(b)){
+                break;
+            }
+            let mut targets : Vec<u8> = blocks.iter().filter(|b| reached(b.start,&self.entries))
+                .flat_map(|b| b.instructions.iter())
+                .filter(|d| d.op == Ops::Loadi)
+                .filter_map(|d| d.immediate)
+                .filter(|addr| blocks.iter().any(|b| b.start == *addr) && !seeded.contains(addr))
+                .collect();
+            targets.sort_unstable();
+            targets.dedup();
+            if targets.is_empty(){
+                break;
+            }
+            seeded.extend(targets.iter());
+            work = targets.into_iter().map(|addr| (addr,[None;4])).collect();
+        }
+
+        let mut stores = Vec::new();
+        for block in blocks.iter().filter(|b| self.entries.iter().any(|(a,_)| *a == b.start)){
+            let mut registers = self.entry(block.start).unwrap();
+            for d in &block.instructions{
+                match d.op{
+                    Ops::Store => stores.push(registers[d.x.bits() as usize]),
+                    Ops::Custom{..} if isa.get_extension(d.op).map(|e| e.micro_ops.iter().any(|m| matches!(m.target,Target::Ram(_)))).unwrap_or(false) =>{
+                        stores.push(None);
+                    },
+                    _=>{}
+                }
+                registers = self.step(isa,d,&registers);
+            }
+        }
+        self.stores = stores;
+    }
+
+    /// registers at the start of the block, None if the block is never reached
+    pub fn entry(&self, addr : u8)->Option<Registers>{
+        self.entries.iter().find(|(a,_)| *a == addr).map(|(_,r)| *r)
+    }
+
+    /// the value of a byte that no store can change, None for the rest
+    pub fn memory(&self, addr : u8)->Option<u8>{
+        self.memory[addr as usize]
+    }
+
+    /// @return true if a store of the program can write the address
+    pub fn is_written(&self, addr : u8)->bool{
+        self.stores.iter().any(|s| s.map(|s| s == addr).unwrap_or(true))
+    }
+
+    /// registers before each instruction of a block
+    pub fn trace(&self, isa : &Isa, block : &BasicBlock)->Vec<Registers>{
+        let mut registers = match self.entry(block.start){
+            Some(registers) => registers,
+            None => return vec![[None;4];block.instructions.len()]
+        };
+        block.instructions.iter().map(|d|{
+            let before = registers;
+            registers = self.step(isa,d,&registers);
+            before
+        }).collect()
+    }
+
+    /// registers after the instruction
+    pub fn step(&self, isa : &Isa, d : &Decoded, registers : &Registers)->Registers{
+        let mut after = *registers;
+        match d.op{
+            Ops::Load => after[d.x.bits() as usize] = registers[d.y.bits() as usize].and_then(|a| self.memory(a)),
+            // micro-ops can write any register
+            Ops::Custom{..} =>{
+                if isa.get_extension(d.op).map(|e| e.micro_ops.iter().any(|m| !matches!(m.target,Target::Ram(_)))).unwrap_or(false){
+                    after = [None;4];
+                }
+            },
+            _=>{
+                if let Some(value) = fold(d,registers){
+                    after[d.x.bits() as usize] = value;
+                }
+            }
+        }
+        after
+    }
+}
+
+/// keep the values that are the same in both
+fn join(a : &Registers, b : &Registers)->Registers{
+    let mut joined = *a;
+    for (i,value) in joined.iter_mut().enumerate(){
+        if *value != b[i]{
+            *value = None;
+        }
+    }
+    joined
+}
+
+/// the address a register jump goes to, None if it is not a register jump or not known
+pub fn jump_target(d : &Decoded, registers : &Registers)->Option<u8>{
+    match d.op{
+        Ops::Jump | Ops::JumpLess | Ops::JumpOverflow | Ops::JumpSign | Ops::JumpCarry | Ops::JumpZero => registers[d.x.bits() as usize],
+        _=> None
+    }
+}
+
+/// the value a register gets from an instruction when its operands are known
+/// @return None if the register is not written, Some(None) if the value is not known
+pub fn fold(d : &Decoded, registers : &Registers)->Option<Option<u8>>{
+    let x = registers[d.x.bits() as usize];
+    let y = registers[d.y.bits() as usize];
+    let both = |f : fn(u8,u8)->u8| x.and_then(|x| y.map(|y| f(x,y)));
+    match d.op{
+        Ops::Loadi      => Some(d.immediate),
+        Ops::CopyReg    => Some(y),
+        Ops::Increment  => Some(x.map(|x| x.wrapping_add(1))),
+        Ops::Decrement  => Some(x.map(|x| x.wrapping_sub(1))),
+        Ops::Add        => Some(both(u8::wrapping_add)),
+        Ops::Sub        => Some(both(u8::wrapping_sub)),
+        Ops::And        => Some(both(|x,y| x & y)),
+        Ops::Or         => Some(both(|x,y| x | y)),
+        Ops::Not        => Some(x.map(|x| !x)),
+        Ops::RightShift => Some(both(|x,y| x.checked_shr(y as u32).unwrap_or(0))),
+        Ops::Load       => Some(None),
+        _=> None
+    }
+}
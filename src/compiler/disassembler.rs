@@ -0,0 +1,135 @@
+use super::{Ops, Register};
+use super::isa::{Isa, Var};
+
+/// a single instruction decoded from memory
+///
+/// x and y are the register operands in the order they are written
+/// in ttpasm, ex: `ld x,(y)` or `st (x),y`
+#[derive(Debug,Clone,PartialEq)]
+pub struct Decoded{
+    pub addr      : u8,
+    pub op        : Ops,
+    pub opcode    : u8,
+    pub x         : Register,
+    pub y         : Register,
+    pub immediate : Option<u8>
+}
+
+impl Decoded{
+
+    /// number of bytes taken by the instruction
+    pub fn size(&self)->u8{
+        self.op.get_byte_count()
+    }
+
+    /// address of the instruction that follows this one
+    pub fn next_addr(&self)->u8{
+        (self.addr as usize + self.size() as usize) as u8
+    }
+
+    /// format the instruction as ttpasm
+    /// immediate jump targets are replaced with their label if one exists
+    pub fn format(&self, isa : &Isa, labels : &[(String,u8)])->String{
+        let x = format!("{:?}",self.x).to_lowercase();
+        let y = format!("{:?}",self.y).to_lowercase();
+        let imm = self.immediate.unwrap_or(0);
+        let target = labels.iter().find(|(_,a)| *a == imm).map(|(l,_)| l.clone()).unwrap_or(format!("{}",imm));
+        let mnemonic = self.op.get_mnemonic();
+
+        match self.op{
+            Ops::NoOp | Ops::Halt => String::from(mnemonic),
+            Ops::Jumpi | Ops::JumpLessi | Ops::JumpOverflowi |
+            Ops::JumpSigni | Ops::JumpCarryi | Ops::JumpZeroi => format!("{} {}",mnemonic,target),
+            Ops::Loadi => format!("{} {},{}",mnemonic,x,imm),
+            Ops::Load  => format!("{} {},({})",mnemonic,x,y),
+            Ops::Store => format!("{} ({}),{}",mnemonic,x,y),
+            Ops::JumpLess | Ops::JumpOverflow | Ops::JumpSign |
+            Ops::Not | Ops::Jump | Ops::JumpCarry | Ops::JumpZero |
+            Ops::Increment | Ops::Decrement => format!("{} {}",mnemonic,x),
+            Ops::Byte => format!("{} {}",mnemonic,self.opcode),
+            Ops::Custom{..} =>{
+                if let Some(ext) = isa.get_extension(self.op){
+                    let operands : Vec<String> = ext.operands.iter().map(|o| match o{
+                        Var::X => x.clone(),
+                        Var::Y => y.clone(),
+                        _=> format!("{}",imm)
+                    }).collect();
+                    format!("{} {}",ext.mnemonic,operands.join(",")).trim_end().to_string()
+                }else{
+                    format!("byte {}",self.opcode)
+                }
+            },
+            _=> format!("{} {},{}",mnemonic,x,y)
+        }
+    }
+}
+
+/// decode the instruction at the address
+/// bytes that are not an instruction decode as `byte`
+pub fn decode(isa : &Isa, memory : &[u8], addr : u8)->Decoded{
+    let opcode = memory.get(addr as usize).copied().unwrap_or(0);
+    let high = Register::from_bits((opcode & 0b0000_1100) >> 2);
+    let low  = Register::from_bits(opcode & 0b0000_0011);
+
+    let (op,x,y) = match opcode{
+        0b0000_0000 => (Ops::NoOp,high,low),
+        0b0000_0001 => (Ops::Halt,high,low),
+        0b0100_0000 => (Ops::Jumpi,high,low),
+        0b0100_0001 => (Ops::JumpLessi,high,low),
+        0b0100_0010 => (Ops::JumpOverflowi,high,low),
+        0b0100_0011 => (Ops::JumpSigni,high,low),
+        0b0100_0100 => (Ops::JumpCarryi,high,low),
+        0b0100_0101 => (Ops::JumpZeroi,high,low),
+        0b0101_0000..=0b0101_1111 => (Ops::CopyReg,high,low),
+        0b0110_0000..=0b0110_0011 => (Ops::JumpLess,low,low),
+        0b0110_0100..=0b0110_0111 => (Ops::JumpOverflow,low,low),
+        0b0110_1000..=0b0110_1011 => (Ops::JumpSign,low,low),
+        0b0110_1100..=0b0110_1111 => (Ops::Loadi,low,low),
+        0b0111_0000..=0b0111_1111 => (Ops::Load,high,low),
+        0b1000_0000..=0b1000_1111 => (Ops::Add,high,low),
+        0b1001_0000..=0b1001_1111 => (Ops::Sub,high,low),
+        0b1010_0000..=0b1010_1111 => (Ops::RightShift,high,low),
+        0b1011_0000..=0b1011_1111 =>{
+            match opcode & 0b11{
+                0 => (Ops::Not,high,high),
+                1 => (Ops::Jump,high,high),
+                2 => (Ops::JumpCarry,high,high),
+                _ => (Ops::JumpZero,high,high)
+            }
+        },
+        0b1100_0000..=0b1100_1111 => (Ops::And,high,low),
+        0b1101_0000..=0b1101_1111 => if high == low {(Ops::Increment,high,high)} else {(Ops::Or,high,low)},
+        0b1110_0000..=0b1110_1111 => if high == low {(Ops::Decrement,high,high)} else {(Ops::Compare,high,low)},
+        0b1111_0000..=0b1111_1111 => (Ops::Store,low,high),
+        _=>{
+            let mut decoded = (Ops::Byte,high,low);
+            for (id,ext) in isa.extensions.iter().enumerate(){
+                if ext.matches(opcode){
+                    let (x,y) = ext.decode(opcode);
+                    decoded = (ext.get_op(id),x,y);
+                }
+            }
+            decoded
+        }
+    };
+
+    let immediate = if op.get_byte_count() == 2{
+        Some(memory.get(addr as usize + 1).copied().unwrap_or(0))
+    }else{
+        None
+    };
+
+    Decoded{addr,op,opcode,x,y,immediate}
+}
+
+/// decode every instruction from the start of memory up to the end address (exclusive)
+pub fn decode_all(isa : &Isa, memory : &[u8], end : usize)->Vec<Decoded>{
+    let mut decoded = Vec::new();
+    let mut addr = 0usize;
+    while addr < end && addr < 256{
+        let instruction = decode(isa,memory,addr as u8);
+        addr += instruction.size() as usize;
+        decoded.push(instruction);
+    }
+    decoded
+}
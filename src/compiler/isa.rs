@@ -0,0 +1,666 @@
+use std::fmt;
+
+use super::{Ops, Register};
+
+// *******************************
+// ISA extension description
+// *******************************
+//
+// An ISA file adds new mnemonics to the assembler and the virtual machine
+// without having to touch the built-in instruction set. Example:
+//
+//     // mul x,y - x = x * y (updates zero and sign)
+//     instr mul x,y
+//         pattern 0001_xxyy
+//         do x = x * y
+//         do zero = x == 0
+//         do sign = x > 127
+//     end
+//
+//     // addi x,imm - add an immediate byte to x
+//     instr addi x,imm
+//         pattern 0010_00xx
+//         do x = x + imm
+//     end
+//
+// - `instr <mnemonic> <operands>` starts a definition. operands are any of
+//   `x`, `y` (registers) and `imm` (second byte, must be last).
+// - `pattern` is the 8 bit opcode. `0`/`1` are fixed bits, `xx`/`yy` are the
+//   register fields and `_` is ignored.
+// - `do <target> = <expression>` is a micro-op executed in order by the VM.
+//   targets: x, y, a, b, c, d, pc, halt, ram[<expr>], zero, less, overflow,
+//   sign, carry or a temporary name. registers and ram are truncated to 8 bits.
+
+/// bytes already used by the built-in instruction set
+fn is_builtin_opcode(byte : u8)->bool{
+    match byte{
+        0x00 | 0x01 => true,             // nop, halt
+        0x40..=0x45 => true,             // immediate jumps
+        0x50..=0xFF => true,             // everything else
+        _=> false
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Flag{
+    Zero,
+    Less,
+    Overflow,
+    Sign,
+    Carry
+}
+
+impl Flag{
+    /// in the bit order of Flags::pack
+    pub const ALL : [Flag;5] = [Flag::Zero,Flag::Less,Flag::Overflow,Flag::Sign,Flag::Carry];
+
+    pub fn from_name(name : &str)->Option<Flag>{
+        match name{
+            "zero"      => Some(Flag::Zero),
+            "less"      => Some(Flag::Less),
+            "overflow"  => Some(Flag::Overflow),
+            "sign"      => Some(Flag::Sign),
+            "carry"     => Some(Flag::Carry),
+            _=> None
+        }
+    }
+
+    pub fn name(&self)->&'static str{
+        match self{
+            Flag::Zero      => "zero",
+            Flag::Less      => "less",
+            Flag::Overflow  => "overflow",
+            Flag::Sign      => "sign",
+            Flag::Carry     => "carry"
+        }
+    }
+}
+
+/// a value that can be read or written by a micro-op
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Var{
+    X,                      // register selected by the x field
+    Y,                      // register selected by the y field
+    Imm,                    // second byte of the instruction
+    Reg(Register),          // fixed register a-d
+    Flag(Flag),
+    Pc,                     // address of the current instruction
+    Halt,
+    Temp(usize)             // temporary defined by a previous micro-op
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Expr{
+    Number(isize),
+    Var(Var),
+    Ram(Box<Expr>),
+    Unary(char,Box<Expr>),
+    Binary(&'static str,Box<Expr>,Box<Expr>)
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum Target{
+    Var(Var),
+    Ram(Expr)
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct MicroOp{
+    pub target : Target,
+    pub value  : Expr
+}
+
+/// access to machine state needed to evaluate an expression
+pub trait Environment{
+    fn value(&self, var : Var)->isize;
+    fn ram(&self, addr : u8)->u8;
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Extension{
+    pub mnemonic  : String,
+    pub operands  : Vec<Var>,   // X, Y and Imm in assembly order
+    pub opcode    : u8,         // fixed bits of the pattern
+    pub mask      : u8,         // which bits of the pattern are fixed
+    pub x_shift   : Option<u8>,
+    pub y_shift   : Option<u8>,
+    pub micro_ops : Vec<MicroOp>,
+    line          : u32
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct Isa{
+    pub extensions : Vec<Extension>
+}
+
+// *******************************
+// Implementations
+// *******************************
+
+impl Extension{
+
+    pub fn get_op(&self, id : usize)->Ops{
+        Ops::Custom{id : id as u8, bytes : self.get_byte_count(), params : self.operands.len() as u8}
+    }
+
+    pub fn get_byte_count(&self)->u8{
+        if self.operands.contains(&Var::Imm) {2} else {1}
+    }
+
+    /// check if the instruction byte belongs to this extension
+    pub fn matches(&self, instruction : u8)->bool{
+        instruction & self.mask == self.opcode
+    }
+
+    /// build the instruction byte from the operand registers
+    pub fn encode(&self, x : u8, y : u8)->u8{
+        let mut op_code = self.opcode;
+        if let Some(shift) = self.x_shift{
+            op_code |= (x & 0b11) << shift;
+        }
+        if let Some(shift) = self.y_shift{
+            op_code |= (y & 0b11) << shift;
+        }
+        op_code
+    }
+
+    /// get the x and y registers out of an instruction byte
+    pub fn decode(&self, instruction : u8)->(Register,Register){
+        let x = self.x_shift.map(|s| (instruction >> s) & 0b11).unwrap_or(0);
+        let y = self.y_shift.map(|s| (instruction >> s) & 0b11).unwrap_or(0);
+        (Register::from_bits(x),Register::from_bits(y))
+    }
+
+    /// all instruction bytes that decode to this extension
+    fn opcodes(&self)->Vec<u8>{
+        let mut codes = Vec::new();
+        for x in 0..4{
+            for y in 0..4{
+                let code = self.encode(x,y);
+                if !codes.contains(&code){
+                    codes.push(code);
+                }
+            }
+        }
+        codes
+    }
+}
+
+impl Isa{
+
+    /// the built-in instruction set without extensions
+    pub fn create()->Self{
+        Isa{extensions:Vec::new()}
+    }
+
+    /// look up an operation by mnemonic including any extension
+    pub fn get_op(&self, s : &str)->Option<Ops>{
+        if let Some(op) = Ops::get_op(s){
+            return Some(op);
+        }
+        for (id,ext) in self.extensions.iter().enumerate(){
+            if ext.mnemonic == s {
+                return Some(ext.get_op(id));
+            }
+        }
+        None
+    }
+
+    pub fn get_extension(&self, op : Ops)->Option<&Extension>{
+        if let Ops::Custom{id,..} = op{
+            self.extensions.get(id as usize)
+        }else{
+            None
+        }
+    }
+
+    /// find the extension an instruction byte belongs to
+    pub fn decode(&self, instruction : u8)->Option<&Extension>{
+        self.extensions.iter().find(|ext| ext.matches(instruction))
+    }
+
+    /// parse an isa description file
+    pub fn parse(source : &str)->Result<Isa,String>{
+        let mut isa = Isa::create();
+        let mut current : Option<(Extension,Vec<String>)> = None;
+        let mut has_pattern = false;
+
+        for (index,raw_line) in source.split('\n').enumerate(){
+            let line_number = index as u32 + 1;
+            let line = match raw_line.find("//"){
+                Some(start) => &raw_line[..start],
+                None => raw_line
+            }.trim();
+
+            if line.is_empty(){
+                continue;
+            }
+
+            let (keyword,rest) = match line.find(char::is_whitespace){
+                Some(split) => (&line[..split],line[split..].trim()),
+                None => (line,"")
+            };
+
+            match keyword{
+                "instr" =>{
+                    if current.is_some(){
+                        return Err(format!("Missing [end] before new instr at line:{}",line_number));
+                    }
+                    current = Some((Isa::parse_header(rest,line_number)?,Vec::new()));
+                    has_pattern = false;
+                },
+                "pattern" =>{
+                    if let Some((ext,_)) = current.as_mut(){
+                        Isa::parse_pattern(ext,rest,line_number)?;
+                        has_pattern = true;
+                    }else{
+                        return Err(format!("[pattern] outside of instr at line:{}",line_number));
+                    }
+                },
+                "do" =>{
+                    if let Some((ext,temps)) = current.as_mut(){
+                        let op = Isa::parse_micro_op(ext,temps,rest,line_number)?;
+                        ext.micro_ops.push(op);
+                    }else{
+                        return Err(format!("[do] outside of instr at line:{}",line_number));
+                    }
+                },
+                "end" =>{
+                    if let Some((ext,_)) = current.take(){
+                        if !has_pattern{
+                            return Err(format!("Instruction [{}] at line:{} has no pattern.",ext.mnemonic,ext.line));
+                        }
+                        isa.add_extension(ext)?;
+                    }else{
+                        return Err(format!("[end] without instr at line:{}",line_number));
+                    }
+                },
+                _=>{
+                    return Err(format!("Unknown isa keyword [{}] at line:{}",keyword,line_number));
+                }
+            }
+        }
+
+        if let Some((ext,_)) = current{
+            return Err(format!("Instruction [{}] at line:{} is missing [end].",ext.mnemonic,ext.line));
+        }
+
+        Ok(isa)
+    }
+
+    /// add an extension making sure it does not clash with anything already defined
+    fn add_extension(&mut self, ext : Extension)->Result<(),String>{
+        if self.get_op(ext.mnemonic.as_str()).is_some(){
+            return Err(format!("Instruction [{}] at line:{} is already defined.",ext.mnemonic,ext.line));
+        }
+
+        for code in ext.opcodes(){
+            if is_builtin_opcode(code){
+                return Err(format!("Pattern of [{}] at line:{} overlaps built-in opcode {:08b}.",ext.mnemonic,ext.line,code));
+            }
+            if let Some(other) = self.decode(code){
+                return Err(format!("Pattern of [{}] at line:{} overlaps [{}] on opcode {:08b}.",ext.mnemonic,ext.line,other.mnemonic,code));
+            }
+        }
+
+        self.extensions.push(ext);
+        Ok(())
+    }
+
+    fn parse_header(rest : &str, line : u32)->Result<Extension,String>{
+        let (mnemonic,operand_list) = match rest.find(char::is_whitespace){
+            Some(split) => (&rest[..split],rest[split..].trim()),
+            None => (rest,"")
+        };
+
+        let valid_name = mnemonic.chars().next().map(|c| c.is_ascii_alphabetic()).unwrap_or(false) &&
+                         mnemonic.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name || mnemonic == "byte" || (mnemonic.len() == 1 && Register::from_char(mnemonic.chars().next().unwrap()).is_some()){
+            return Err(format!("Invalid instruction mnemonic [{}] at line:{}",mnemonic,line));
+        }
+
+        let mut operands = Vec::new();
+        for operand in operand_list.split(',').map(str::trim).filter(|o| !o.is_empty()){
+            let var = match operand{
+                "x"   => Var::X,
+                "y"   => Var::Y,
+                "imm" => Var::Imm,
+                _=> return Err(format!("Invalid operand [{}] for [{}] at line:{} expected x, y or imm.",operand,mnemonic,line))
+            };
+            if operands.contains(&var) {
+                return Err(format!("Duplicate operand [{}] for [{}] at line:{}",operand,mnemonic,line));
+            }
+            if operands.contains(&Var::Imm){
+                return Err(format!("Operand [imm] must be the last operand of [{}] at line:{}",mnemonic,line));
+            }
+            operands.push(var);
+        }
+
+        Ok(Extension{
+            mnemonic : String::from(mnemonic),
+            operands,
+            opcode : 0,
+            mask : 0,
+            x_shift : None,
+            y_shift : None,
+            micro_ops : Vec::new(),
+            line
+        })
+    }
+
+    fn parse_pattern(ext : &mut Extension, pattern : &str, line : u32)->Result<(),String>{
+        let bits : Vec<char> = pattern.chars().filter(|c| *c != '_').collect();
+        if bits.len() != 8 {
+            return Err(format!("Pattern [{}] at line:{} must be 8 bits long.",pattern,line));
+        }
+
+        let mut opcode = 0u8;
+        let mut mask   = 0u8;
+        let mut x_bits = Vec::new();
+        let mut y_bits = Vec::new();
+        for (i,c) in bits.iter().enumerate(){
+            let bit = 7 - i as u8;
+            match c{
+                '0' => {mask |= 1 << bit;},
+                '1' => {mask |= 1 << bit; opcode |= 1 << bit;},
+                'x' => x_bits.push(bit),
+                'y' => y_bits.push(bit),
+                _=> return Err(format!("Invalid pattern bit [{}] at line:{}",c,line))
+            }
+        }
+
+        ext.opcode  = opcode;
+        ext.mask    = mask;
+        ext.x_shift = Isa::register_field(&x_bits,'x',ext.operands.contains(&Var::X),line)?;
+        ext.y_shift = Isa::register_field(&y_bits,'y',ext.operands.contains(&Var::Y),line)?;
+        Ok(())
+    }
+
+    /// a register field is two neighbouring bits
+    fn register_field(bits : &[u8], name : char, used : bool, line : u32)->Result<Option<u8>,String>{
+        if bits.is_empty() && !used{
+            return Ok(None);
+        }
+        if bits.len() != 2 || bits[0] != bits[1] + 1 || !used{
+            return Err(format!("Register field [{}] at line:{} must be two adjacent bits and listed as an operand.",name,line));
+        }
+        Ok(Some(bits[1]))
+    }
+
+    fn parse_micro_op(ext : &Extension, temps : &mut Vec<String>, source : &str, line : u32)->Result<MicroOp,String>{
+        let assign = match source.find('='){
+            Some(index) if !source[index+1..].starts_with('=') => index,
+            _=> return Err(format!("Expected [target = expression] at line:{}",line))
+        };
+
+        let target_str = source[..assign].trim();
+        let value = parse_expression(&source[assign+1..],&Scope{operands:&ext.operands,temps})
+            .map_err(|e| format!("{} at line:{}",e,line))?;
+
+        let target = if target_str.starts_with("ram[") && target_str.ends_with(']'){
+            Target::Ram(parse_expression(&target_str[4..target_str.len()-1],&Scope{operands:&ext.operands,temps})
+                .map_err(|e| format!("{} at line:{}",e,line))?)
+        }else{
+            match resolve_name(target_str,&Scope{operands:&ext.operands,temps}){
+                Some(Var::Imm) => return Err(format!("Cannot assign to [imm] at line:{}",line)),
+                Some(var) => Target::Var(var),
+                None =>{
+                    let valid = target_str.chars().next().map(|c| c.is_ascii_alphabetic()).unwrap_or(false) &&
+                                target_str.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+                    if !valid || target_str == "x" || target_str == "y" || target_str == "imm"{
+                        return Err(format!("Invalid micro-op target [{}] at line:{}",target_str,line));
+                    }
+                    temps.push(String::from(target_str));
+                    Target::Var(Var::Temp(temps.len() - 1))
+                }
+            }
+        };
+
+        Ok(MicroOp{target,value})
+    }
+}
+
+// *******************************
+// Expressions
+// *******************************
+
+/// names visible to an expression
+pub struct Scope<'a>{
+    pub operands : &'a [Var],
+    pub temps    : &'a [String]
+}
+
+fn resolve_name(name : &str, scope : &Scope)->Option<Var>{
+    match name{
+        "x"   if scope.operands.contains(&Var::X)   => Some(Var::X),
+        "y"   if scope.operands.contains(&Var::Y)   => Some(Var::Y),
+        "imm" if scope.operands.contains(&Var::Imm) => Some(Var::Imm),
+        "pc"   => Some(Var::Pc),
+        "halt" => Some(Var::Halt),
+        _=>{
+            if let Some(flag) = Flag::from_name(name){
+                Some(Var::Flag(flag))
+            }else if let Some(index) = scope.temps.iter().position(|t| t == name){
+                Some(Var::Temp(index))
+            }else if name.len() == 1{
+                Register::from_char(name.chars().next().unwrap()).map(Var::Reg)
+            }else{
+                None
+            }
+        }
+    }
+}
+
+const BINARY_OPS : &[(&str,u8)] = &[
+    ("||",1),("&&",2),
+    ("==",3),("!=",3),("<=",3),(">=",3),("<",3),(">",3),
+    ("|",4),("^",5),("&",6),("<<",7),(">>",7),
+    ("+",8),("-",8),("*",9),("/",9),("%",9)
+];
+
+#[derive(Debug,Clone,PartialEq)]
+enum ExprToken{
+    Number(isize),
+    Name(String),
+    Symbol(&'static str),
+}
+
+fn tokenize_expression(source : &str)->Result<Vec<ExprToken>,String>{
+    let chars : Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    'outer: while i < chars.len(){
+        let c = chars[i];
+        if c.is_whitespace(){
+            i+=1;
+        }else if c.is_ascii_digit(){
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_'){
+                i+=1;
+            }
+            let text : String = chars[start..i].iter().filter(|c| **c != '_').collect();
+            tokens.push(ExprToken::Number(parse_number(&text).ok_or(format!("Invalid number [{}]",text))?));
+        }else if c.is_ascii_alphabetic() || c == '_'{
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_'){
+                i+=1;
+            }
+            tokens.push(ExprToken::Name(chars[start..i].iter().collect()));
+        }else{
+            for symbol in &["||","&&","==","!=","<=",">=","<<",">>","<",">","|","^","&","+","-","*","/","%","~","!","(",")","[","]"]{
+                let len = symbol.len();
+                if i + len <= chars.len() && chars[i..i+len].iter().collect::<String>() == *symbol{
+                    tokens.push(ExprToken::Symbol(symbol));
+                    i+=len;
+                    continue 'outer;
+                }
+            }
+            return Err(format!("Unexpected character [{}] in expression",c));
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// parse a decimal, 0x hexadecimal or 0b binary number
+pub fn parse_number(text : &str)->Option<isize>{
+    let text = text.trim();
+    let (negative,digits) = match text.strip_prefix('-'){
+        Some(rest) => (true,rest),
+        None => (false,text)
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")){
+        isize::from_str_radix(hex,16).ok()?
+    }else if let Some(bin) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")){
+        isize::from_str_radix(bin,2).ok()?
+    }else{
+        digits.parse::<isize>().ok()?
+    };
+    Some(if negative {-value} else {value})
+}
+
+/// parse an expression within the given scope
+pub fn parse_expression(source : &str, scope : &Scope)->Result<Expr,String>{
+    let tokens = tokenize_expression(source)?;
+    let mut index = 0;
+    let expr = parse_binary(&tokens,&mut index,0,scope)?;
+    if index != tokens.len(){
+        return Err(format!("Unexpected [{}] in expression",token_text(&tokens[index])));
+    }
+    Ok(expr)
+}
+
+fn token_text(token : &ExprToken)->String{
+    match token{
+        ExprToken::Number(n) => format!("{}",n),
+        ExprToken::Name(n) => n.clone(),
+        ExprToken::Symbol(s) => String::from(*s)
+    }
+}
+
+fn parse_binary(tokens : &[ExprToken], index : &mut usize, min_precedence : u8, scope : &Scope)->Result<Expr,String>{
+    let mut left = parse_unary(tokens,index,scope)?;
+
+    while let Some(ExprToken::Symbol(symbol)) = tokens.get(*index){
+        let precedence = match BINARY_OPS.iter().find(|(s,_)| s == symbol){
+            Some((_,p)) if *p > min_precedence => *p,
+            _=> break
+        };
+        *index+=1;
+        let right = parse_binary(tokens,index,precedence,scope)?;
+        left = Expr::Binary(symbol,Box::new(left),Box::new(right));
+    }
+
+    Ok(left)
+}
+
+fn parse_unary(tokens : &[ExprToken], index : &mut usize, scope : &Scope)->Result<Expr,String>{
+    let token = tokens.get(*index).ok_or(String::from("Unexpected end of expression"))?;
+    *index+=1;
+    match token{
+        ExprToken::Number(n) => Ok(Expr::Number(*n)),
+        ExprToken::Name(name) =>{
+            if name == "ram"{
+                if tokens.get(*index) != Some(&ExprToken::Symbol("[")){
+                    return Err(String::from("Expected [ after ram"));
+                }
+                *index+=1;
+                let addr = parse_binary(tokens,index,0,scope)?;
+                if tokens.get(*index) != Some(&ExprToken::Symbol("]")){
+                    return Err(String::from("Expected ] after ram address"));
+                }
+                *index+=1;
+                Ok(Expr::Ram(Box::new(addr)))
+            }else if let Some(var) = resolve_name(name,scope){
+                Ok(Expr::Var(var))
+            }else{
+                Err(format!("Unknown name [{}] in expression",name))
+            }
+        },
+        ExprToken::Symbol("(") =>{
+            let expr = parse_binary(tokens,index,0,scope)?;
+            if tokens.get(*index) != Some(&ExprToken::Symbol(")")){
+                return Err(String::from("Expected )"));
+            }
+            *index+=1;
+            Ok(expr)
+        },
+        ExprToken::Symbol(s) if *s == "-" || *s == "~" || *s == "!" =>{
+            let expr = parse_unary(tokens,index,scope)?;
+            Ok(Expr::Unary(s.chars().next().unwrap(),Box::new(expr)))
+        },
+        ExprToken::Symbol(s) => Err(format!("Unexpected [{}] in expression",s))
+    }
+}
+
+impl Expr{
+
+    /// evaluate the expression against the machine state
+    pub fn evaluate(&self, env : &dyn Environment)->isize{
+        match self{
+            Expr::Number(n) => *n,
+            Expr::Var(var) => env.value(*var),
+            Expr::Ram(addr) => env.ram(addr.evaluate(env) as u8) as isize,
+            Expr::Unary(op,expr) =>{
+                let value = expr.evaluate(env);
+                match op{
+                    '-' => value.wrapping_neg(),
+                    '~' => !value & 0xFF,
+                    _=> (value == 0) as isize
+                }
+            },
+            Expr::Binary(op,left,right) =>{
+                let l = left.evaluate(env);
+                let r = right.evaluate(env);
+                match *op{
+                    "||" => (l != 0 || r != 0) as isize,
+                    "&&" => (l != 0 && r != 0) as isize,
+                    "==" => (l == r) as isize,
+                    "!=" => (l != r) as isize,
+                    "<=" => (l <= r) as isize,
+                    ">=" => (l >= r) as isize,
+                    "<"  => (l < r) as isize,
+                    ">"  => (l > r) as isize,
+                    "|"  => l | r,
+                    "^"  => l ^ r,
+                    "&"  => l & r,
+                    "<<" => l.wrapping_shl(r as u32),
+                    ">>" => l.wrapping_shr(r as u32),
+                    "+"  => l.wrapping_add(r),
+                    "-"  => l.wrapping_sub(r),
+                    "*"  => l.wrapping_mul(r),
+                    "/"  => if r == 0 {0} else {l.wrapping_div(r)},
+                    _    => if r == 0 {0} else {l.wrapping_rem(r)}
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Var{
+    fn fmt(&self, f : &mut fmt::Formatter)->fmt::Result{
+        match self{
+            Var::X => write!(f,"x"),
+            Var::Y => write!(f,"y"),
+            Var::Imm => write!(f,"imm"),
+            Var::Reg(r) => write!(f,"{}",format!("{:?}",r).to_lowercase()),
+            Var::Flag(flag) => write!(f,"{}",flag.name()),
+            Var::Pc => write!(f,"pc"),
+            Var::Halt => write!(f,"halt"),
+            Var::Temp(index) => write!(f,"t{}",index)
+        }
+    }
+}
+
+impl fmt::Display for Expr{
+    fn fmt(&self, f : &mut fmt::Formatter)->fmt::Result{
+        match self{
+            Expr::Number(n) => write!(f,"{}",n),
+            Expr::Var(var) => write!(f,"{}",var),
+            Expr::Ram(addr) => write!(f,"ram[{}]",addr),
+            Expr::Unary(op,expr) => write!(f,"{}{}",op,expr),
+            Expr::Binary(op,left,right) => write!(f,"({} {} {})",left,op,right)
+        }
+    }
+}
@@ -2,6 +2,8 @@
 use std::fmt;
 
 use super::Ops;
+use super::isa::Isa;
+use super::structured;
 
 
 #[derive(Clone,PartialEq)]
@@ -59,12 +61,18 @@ impl fmt::Display for LexerState{
 
 pub struct Lexer{
     current_state :  LexerState,
+    isa           :  Isa,
 }
 
 impl Lexer{
 
     pub fn create()->Self{
-        Lexer{current_state:LexerState::Base}
+        Lexer{current_state:LexerState::Base,isa:Isa::create()}
+    }
+
+    /// also recognize the mnemonics of the isa extensions
+    pub fn set_isa(&mut self, isa : Isa){
+        self.isa = isa;
     }
 
     pub fn tokenize(&mut self,strict: bool, input : &str)->Result<Vec<Token>,String>{
@@ -127,7 +135,7 @@ impl Lexer{
                             }
 
                             // cache the op for this line
-                            line_op =  Ops::get_op(identifier.as_str());
+                            line_op =  self.isa.get_op(identifier.as_str());
                             line_tokens.push(Token::create(TokenType::Op,line_number,col_start,identifier,self.current_state));
 
                         }
@@ -291,6 +299,9 @@ impl Lexer{
 
                                                 line_tokens.push(Token::create(TokenType::Reg,line_number,start_col,identifier,self.current_state));
                                             }
+                                        }else if line_tokens.first().map(|t| t.token_type == TokenType::Op && structured::is_keyword(&t.value)).unwrap_or(false){
+                                            // the register of loop or the carry flag of if/while
+                                            line_tokens.push(Token::create(TokenType::Reg,line_number,start_col,identifier,self.current_state));
                                         }else{
                                             // this is following a label so the only possibility is an identifier
                                             if strict {
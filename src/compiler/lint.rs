@@ -0,0 +1,92 @@
+use std::fmt;
+
+use super::{Ops, Program};
+use super::cfg::{Cfg, EdgeKind};
+use super::isa::{Isa, Target, Var};
+use super::stack;
+
+/// a likely mistake found without running the program
+#[derive(Debug,Clone,PartialEq)]
+pub struct Warning{
+    pub addr    : u8,
+    pub line    : Option<u32>,
+    pub message : String
+}
+
+impl fmt::Display for Warning{
+    fn fmt(&self, f : &mut fmt::Formatter)->fmt::Result{
+        match self.line{
+            Some(line) => write!(f,"{} at line:{} (0x{:02X})",self.message,line,self.addr),
+            None => write!(f,"{} at 0x{:02X}",self.message,self.addr)
+        }
+    }
+}
+
+/// check the control flow of a compiled source for unreachable code, execution running
+/// into data, programs that can never halt, loads of bytes nothing writes and misuse of the stack
+/// images are not checked since their data can not be told apart from code
+/// @param inputs addresses that get a value from outside the program, ex: devices or --set
+pub fn check(isa : &Isa, program : &Program, inputs : &[u8])->Vec<Warning>{
+    if program.lines.is_empty(){
+        return Vec::new();
+    }
+    let cfg = Cfg::build(isa,program);
+    let reached = cfg.reachable();
+    let line_of = |addr : u8| program.lines.iter().find(|l| l.addr == addr).map(|l| l.line);
+    let name = |addr : u8| program.label_at(addr).map(|label| format!(" `{}`",label)).unwrap_or_default();
+    let mut warnings = Vec::new();
+
+    // one warning per run of unreachable blocks
+    for (i,block) in cfg.blocks.iter().enumerate(){
+        let follows_unreached = i > 0 && !reached[i - 1] && cfg.blocks[i - 1].end() == block.start as usize;
+        if !reached[i] && !follows_unreached{
+            warnings.push(Warning{addr:block.start,line:line_of(block.start),message:format!("unreachable code{}",name(block.start))});
+        }
+    }
+
+    for (block,_) in cfg.blocks.iter().zip(reached.iter()).filter(|(_,r)| **r){
+        let last = block.last();
+        for edge in block.successors.iter().filter(|e| cfg.block_at(e.target).is_none()){
+            let target = edge.target;
+            let outside = target as usize >= program.instructions.len() || last.next_addr() < last.addr;
+            let what = if program.lines.iter().any(|l| l.data && l.addr == target){
+                format!("into data{} at 0x{:02X}",name(target),target)
+            }else if outside{
+                format!("outside the program to 0x{:02X}",target)
+            }else{
+                format!("into the middle of an instruction at 0x{:02X}",target)
+            };
+            let message = match edge.kind{
+                EdgeKind::Next if outside => String::from("execution can run past the end of the program"),
+                EdgeKind::Next => format!("execution can fall through {}",what),
+                _=> format!("jump {}",what)
+            };
+            warnings.push(Warning{addr:last.addr,line:line_of(last.addr),message});
+        }
+    }
+
+    // a known address past the program that nothing stores to still holds whatever was in ram
+    for (block,_) in cfg.blocks.iter().zip(reached.iter()).filter(|(_,r)| **r){
+        for (d,registers) in block.instructions.iter().zip(cfg.constants.trace(isa,block)){
+            if let Some(addr) = registers[d.y.bits() as usize].filter(|_| d.op == Ops::Load){
+                if addr as usize >= program.instructions.len() && !cfg.constants.is_written(addr) && !inputs.contains(&addr){
+                    warnings.push(Warning{addr:d.addr,line:line_of(d.addr),message:format!("load from 0x{:02X} which the program never writes",addr)});
+                }
+            }
+        }
+    }
+
+    if let Some(report) = stack::analyze(isa,program,&cfg){
+        warnings.extend(report.warnings);
+    }
+    warnings.sort_by_key(|w| w.addr);
+
+    let halts = cfg.blocks.iter().zip(reached.iter()).filter(|(_,r)| **r).any(|(b,_)|{
+        b.halts() || b.instructions.iter().any(|d| isa.get_extension(d.op).map(|ext| ext.micro_ops.iter().any(|m| m.target == Target::Var(Var::Halt))).unwrap_or(false))
+    });
+    if !halts{
+        warnings.push(Warning{addr:0,line:line_of(0),message:String::from("no path from the start of the program reaches halt")});
+    }
+
+    warnings
+}
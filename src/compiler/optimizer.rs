@@ -0,0 +1,178 @@
+use super::{branch, Ops, Register};
+use super::isa::Isa;
+use super::parser::{self, DebugInfo, Expression, ExpressionType, RootNode, Statement, StatementType};
+
+/// an instruction the optimizer took out
+#[derive(Debug,Clone,PartialEq)]
+pub struct Removal{
+    pub line   : u32,
+    pub text   : String,    // ex: cpr a,a
+    pub bytes  : u8,
+    pub reason : &'static str
+}
+
+/// remove instructions that do nothing from the statements before they are encoded
+/// - `cpr x,x`
+/// - `ldi x,n` when the next instruction sets x without reading it
+/// - a jump to the instruction right after it
+/// - `inc x` next to `dec x` with no label between them
+///
+/// the byte address of every statement is updated so labels follow their code
+/// jump targets have to be labels (or `.`) since numbers would no longer point to the same code,
+/// a number inside the program loaded with ldi or kept in a byte can be the address of a register
+/// jump so nothing up to the highest one is removed
+pub fn optimize(strict : bool, isa : &Isa, root : &mut RootNode)->Result<Vec<Removal>,String>{
+    for statement in root.statements.iter().filter(|s| is_immediate_jump(isa,s)){
+        if !matches!(statement.expressions[0].expression_type,ExpressionType::LabelPtr | ExpressionType::Dot){
+            return Err(format!("can not optimize, [{}] at line:{} jumps to an address that is not a label.",statement.raw(),statement.line()));
+        }
+    }
+    let fixed = fixed_until(isa,&root.statements);
+
+    let mut removed = Vec::new();
+    // removing one instruction can put two others next to each other
+    loop{
+        let before = removed.len();
+        let mut i = 0;
+        while i < root.statements.len(){
+            if root.statements[i].statement_type != StatementType::Operation || fixed.map(|f| root.statements[i].byte_addr <= f).unwrap_or(false){
+                i+=1;
+                continue;
+            }
+            let next = next_operation(&root.statements,i + 1);
+            let remove : Option<(&'static str,bool)> = {
+                let statement = &root.statements[i];
+                let op = isa.get_op(statement.raw());
+                let x = register(statement,0);
+                let following = next.map(|n| &root.statements[n]);
+                let adjacent = next == Some(i + 1);
+
+                match op{
+                    Some(Ops::CopyReg) if x.is_some() && x == register(statement,1) => Some(("copies a register to itself",false)),
+                    Some(Ops::Loadi) if following.map(|f| overwrites(isa,f,x)).unwrap_or(false) => Some(("the register is set again right after",false)),
+                    Some(op) if is_immediate_jump(isa,statement) && jumps_to_next(strict,&root.statements,i) => Some((if op == Ops::Jumpi || op == Ops::Branch {"jumps to the next instruction"} else {"both ways go to the next instruction"},false)),
+                    Some(Ops::Increment) | Some(Ops::Decrement) if adjacent && following.map(|f| cancels(isa,op.unwrap(),x,f)).unwrap_or(false) =>{
+                        Some(("an inc and dec of the same register cancel out",true))
+                    },
+                    _=> None
+                }
+            };
+
+            match remove{
+                Some((reason,pair)) =>{
+                    let count = if pair {2} else {1};
+                    for _ in 0..count{
+                        let statement = root.statements.remove(i);
+                        let bytes = isa.get_op(statement.raw()).map(|op| op.get_byte_count()).unwrap_or(0);
+                        removed.push(Removal{line:statement.line(),text:format_statement(&statement),bytes,reason});
+                    }
+                },
+                None => i+=1
+            }
+        }
+        if removed.len() == before{
+            break;
+        }
+    }
+
+    // labels move with the code they were on
+    parser::assign_addresses(isa,&mut root.statements);
+
+    Ok(removed)
+}
+
+/// the highest number loaded with ldi or kept in a byte that is an address inside the program
+fn fixed_until(isa : &Isa, statements : &[Statement])->Option<u8>{
+    let operations = statements.iter().filter(|s| s.statement_type == StatementType::Operation);
+    let size : usize = operations.clone().map(|s| isa.get_op(s.raw()).map(|op| op.get_byte_count() as usize).unwrap_or(0)).sum();
+    operations
+        .filter_map(|s| match isa.get_op(s.raw()){
+            Some(Ops::Loadi) => s.expressions.get(1),
+            Some(Ops::Byte) => s.expressions.first(),
+            _=> None
+        })
+        .filter_map(number)
+        .filter(|value| (*value as usize) < size)
+        .max()
+}
+
+/// the value of an expression made only of numbers, None if it has a label or `.` in it
+fn number(expression : &Expression)->Option<u8>{
+    match expression.expression_type{
+        ExpressionType::Value => expression.value.parse::<isize>().ok().map(|v| v as u8),
+        ExpressionType::Equation =>{
+            let values = expression.expressions.iter().map(number).collect::<Option<Vec<u8>>>()?;
+            if expression.value == "+"{
+                Some(values.iter().fold(0u8,|sum,v| sum.wrapping_add(*v)))
+            }else{
+                Some(values.get(1)?.wrapping_sub(*values.first()?))
+            }
+        },
+        _=> None
+    }
+}
+
+fn is_immediate_jump(isa : &Isa, statement : &Statement)->bool{
+    if statement.statement_type != StatementType::Operation{
+        return false;
+    }
+    match isa.get_op(statement.raw()){
+        Some(Ops::Jumpi) | Some(Ops::JumpLessi) | Some(Ops::JumpOverflowi) | Some(Ops::JumpSigni) | Some(Ops::JumpCarryi) | Some(Ops::JumpZeroi) => true,
+        Some(op) => branch::is_branch(op),
+        None => false
+    }
+}
+
+fn next_operation(statements : &[Statement], from : usize)->Option<usize>{
+    (from..statements.len()).find(|i| statements[*i].statement_type == StatementType::Operation)
+}
+
+/// the register operand at the index, ex: the x of ld x,(y)
+fn register(statement : &Statement, index : usize)->Option<Register>{
+    statement.expressions.get(index)
+        .filter(|e| e.expression_type == ExpressionType::Register)
+        .and_then(|e| e.value.chars().find(|c| c.is_alphabetic()))
+        .and_then(Register::from_char)
+}
+
+/// the statement sets the register without reading it first
+fn overwrites(isa : &Isa, statement : &Statement, x : Option<Register>)->bool{
+    let target = register(statement,0);
+    if x.is_none() || target != x{
+        return false;
+    }
+    match isa.get_op(statement.raw()){
+        Some(Ops::Loadi) => true,
+        Some(Ops::CopyReg) | Some(Ops::Load) => register(statement,1) != x,
+        _=> false
+    }
+}
+
+/// inc x then dec x or the other way around
+fn cancels(isa : &Isa, op : Ops, x : Option<Register>, statement : &Statement)->bool{
+    let other = match op{
+        Ops::Increment => Ops::Decrement,
+        _=> Ops::Increment
+    };
+    isa.get_op(statement.raw()) == Some(other) && x.is_some() && register(statement,0) == x
+}
+
+/// the jump target is one of the labels between the jump and the next instruction
+fn jumps_to_next(strict : bool, statements : &[Statement], index : usize)->bool{
+    let target = &statements[index].expressions[0];
+    if target.expression_type != ExpressionType::LabelPtr{
+        return false;
+    }
+    statements[index + 1..].iter()
+        .take_while(|s| s.statement_type == StatementType::Label)
+        .filter(|s| s.expressions.is_empty())
+        .any(|s|{
+            let name = s.raw().trim_end_matches(':');
+            if strict {name == target.value} else {name.eq_ignore_ascii_case(&target.value)}
+        })
+}
+
+fn format_statement(statement : &Statement)->String{
+    let operands : Vec<&str> = statement.expressions.iter().map(|e| e.value.as_str()).collect();
+    format!("{} {}",statement.raw(),operands.join(",")).trim_end().to_string()
+}
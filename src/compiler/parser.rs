@@ -1,5 +1,7 @@
 use crate::compiler;
 use crate::compiler::lexer::*;
+use crate::compiler::isa::Isa;
+use crate::compiler::structured;
 use std::fmt;
 
 
@@ -21,6 +23,7 @@ pub enum ExpressionType{
     Equation                // an Equation(+ or -) with two preceeding Label or Ptr Expressions
 }
 
+#[derive(Clone)]
 pub struct Statement{
     pub value : String,
     pub statement_type : StatementType,
@@ -34,7 +37,7 @@ pub struct RootNode{
     pub statements:Vec<Statement>,
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq,Clone)]
 pub struct Expression{
     pub expression_type : ExpressionType,
     pub expressions : Vec <Expression>,
@@ -47,6 +50,7 @@ pub struct Parser{
     pub root : RootNode,
     tokens :  Vec<Token>,
     current_index : usize,
+    isa : Isa,
 }
 
 pub trait DebugInfo{
@@ -67,7 +71,12 @@ impl Parser{
     }
 
     pub fn create(tokens:Vec<Token>)->Self{
-        Parser{root:RootNode{statements:Vec::new()},tokens,current_index:0}
+        Parser{root:RootNode{statements:Vec::new()},tokens,current_index:0,isa:Isa::create()}
+    }
+
+    /// also accept the operations of the isa extensions
+    pub fn set_isa(&mut self, isa : Isa){
+        self.isa = isa;
     }
 
     pub fn reset(&mut self){
@@ -78,6 +87,7 @@ impl Parser{
         let parser = self;
 
         parser.reset();
+        parser.tokens = structured::expand(&parser.tokens)?;
 
         parser.root = RootNode{statements:Vec::new()};
 
@@ -87,7 +97,7 @@ impl Parser{
             // either operation or label
             let token : &Token = next_option.unwrap();
             let mut statement = Statement::new();
-            let mut op_type = compiler::Ops::get_op(statement.value.as_str());
+            let mut op_type = parser.isa.get_op(statement.value.as_str());
 
             if token.token_type == TokenType::Label{
                 statement = Statement{
@@ -108,7 +118,7 @@ impl Parser{
                 };
 
                 // add appropriate number of bytes to the byte_counter
-                if let Some(op) = compiler::Ops::get_op(statement.value.as_str()){
+                if let Some(op) = parser.isa.get_op(statement.value.as_str()){
                     op_type = Some(op);
                     byte_counter+=op.get_byte_count();
                 }else{
@@ -379,6 +389,17 @@ impl Statement{
         Statement{byte_addr:0, col : 0, line : 0,expressions : Vec::new(), statement_type: StatementType::Operation, value: String::new()}
     }
 }
+
+/// lay the statements out again after some were changed, labels get the address of what follows them
+pub fn assign_addresses(isa : &Isa, statements : &mut [Statement]){
+    let mut byte_counter : u8 = 0;
+    for statement in statements.iter_mut(){
+        statement.byte_addr = byte_counter;
+        if statement.statement_type == StatementType::Operation{
+            byte_counter = byte_counter.wrapping_add(isa.get_op(statement.raw()).map(|op| op.get_byte_count()).unwrap_or(0));
+        }
+    }
+}
 impl DebugInfo for Statement{
     fn line(&self)->u32{self.line}
     fn col(&self)->u32{self.col}
@@ -0,0 +1,230 @@
+use super::{Ops, Program, Register};
+use super::cfg::{BasicBlock, Cfg, EdgeKind};
+use super::constants;
+use super::disassembler::Decoded;
+use super::isa::{Isa, Target, Var};
+use super::lint::Warning;
+
+/// the stack pointer, it starts at 0 so the first push goes to 0xFF
+pub const STACK_POINTER : Register = Register::D;
+
+/// what is known about the stack of a program
+#[derive(Debug,Clone,PartialEq)]
+pub struct StackReport{
+    pub max_depth : Option<usize>,      // bytes below the top of ram, None if recursion has no bound
+    pub warnings  : Vec<Warning>
+}
+
+/// what a subroutine does to the stack, depths are relative to the D it was entered with
+#[derive(Debug,Clone)]
+struct Summary{
+    max_depth : Option<isize>,
+    deepest   : u8,                 // instruction that pushes the stack to max_depth
+    exits     : Vec<(u8,isize)>     // address of each return jump and D there
+}
+
+/// a jump to a subroutine, the return address was loaded with ldi just before
+struct Call{
+    callee   : u8,
+    ret      : u8,
+    at_load  : Option<isize>,   // D when the return address was loaded
+    at_jump  : Option<isize>    // D at the jump
+}
+
+struct Analysis<'a>{
+    isa      : &'a Isa,
+    cfg      : &'a Cfg,
+    program  : &'a Program,
+    done     : Vec<(u8,Summary)>,
+    active   : Vec<u8>,         // subroutines being analyzed, a call to one of them is recursion
+    warnings : Vec<Warning>
+}
+
+/// follow D through `dec d`/`st (d),x` pushes and `ld x,(d)`/`inc d` pops along the cfg
+/// subroutine calls are found by the return address loaded with ldi before the jump,
+/// each subroutine is checked on its own and has to give back what the caller pushed
+/// @return None if the program never loads or stores through D
+pub fn analyze(isa : &Isa, program : &Program, cfg : &Cfg)->Option<StackReport>{
+    let uses_stack = cfg.blocks.iter().flat_map(|b| b.instructions.iter()).any(|d|{
+        (d.op == Ops::Store && d.x == STACK_POINTER) || (d.op == Ops::Load && d.y == STACK_POINTER)
+    });
+    if !uses_stack{
+        return None;
+    }
+
+    let mut analysis = Analysis{isa,cfg,program,done:Vec::new(),active:Vec::new(),warnings:Vec::new()};
+    let main = analysis.function(0,true);
+    let max_depth = main.max_depth.map(|depth| depth.max(0) as usize);
+
+    let end = program.instructions.len();
+    if let Some(depth) = max_depth.filter(|depth| 256 - *depth < end){
+        let lowest = (256 - depth) as u8;
+        let name = program.label_at(lowest).map(|label| format!(" (`{}`)",label)).unwrap_or_default();
+        analysis.warnings.push(Warning{addr:main.deepest,line:None,
+            message:format!("the stack can grow to {} bytes, down to 0x{:02X}{} which overwrites the program",depth,lowest,name)});
+    }
+
+    let mut warnings = analysis.warnings;
+    warnings.sort_by_key(|w| w.addr);
+    warnings.dedup();
+    for warning in warnings.iter_mut(){
+        warning.line = program.lines.iter().find(|l| l.addr == warning.addr).map(|l| l.line);
+    }
+    Some(StackReport{max_depth,warnings})
+}
+
+impl<'a> Analysis<'a>{
+
+    fn warn(&mut self, addr : u8, message : String){
+        self.warnings.push(Warning{addr,line:None,message});
+    }
+
+    fn name(&self, addr : u8)->String{
+        self.program.labels.iter().find(|(_,a)| *a == addr).map(|(l,_)| format!("`{}`",l)).unwrap_or(format!("0x{:02X}",addr))
+    }
+
+    /// walk a subroutine from its entry without going into the subroutines it calls
+    /// @param main D starts at 0 (the top of ram) rather than somewhere unknown
+    fn function(&mut self, entry : u8, main : bool)->Summary{
+        if let Some((_,summary)) = self.done.iter().find(|(e,_)| *e == entry){
+            return summary.clone();
+        }
+        self.active.push(entry);
+
+        let mut summary = Summary{max_depth:Some(0),deepest:entry,exits:Vec::new()};
+        let mut seen : Vec<(u8,Option<isize>)> = Vec::new();
+        let mut work : Vec<(u8,Option<isize>)> = vec![(entry,Some(0))];
+
+        while let Some((addr,pointer)) = work.pop(){
+            match seen.iter().find(|(a,_)| *a == addr){
+                Some((_,Some(before))) if pointer.is_some() && pointer != Some(*before) =>{
+                    let message = format!("unbalanced stack, {} is reached with {} and {} bytes pushed",self.name(addr),-before,-pointer.unwrap());
+                    self.warn(addr,message);
+                    continue;
+                },
+                Some(_) => continue,
+                None => seen.push((addr,pointer))
+            }
+            let block = match self.cfg.block_at(addr){
+                Some(block) => block,
+                None => continue
+            };
+
+            let (pointer,call) = self.walk(block,pointer,main,&mut summary);
+            if block.indirect{
+                if let Some(pointer) = pointer{
+                    summary.exits.push((block.last().addr,pointer));
+                }
+            }
+
+            match call{
+                Some(call) =>{
+                    self.call(&call,block.last().addr,&mut summary);
+                    work.push((call.ret,call.at_load));
+                },
+                None =>{
+                    for edge in &block.successors{
+                        work.push((edge.target,pointer));
+                    }
+                }
+            }
+        }
+
+        // every return should leave the stack the same way
+        if let Some((_,first)) = summary.exits.first().copied(){
+            for (addr,pointer) in summary.exits.clone(){
+                if pointer != first{
+                    let message = format!("unbalanced stack, {} returns with {} bytes popped here and {} at another return",self.name(entry),pointer,first);
+                    self.warn(addr,message);
+                }
+            }
+        }
+
+        self.active.retain(|e| *e != entry);
+        self.done.push((entry,summary.clone()));
+        summary
+    }
+
+    /// follow D through the instructions of a block
+    /// @return D at the end of the block and the subroutine call the block ends with
+    fn walk(&mut self, block : &BasicBlock, mut pointer : Option<isize>, main : bool, summary : &mut Summary)->(Option<isize>,Option<Call>){
+        let mut loaded : Option<(u8,Option<isize>)> = None;
+
+        for d in &block.instructions{
+            if d.op == Ops::Loadi{
+                if let Some(ret) = d.immediate.filter(|addr| self.cfg.block_at(*addr).is_some()){
+                    loaded = Some((ret,pointer));
+                }
+            }
+            pointer = self.step(d,pointer,main);
+            if let (Some(depth),Some(max)) = (pointer.map(|p| -p),summary.max_depth){
+                if depth > max{
+                    summary.max_depth = Some(depth);
+                    summary.deepest = d.addr;
+                }
+            }
+        }
+
+        let last = block.last();
+        let jump = block.successors.iter().find(|e| e.kind == EdgeKind::Jump);
+        let call = match (loaded,jump){
+            (Some((ret,at_load)),Some(jump)) if ret != jump.target && ret == last.next_addr() =>{
+                Some(Call{callee:jump.target,ret,at_load,at_jump:pointer})
+            },
+            _=> None
+        };
+        (pointer,call)
+    }
+
+    /// D after the instruction
+    fn step(&self, d : &Decoded, pointer : Option<isize>, main : bool)->Option<isize>{
+        match d.op{
+            Ops::Increment if d.x == STACK_POINTER => pointer.map(|p| p + 1),
+            Ops::Decrement if d.x == STACK_POINTER => pointer.map(|p| p - 1),
+            // only the main program knows where D is relative to the top of ram
+            Ops::Loadi if d.x == STACK_POINTER && main => d.immediate.map(|v| if v == 0 {0} else {v as isize - 256}),
+            Ops::Custom{..} =>{
+                let writes = self.isa.get_extension(d.op).map(|ext| ext.micro_ops.iter().any(|m| match m.target{
+                    Target::Var(Var::Reg(r)) => r == STACK_POINTER,
+                    Target::Var(Var::X) => d.x == STACK_POINTER,
+                    Target::Var(Var::Y) => d.y == STACK_POINTER,
+                    _=> false
+                })).unwrap_or(false);
+                if writes {None} else {pointer}
+            },
+            _=>{
+                let writes = d.x == STACK_POINTER && constants::fold(d,&[None;4]).is_some();
+                if writes {None} else {pointer}
+            }
+        }
+    }
+
+    /// account for the depth of a subroutine and check it gives back what was pushed for it
+    fn call(&mut self, call : &Call, addr : u8, summary : &mut Summary){
+        if self.active.contains(&call.callee){
+            let message = format!("recursive call to {}, the stack depth has no bound",self.name(call.callee));
+            self.warn(addr,message);
+            summary.max_depth = None;
+            return;
+        }
+        let callee = self.function(call.callee,false);
+        summary.max_depth = match (summary.max_depth,callee.max_depth,call.at_jump){
+            (Some(max),Some(inner),Some(at_jump)) =>{
+                if inner - at_jump > max{
+                    summary.deepest = callee.deepest;
+                }
+                Some(max.max(inner - at_jump))
+            },
+            (Some(max),Some(_),None) => Some(max),
+            _=> None
+        };
+
+        if let (Some(at_load),Some(at_jump),Some((_,popped))) = (call.at_load,call.at_jump,callee.exits.first()){
+            let pushed = at_load - at_jump;
+            if pushed != *popped{
+                let message = format!("unbalanced stack, {} bytes are pushed for the call to {} but it pops {}",pushed,self.name(call.callee),popped);
+                self.warn(addr,message);
+            }
+        }
+    }
+}
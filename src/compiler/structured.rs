@@ -0,0 +1,171 @@
+use super::lexer::{LexerState, Token, TokenType};
+
+/// words that open or close a structured block, they take the place of an op on their line
+const KEYWORDS : [&str;7] = ["if","else","endif","while","endw","loop","endloop"];
+
+/// a block that is open while its body is expanded
+enum Block{
+    If{line : u32, has_else : bool},
+    While{line : u32},
+    Loop{line : u32, register : String}
+}
+
+pub fn is_keyword(word : &str)->bool{
+    KEYWORDS.contains(&word)
+}
+
+/// the immediate jump taken when the flag is set and if the condition is the flag being clear
+/// ex: z -> (jzi,false), nz -> (jzi,true)
+fn condition(value : &str)->Option<(&'static str,bool)>{
+    let (flag,negated) = match value.strip_prefix('n'){
+        Some(flag) if !flag.is_empty() => (flag,true),
+        _=> (value,false)
+    };
+    let jump = match flag{
+        "z" => "jzi",
+        "c" => "jci",
+        "l" => "jli",
+        "s" => "jsi",
+        "o" => "joi",
+        _=> return None
+    };
+    Some((jump,negated))
+}
+
+/// replace if/else/endif, while/endw and loop/endloop with jumps and labels
+/// the labels start with _ so they can not clash with the labels of the program,
+/// ex: the if on line 7 uses _then7, _else7 and _endif7
+/// - `if z` runs the body when the flag is set, `if nz` when it is clear, `else` is optional
+/// - `while z` checks the flag before each pass of the body
+/// - `loop c` runs the body c times using `and c,c` to test it (which sets the flags) and `dec c`
+pub fn expand(tokens : &[Token])->Result<Vec<Token>,String>{
+    let mut out : Vec<Token> = Vec::new();
+    let mut open : Vec<Block> = Vec::new();
+    let mut i = 0;
+
+    while i < tokens.len(){
+        let token = &tokens[i];
+        let mut end = i + 1;
+        while end < tokens.len() && !matches!(tokens[end].token_type,TokenType::Op | TokenType::Label | TokenType::Eof){
+            end+=1;
+        }
+        if token.token_type != TokenType::Op || !is_keyword(&token.value){
+            out.extend(tokens[i..end].iter().cloned());
+            i = end;
+            continue;
+        }
+
+        let operands : Vec<&Token> = tokens[i + 1..end].iter().filter(|t| t.token_type != TokenType::Comma).collect();
+        let line = token.line;
+        let takes_operand = matches!(token.value.as_str(),"if" | "while" | "loop");
+        if operands.len() != takes_operand as usize{
+            return Err(format!("[{}] at line:{} takes {} operand.",token.value,line,if takes_operand {"one"} else {"no"}));
+        }
+
+        let jump = |out : &mut Vec<Token>, op : &str, target : String|{
+            out.push(Token{token_type:TokenType::Op,line,column:token.column,value:String::from(op),state:LexerState::Base});
+            out.push(Token{token_type:TokenType::Identifier,line,column:token.column,value:target,state:LexerState::Operand});
+        };
+        let label = |out : &mut Vec<Token>, name : String|{
+            out.push(Token{token_type:TokenType::Label,line,column:token.column,value:format!("{}:",name),state:LexerState::Base});
+        };
+        let test = |out : &mut Vec<Token>, value : &str, body : String, skip : String|->Result<(),String>{
+            let (op,negated) = condition(value).ok_or(format!("[{}] at line:{} is not a condition. use z, c, l, s, o or nz, nc, nl, ns, no.",value,line))?;
+            if negated{
+                jump(out,op,skip);
+            }else{
+                jump(out,op,body.clone());
+                jump(out,"jmpi",skip);
+                label(out,body);
+            }
+            Ok(())
+        };
+        let mismatch = |open : Option<&Block>|{
+            let opened = match open{
+                Some(Block::If{line,..}) => format!(", the if at line:{} is still open",line),
+                Some(Block::While{line}) => format!(", the while at line:{} is still open",line),
+                Some(Block::Loop{line,..}) => format!(", the loop at line:{} is still open",line),
+                None => String::new()
+            };
+            format!("[{}] at line:{} has no matching block{}.",token.value,line,opened)
+        };
+
+        match token.value.as_str(){
+            "if" =>{
+                test(&mut out,&operands[0].value,format!("_then{}",line),format!("_else{}",line))?;
+                open.push(Block::If{line,has_else:false});
+            },
+            "else" =>{
+                match open.last_mut(){
+                    Some(Block::If{line:start,has_else}) if !*has_else =>{
+                        *has_else = true;
+                        let start = *start;
+                        jump(&mut out,"jmpi",format!("_endif{}",start));
+                        label(&mut out,format!("_else{}",start));
+                    },
+                    other => return Err(mismatch(other.map(|b| &*b)))
+                }
+            },
+            "endif" =>{
+                match open.last(){
+                    Some(Block::If{line:start,has_else}) =>{
+                        if !*has_else{
+                            label(&mut out,format!("_else{}",start));
+                        }
+                        label(&mut out,format!("_endif{}",start));
+                        open.pop();
+                    },
+                    other => return Err(mismatch(other))
+                }
+            },
+            "while" =>{
+                label(&mut out,format!("_while{}",line));
+                test(&mut out,&operands[0].value,format!("_do{}",line),format!("_endw{}",line))?;
+                open.push(Block::While{line});
+            },
+            "endw" =>{
+                match open.last(){
+                    Some(Block::While{line:start}) =>{
+                        jump(&mut out,"jmpi",format!("_while{}",start));
+                        label(&mut out,format!("_endw{}",start));
+                        open.pop();
+                    },
+                    other => return Err(mismatch(other))
+                }
+            },
+            "loop" =>{
+                let register = operands[0];
+                if register.token_type != TokenType::Reg{
+                    return Err(format!("[loop {}] at line:{} needs a register to count with.",register.value,line));
+                }
+                label(&mut out,format!("_loop{}",line));
+                out.push(Token{token_type:TokenType::Op,line,column:token.column,value:String::from("and"),state:LexerState::Base});
+                out.push(register.clone());
+                out.push(Token{token_type:TokenType::Comma,value:String::from(","),..register.clone()});
+                out.push(register.clone());
+                jump(&mut out,"jzi",format!("_endloop{}",line));
+                open.push(Block::Loop{line,register:register.value.clone()});
+            },
+            _ =>{
+                match open.last(){
+                    Some(Block::Loop{line:start,register}) =>{
+                        out.push(Token{token_type:TokenType::Op,line,column:token.column,value:String::from("dec"),state:LexerState::Base});
+                        out.push(Token{token_type:TokenType::Reg,line,column:token.column,value:register.clone(),state:LexerState::Operand});
+                        jump(&mut out,"jmpi",format!("_loop{}",start));
+                        label(&mut out,format!("_endloop{}",start));
+                        open.pop();
+                    },
+                    other => return Err(mismatch(other))
+                }
+            }
+        }
+        i = end;
+    }
+
+    match open.last(){
+        Some(Block::If{line,..}) => Err(format!("the if at line:{} has no endif.",line)),
+        Some(Block::While{line}) => Err(format!("the while at line:{} has no endw.",line)),
+        Some(Block::Loop{line,..}) => Err(format!("the loop at line:{} has no endloop.",line)),
+        None => Ok(out)
+    }
+}
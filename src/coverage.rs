@@ -0,0 +1,139 @@
+use crate::compiler::{LineInfo, Program};
+use crate::compiler::disassembler;
+use crate::compiler::isa::Isa;
+
+// access bits kept per address by the vm
+pub const READ      : u8 = 1;
+pub const WRITTEN   : u8 = 2;
+pub const TAKEN     : u8 = 4;   // conditional jump went to its target
+pub const NOT_TAKEN : u8 = 8;   // conditional jump fell through
+
+const HEADER : &str = "ttpc coverage";
+
+/// what happened to every byte of a program over one or more runs
+#[derive(Debug,Clone,PartialEq)]
+pub struct Coverage{
+    pub program  : Vec<u8>,         // bytes the coverage was recorded for
+    pub executed : [usize;256],     // times each address was executed
+    pub access   : [u8;256]         // READ | WRITTEN | TAKEN | NOT_TAKEN
+}
+
+impl Coverage{
+
+    pub fn create(program : Vec<u8>, executed : [usize;256], access : [u8;256])->Self{
+        Coverage{program,executed,access}
+    }
+
+    /// add the counts of another run of the same program
+    pub fn merge(&mut self, other : &Coverage)->Result<(),String>{
+        if self.program != other.program{
+            return Err(String::from("coverage was recorded for a different program."));
+        }
+        for addr in 0..256{
+            self.executed[addr]+=other.executed[addr];
+            self.access[addr]|=other.access[addr];
+        }
+        Ok(())
+    }
+
+    /// text format: header, program bytes and one line per touched address
+    pub fn serialize(&self)->String{
+        let program : Vec<String> = self.program.iter().map(|b| format!("{:02X}",b)).collect();
+        let mut out = format!("{}\nprogram {}\n",HEADER,program.join(" "));
+        for addr in 0..256{
+            if self.executed[addr] > 0 || self.access[addr] != 0{
+                out.push_str(format!("{:02X} {} {}\n",addr,self.executed[addr],self.access[addr]).as_str());
+            }
+        }
+        out
+    }
+
+    pub fn parse(source : &str)->Result<Coverage,String>{
+        let mut lines = source.lines();
+        if lines.next().map(str::trim) != Some(HEADER){
+            return Err(String::from("not a ttpc coverage file."));
+        }
+        let program = lines.next().and_then(|l| l.strip_prefix("program"))
+            .ok_or("coverage file is missing the program line.")?
+            .split_whitespace().map(|b| u8::from_str_radix(b,16).map_err(|_| format!("[{}] is not a program byte.",b)))
+            .collect::<Result<Vec<u8>,String>>()?;
+
+        let mut coverage = Coverage::create(program,[0;256],[0;256]);
+        for (number,line) in lines.enumerate().filter(|(_,l)| !l.trim().is_empty()){
+            let fields : Vec<&str> = line.split_whitespace().collect();
+            let invalid = || format!("invalid coverage entry [{}] at line:{}",line,number + 3);
+            if fields.len() != 3{
+                return Err(invalid());
+            }
+            let addr = u8::from_str_radix(fields[0],16).map_err(|_| invalid())? as usize;
+            coverage.executed[addr] = fields[1].parse::<usize>().map_err(|_| invalid())?;
+            coverage.access[addr] = fields[2].parse::<u8>().map_err(|_| invalid())?;
+        }
+        Ok(coverage)
+    }
+
+    /// count of executed instructions and conditional jumps that went both ways
+    /// @return the summary line
+    fn summary(&self, starts : &[(u8,bool)])->String{
+        let code : Vec<u8> = starts.iter().filter(|(_,data)| !data).map(|(a,_)| *a).collect();
+        let executed = code.iter().filter(|a| self.executed[**a as usize] > 0).count();
+        let branches : Vec<u8> = code.iter().filter(|a| self.access[**a as usize] & (TAKEN | NOT_TAKEN) != 0).copied().collect();
+        let both = branches.iter().filter(|a| self.access[**a as usize] & (TAKEN | NOT_TAKEN) == (TAKEN | NOT_TAKEN)).count();
+        let percent = if code.is_empty() {100.0} else {executed as f64 * 100.0 / code.len() as f64};
+        format!("COVERAGE: {} of {} instructions executed ({:.1}%), {} of {} branches went both ways",
+                executed,code.len(),percent,both,branches.len())
+    }
+
+    /// marker for the statement at the address, gcov style
+    /// count for executed code, ##### for code that never ran and data for byte statements
+    fn marker(&self, addr : u8, data : bool, conditional : bool)->(String,String){
+        let executed = self.executed[addr as usize];
+        let access = self.access[addr as usize];
+        let count = if data || (executed == 0 && access & (READ | WRITTEN) != 0){
+            String::from("data")
+        }else if executed == 0{
+            String::from("#####")
+        }else{
+            format!("{}",executed)
+        };
+
+        let mut notes = Vec::new();
+        if access & READ != 0 {notes.push("read");}
+        if access & WRITTEN != 0 {notes.push("written");}
+        if conditional && executed > 0{
+            if access & TAKEN == 0 {notes.push("never taken");}
+            if access & NOT_TAKEN == 0 {notes.push("always taken");}
+        }
+        (count,if notes.is_empty() {String::new()} else {format!("  // {}",notes.join(", "))})
+    }
+
+    /// the source annotated with the coverage of each line
+    pub fn listing(&self, isa : &Isa, source : &str, lines : &[LineInfo])->String{
+        let starts : Vec<(u8,bool)> = lines.iter().map(|l| (l.addr,l.data)).collect();
+        let mut out = format!("{}\n",self.summary(&starts));
+        for (number,text) in source.lines().enumerate(){
+            match lines.iter().find(|l| l.line as usize == number + 1){
+                Some(info) =>{
+                    let op = disassembler::decode(isa,&self.program,info.addr).op;
+                    let (count,notes) = self.marker(info.addr,info.data,!info.data && op.is_conditional_jump());
+                    out.push_str(format!("{:>9}:{:02X}:{:>4}: {}{}\n",count,info.addr,number + 1,text,notes).as_str());
+                },
+                None => out.push_str(format!("{:>9}:  :{:>4}: {}\n","-",number + 1,text).as_str())
+            }
+        }
+        out
+    }
+
+    /// coverage of a program without source, ex: an assembled image
+    pub fn disassembly(&self, isa : &Isa, program : &Program)->String{
+        let decoded = disassembler::decode_all(isa,&self.program,self.program.len());
+        // bytes that were only read or written are data
+        let starts : Vec<(u8,bool)> = decoded.iter().map(|d| (d.addr,self.executed[d.addr as usize] == 0 && self.access[d.addr as usize] & (READ | WRITTEN) != 0)).collect();
+        let mut out = format!("{}\n",self.summary(&starts));
+        for d in &decoded{
+            let (count,notes) = self.marker(d.addr,false,d.op.is_conditional_jump());
+            out.push_str(format!("{:>9}:{:02X}: {}{}\n",count,d.addr,d.format(isa,&program.labels),notes).as_str());
+        }
+        out
+    }
+}
@@ -0,0 +1,483 @@
+use std::io::{self, BufRead, Write};
+
+use crate::compiler::{Program, Register};
+use crate::compiler::disassembler;
+use crate::compiler::isa::{self, Expr, Flag, Isa};
+use crate::snapshot::Snapshot;
+use crate::vm::{Access, VirtualMachine, Watchpoint};
+
+/// next and continue give up after this many instructions so a
+/// program stuck in a loop does not lock up the prompt
+const RUN_LIMIT : usize = 100_000;
+
+const HELP : &str = "\
+step [count]          (s)  execute one or <count> instructions
+next                  (n)  step over the current instruction, following jumps until it returns
+continue              (c)  run until a breakpoint or halt
+back [count]          (bs) step backwards one or <count> instructions
+rcontinue             (rc) run backwards until a breakpoint, a write to a watched address or the start
+lastwrite <addr|label> (lw) go back to the instruction that last wrote the address
+history [count]            show or set how many instructions back can go (default 100000)
+break [addr|label]    (b)  set a breakpoint or list all breakpoints and watchpoints
+    [if <condition>]       only stop when the condition holds, ex: break loop if c == 0 && zero
+delete <addr|label>   (d)  remove a breakpoint
+watch <addr|label> [r|w|rw] (w)  stop when an instruction writes (default), reads or accesses ram
+unwatch <addr|label>       remove a watchpoint
+print [what]          (p)  registers and flags, a register, flags, pc or ram <addr> [count]
+set <what> <value>         set a register, pc, a flag (zero less overflow sign carry) or ram <addr>
+disasm [addr] [count] (x)  disassemble around the pc or the address
+labels                (l)  list all labels
+memory [diff]         (m)  hexdump of the ram or the bytes changed since load
+save <file>                save the complete vm state to a file
+restore <file>             continue from a saved vm state (clears the back history)
+help                  (h)  show this help
+quit                  (q)  exit the debugger
+an empty line repeats the last command.";
+
+pub struct Debugger{
+    pub vm       : VirtualMachine,
+    isa          : Isa,
+    labels       : Vec<(String,u8)>,
+    program_size : usize,
+    breakpoints  : Vec<(u8,Option<Expr>)>,
+    last_command : String
+}
+
+impl Debugger{
+
+    pub fn create(vm : VirtualMachine, program : &Program, isa : Isa)->Self{
+        vm.set_recording(true);
+        Debugger{
+            vm,
+            isa,
+            labels       : program.labels.clone(),
+            program_size : program.instructions.len(),
+            breakpoints  : Vec::new(),
+            last_command : String::new()
+        }
+    }
+
+    /// read commands from stdin until quit or end of input
+    pub fn run(&mut self){
+        println!("ttpc debugger - type help for a list of commands.");
+        println!("{}",self.disassemble(self.vm.get_program_counter(),1));
+
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+        loop{
+            print!("(ttpdb) ");
+            io::stdout().flush().unwrap();
+
+            let line = match lines.next(){
+                Some(Ok(line)) => line,
+                _=> break
+            };
+
+            match self.execute(line.as_str()){
+                Ok(true) => {},
+                Ok(false) => break,
+                Err(message) => println!("{}",message)
+            }
+        }
+    }
+
+    /// execute a single debugger command
+    /// @return false when the debugger should exit
+    pub fn execute(&mut self, line : &str)->Result<bool,String>{
+        let line = if line.trim().is_empty(){
+            self.last_command.clone()
+        }else{
+            String::from(line.trim())
+        };
+        self.last_command = line.clone();
+
+        let mut parts = line.split_whitespace();
+        let command = match parts.next(){
+            Some(command) => command,
+            None => return Ok(true)
+        };
+        let args : Vec<&str> = parts.collect();
+
+        match command{
+            "s" | "step" =>{
+                let count = match args.first(){
+                    Some(arg) => self.parse_value(arg)? as usize,
+                    None => 1
+                };
+                for _ in 0..count{
+                    if self.vm.is_halted(){
+                        println!("program has halted.");
+                        break;
+                    }
+                    println!("{}",self.vm.step());
+                    if let Some(message) = self.watch_message(){
+                        println!("{}",message);
+                        break;
+                    }
+                }
+                println!("{}",self.disassemble(self.vm.get_program_counter(),1));
+            },
+            "n" | "next" =>{
+                let ram = self.vm.get_ram();
+                let current = disassembler::decode(&self.isa,&ram,self.vm.get_program_counter());
+                let reason = self.run_until(Some(current.next_addr()));
+                println!("{}\n{}",reason,self.disassemble(self.vm.get_program_counter(),1));
+            },
+            "c" | "continue" =>{
+                let reason = self.run_until(None);
+                println!("{}\n{}",reason,self.disassemble(self.vm.get_program_counter(),1));
+            },
+            "bs" | "back" =>{
+                let count = match args.first(){
+                    Some(arg) => self.parse_value(arg)? as usize,
+                    None => 1
+                };
+                for _ in 0..count{
+                    if self.vm.step_back().is_none(){
+                        println!("reached the start of the recorded history.");
+                        break;
+                    }
+                }
+                println!("{}",self.disassemble(self.vm.get_program_counter(),1));
+            },
+            "rc" | "rcontinue" =>{
+                let reason = self.run_back();
+                println!("{}\n{}",reason,self.disassemble(self.vm.get_program_counter(),1));
+            },
+            "lw" | "lastwrite" =>{
+                let addr = self.parse_value(args.first().ok_or("expected an address or label.")?)?;
+                match self.vm.steps_since_write(addr){
+                    Some(steps) =>{
+                        // nothing after the write touched the address so this is the written value
+                        for _ in 1..steps{
+                            self.vm.step_back();
+                        }
+                        let new = self.vm.get_ram()[addr as usize];
+                        self.vm.step_back();
+                        let old = self.vm.get_ram()[addr as usize];
+                        println!("{} was last written {} instructions ago: {:02X} -> {:02X} by the instruction at {}.",
+                                 self.location(addr),steps,old,new,self.location(self.vm.get_program_counter()));
+                        println!("{}",self.disassemble(self.vm.get_program_counter(),1));
+                    },
+                    None => println!("{} was not written in the recorded history.",self.location(addr))
+                }
+            },
+            "b" | "break" =>{
+                if let Some(arg) = args.first(){
+                    let addr = self.parse_value(arg)?;
+                    let condition = match args.get(1){
+                        Some(&"if") =>{
+                            let scope = isa::Scope{operands:&[],temps:&[]};
+                            Some(isa::parse_expression(args[2..].join(" ").as_str(),&scope)?)
+                        },
+                        Some(other) => return Err(format!("expected [if] but got [{}].",other)),
+                        None => None
+                    };
+                    self.breakpoints.retain(|(b,_)| *b != addr);
+                    println!("breakpoint at {}{}",self.location(addr),if condition.is_some() {" (conditional)"} else {""});
+                    self.breakpoints.push((addr,condition));
+                }else{
+                    if self.breakpoints.is_empty() && self.vm.get_watchpoints().is_empty(){
+                        println!("no breakpoints or watchpoints.");
+                    }
+                    for (addr,condition) in &self.breakpoints{
+                        match condition{
+                            Some(condition) => println!("breakpoint at {} if {}",self.location(*addr),condition),
+                            None => println!("breakpoint at {}",self.location(*addr))
+                        }
+                    }
+                    for watchpoint in self.vm.get_watchpoints(){
+                        println!("watchpoint on {} ({}{})",self.location(watchpoint.addr),
+                                 if watchpoint.read {"r"} else {""},if watchpoint.write {"w"} else {""});
+                    }
+                }
+            },
+            "d" | "delete" =>{
+                let addr = self.parse_value(args.first().ok_or("expected an address or label.")?)?;
+                self.breakpoints.retain(|(b,_)| *b != addr);
+            },
+            "w" | "watch" =>{
+                let addr = self.parse_value(args.first().ok_or("expected an address or label.")?)?;
+                let (read,write) = match args.get(1).copied(){
+                    None | Some("w") => (false,true),
+                    Some("r") => (true,false),
+                    Some("rw") => (true,true),
+                    Some(other) => return Err(format!("[{}] is not a watch mode. use r, w or rw.",other))
+                };
+                self.vm.add_watchpoint(Watchpoint{addr,read,write});
+                println!("watchpoint on {}",self.location(addr));
+            },
+            "unwatch" =>{
+                let addr = self.parse_value(args.first().ok_or("expected an address or label.")?)?;
+                self.vm.remove_watchpoint(addr);
+            },
+            "p" | "print" =>{
+                println!("{}",self.print(&args)?);
+            },
+            "set" =>{
+                self.set(&args)?;
+            },
+            "x" | "disasm" =>{
+                let addr = match args.first(){
+                    Some(arg) => self.parse_value(arg)?,
+                    None => self.vm.get_program_counter()
+                };
+                let count = match args.get(1){
+                    Some(arg) => self.parse_value(arg)? as usize,
+                    None => 11
+                };
+                println!("{}",self.disassemble(addr,count));
+            },
+            "history" =>{
+                if let Some(arg) = args.first(){
+                    let limit = arg.parse::<usize>().map_err(|_| format!("[{}] is not a valid history length.",arg))?;
+                    self.vm.set_history_limit(limit);
+                }
+                println!("back can go up to {} instructions.",self.vm.get_history_limit());
+            },
+            "l" | "labels" =>{
+                for (label,addr) in &self.labels{
+                    println!("{:02X} {}",addr,label);
+                }
+            },
+            "save" =>{
+                let path = args.first().ok_or("expected a file name.")?;
+                std::fs::write(path,self.vm.save_state().serialize()).map_err(|e| format!("{}",e))?;
+                println!("saved the state to {}",path);
+            },
+            "restore" =>{
+                let path = args.first().ok_or("expected a file name.")?;
+                let source = std::fs::read_to_string(path).map_err(|e| format!("{}",e))?;
+                self.vm.restore_state(&Snapshot::parse(source.as_str())?);
+                println!("{}",self.disassemble(self.vm.get_program_counter(),1));
+            },
+            "m" | "memory" =>{
+                match args.first(){
+                    None => println!("{}",self.vm.dump_memory()),
+                    Some(&"diff") => println!("{}",self.vm.diff_memory()),
+                    Some(other) => return Err(format!("[{}] is not a memory view. use memory or memory diff.",other))
+                }
+            },
+            "h" | "help" =>{
+                println!("{}",HELP);
+            },
+            "q" | "quit" =>{
+                return Ok(false);
+            },
+            _=>{
+                return Err(format!("[{}] is not a debugger command. type help for a list of commands.",command));
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// run until the pc reaches the stop address, a breakpoint or the program halts
+    /// @return the reason the run stopped
+    fn run_until(&mut self, stop_at : Option<u8>)->String{
+        let mut count = 0;
+        while !self.vm.is_halted(){
+            self.vm.step();
+            count+=1;
+
+            if let Some(message) = self.watch_message(){
+                return message;
+            }
+
+            let pc = self.vm.get_program_counter();
+            if Some(pc) == stop_at{
+                return format!("stopped after {} instructions.",count);
+            }
+            if self.breakpoint_hit(pc){
+                return format!("breakpoint hit at {}.",self.location(pc));
+            }
+            if count >= RUN_LIMIT{
+                return format!("stopped after {} instructions without reaching a breakpoint.",count);
+            }
+        }
+        String::from("program has halted.")
+    }
+
+    /// undo instructions until a breakpoint, a write to a watched address or the start of the history
+    fn run_back(&mut self)->String{
+        let mut count = 0;
+        while let Some(entry) = self.vm.step_back(){
+            count+=1;
+
+            let watched = self.vm.get_watchpoints().iter().any(|w| w.write && entry.ram.iter().any(|(a,_)| *a == w.addr));
+            if watched{
+                return format!("watchpoint: went back {} instructions to a write of a watched address.",count);
+            }
+
+            let pc = self.vm.get_program_counter();
+            if self.breakpoint_hit(pc){
+                return format!("breakpoint hit at {} after going back {} instructions.",self.location(pc),count);
+            }
+        }
+        format!("reached the start of the recorded history after going back {} instructions.",count)
+    }
+
+    /// check for a breakpoint at the address whose condition holds
+    fn breakpoint_hit(&self, pc : u8)->bool{
+        self.breakpoints.iter().any(|(addr,condition)| *addr == pc && match condition{
+            Some(condition) => condition.evaluate(&self.vm) != 0,
+            None => true
+        })
+    }
+
+    /// describe the watched access made by the last instruction
+    /// or the write to code it made when code writes are errors
+    fn watch_message(&self)->Option<String>{
+        if let Some((_,message)) = self.vm.take_code_fault(){
+            return Some(format!("{}.",message));
+        }
+        let hit = self.vm.take_watch_hit()?;
+        Some(match hit.access{
+            Access::Read  => format!("watchpoint: read {:02X} from {} by instruction at {}.",
+                                     hit.new,self.location(hit.addr),self.location(hit.pc)),
+            Access::Write => match hit.old{
+                Some(old) => format!("watchpoint: write {} {:02X} -> {:02X} by instruction at {}.",
+                                     self.location(hit.addr),old,hit.new,self.location(hit.pc)),
+                None => format!("watchpoint: write {:02X} to {} by instruction at {}.",
+                                hit.new,self.location(hit.addr),self.location(hit.pc))
+            }
+        })
+    }
+
+    fn print(&self, args : &[&str])->Result<String,String>{
+        let vm = &self.vm;
+        match args.first().copied(){
+            None =>{
+                Ok(format!("PC[{:02X}] Registers[A:{:02X},B:{:02X},C:{:02X},D:{:02X}] {} count:{}{}",
+                           vm.get_program_counter(),
+                           vm.get_register_data(Register::A),
+                           vm.get_register_data(Register::B),
+                           vm.get_register_data(Register::C),
+                           vm.get_register_data(Register::D),
+                           self.print_flags(),
+                           vm.get_instruction_count(),
+                           if vm.is_halted() {" (halted)"} else {""}))
+            },
+            Some("flags") => Ok(self.print_flags()),
+            Some("pc") => Ok(format!("pc={:02X} {}",vm.get_program_counter(),self.location(vm.get_program_counter()))),
+            Some("ram") =>{
+                let addr = self.parse_value(args.get(1).ok_or("expected a ram address or label.")?)?;
+                let count = match args.get(2){
+                    Some(arg) => self.parse_value(arg)? as usize,
+                    None => 1
+                };
+                let ram = vm.get_ram();
+                let bytes : Vec<String> = (0..count).map(|i| format!("{:02X}",ram[(addr as usize + i) % 256])).collect();
+                Ok(format!("[{:02X}]={}",addr,bytes.join(" ")))
+            },
+            Some(name) =>{
+                if let Some(register) = Debugger::parse_register(name){
+                    let value = vm.get_register_data(register);
+                    Ok(format!("{:?}={:02X} ({})",register,value,value))
+                }else if let Some(flag) = Flag::from_name(name){
+                    Ok(format!("{}={}",name,vm.flags.get(flag) as u8))
+                }else{
+                    Err(format!("unable to print [{}].",name))
+                }
+            }
+        }
+    }
+
+    fn print_flags(&self)->String{
+        let flags = &self.vm.flags;
+        format!("Flags[C:{}, L:{}, Z:{}, O:{}, S:{}]",
+                flags.carry.get() as u8,
+                flags.less_than.get() as u8,
+                flags.zero.get() as u8,
+                flags.overflow.get() as u8,
+                flags.sign.get() as u8)
+    }
+
+    fn set(&self, args : &[&str])->Result<(),String>{
+        let usage = "usage: set <a|b|c|d|pc|flag> <value> or set ram <addr> <value>";
+        let name = args.first().ok_or(usage)?;
+
+        if *name == "ram"{
+            let addr  = self.parse_value(args.get(1).ok_or(usage)?)?;
+            let value = self.parse_value(args.get(2).ok_or(usage)?)?;
+            self.vm.poke(addr,value);
+            return Ok(());
+        }
+
+        let value = self.parse_value(args.get(1).ok_or(usage)?)?;
+        if let Some(register) = Debugger::parse_register(name){
+            self.vm.set_register_data(register,value);
+        }else if let Some(flag) = Flag::from_name(name){
+            self.vm.flags.set(flag,value != 0);
+        }else if *name == "pc"{
+            self.vm.set_program_counter(value);
+            self.vm.set_halted(false);
+        }else{
+            return Err(String::from(usage));
+        }
+        Ok(())
+    }
+
+    /// disassemble count instructions centered on the address
+    fn disassemble(&self, addr : u8, count : usize)->String{
+        let ram = self.vm.get_ram();
+        let end = self.program_size.max(addr as usize + count * 2);
+        let listing = disassembler::decode_all(&self.isa,&ram,end);
+
+        let window : Vec<disassembler::Decoded> = match listing.iter().position(|d| d.addr == addr){
+            Some(index) =>{
+                let start = index.saturating_sub(count / 2);
+                listing.into_iter().skip(start).take(count).collect()
+            },
+            None =>{
+                // the address is not on an instruction boundary so decode from it directly
+                let mut decoded = Vec::new();
+                let mut current = addr;
+                for _ in 0..count{
+                    let instruction = disassembler::decode(&self.isa,&ram,current);
+                    current = instruction.next_addr();
+                    decoded.push(instruction);
+                }
+                decoded
+            }
+        };
+
+        let pc = self.vm.get_program_counter();
+        let mut lines = Vec::new();
+        for instruction in window{
+            let label = self.labels.iter().find(|(_,a)| *a == instruction.addr).map(|(l,_)| format!("{}:",l)).unwrap_or_default();
+            lines.push(format!("{}{} {:02X}: {:<12} {}",
+                               if instruction.addr == pc {"=>"} else {"  "},
+                               if self.breakpoints.iter().any(|(b,_)| *b == instruction.addr) {"*"} else {" "},
+                               instruction.addr,
+                               label,
+                               instruction.format(&self.isa,&self.labels)));
+        }
+        lines.join("\n")
+    }
+
+    /// address with the closest label before it, ex: 0A <loop+2>
+    fn location(&self, addr : u8)->String{
+        match self.vm.label_at(addr){
+            Some(label) => format!("{:02X} <{}>",addr,label),
+            None => format!("{:02X}",addr)
+        }
+    }
+
+    /// a number (decimal, 0x or 0b) or a label
+    fn parse_value(&self, text : &str)->Result<u8,String>{
+        if let Some(value) = isa::parse_number(text){
+            return Ok(value as u8);
+        }
+        self.labels.iter().find(|(l,_)| l.eq_ignore_ascii_case(text)).map(|(_,a)| *a)
+            .ok_or(format!("[{}] is not a number or a label.",text))
+    }
+
+    fn parse_register(name : &str)->Option<Register>{
+        let mut chars = name.chars();
+        match (chars.next(),chars.next()){
+            (Some(c),None) => Register::from_char(c),
+            _=> None
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::io::{self, Read, Write};
+
+/// a memory mapped device
+/// reads and writes to the mapped addresses go to the device instead of ram
+pub trait Device{
+    fn name(&self)->&str;
+
+    /// number of consecutive addresses the device takes
+    fn size(&self)->u8{
+        1
+    }
+
+    /// @param port offset from the start address of the device
+    /// @param cycles instructions executed so far
+    fn read(&mut self, port : u8, cycles : usize)->u8;
+
+    fn write(&mut self, port : u8, data : u8, cycles : usize);
+}
+
+/// a device and the first address it is mapped to
+pub struct MappedDevice{
+    pub start  : u8,
+    pub device : Box<dyn Device>
+}
+
+impl MappedDevice{
+    pub fn contains(&self, addr : u8)->bool{
+        addr >= self.start && (addr as usize) < self.start as usize + self.device.size() as usize
+    }
+}
+
+/// prints every byte written to it as a character
+pub struct ConsoleOut{
+    output : Box<dyn Write>
+}
+
+impl ConsoleOut{
+    pub fn create(output : Box<dyn Write>)->Self{
+        ConsoleOut{output}
+    }
+}
+
+impl Device for ConsoleOut{
+    fn name(&self)->&str{"out"}
+
+    /// reading the output port gives 0
+    fn read(&mut self, _port : u8, _cycles : usize)->u8{
+        0
+    }
+
+    fn write(&mut self, _port : u8, data : u8, _cycles : usize){
+        // nothing sensible to do if the terminal is gone
+        let _ = self.output.write_all(&[data]);
+        let _ = self.output.flush();
+    }
+}
+
+/// every read takes the next byte of the input, 0 once it runs out
+pub struct ConsoleIn{
+    input : Box<dyn Read>
+}
+
+impl ConsoleIn{
+    pub fn create(input : Box<dyn Read>)->Self{
+        ConsoleIn{input}
+    }
+}
+
+impl Device for ConsoleIn{
+    fn name(&self)->&str{"in"}
+
+    fn read(&mut self, _port : u8, _cycles : usize)->u8{
+        let mut byte = [0u8;1];
+        match self.input.read(&mut byte){
+            Ok(1) => byte[0],
+            _=> 0
+        }
+    }
+
+    /// writes to the input port are ignored
+    fn write(&mut self, _port : u8, _data : u8, _cycles : usize){}
+}
+
+/// xorshift random numbers, writing a byte reseeds the generator
+pub struct Random{
+    state : u32
+}
+
+impl Random{
+    pub fn create(seed : u32)->Self{
+        Random{state: if seed == 0 {0x2545_F491} else {seed}}
+    }
+}
+
+impl Device for Random{
+    fn name(&self)->&str{"random"}
+
+    fn read(&mut self, _port : u8, _cycles : usize)->u8{
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        (self.state >> 24) as u8
+    }
+
+    fn write(&mut self, _port : u8, data : u8, _cycles : usize){
+        *self = Random::create(data as u32);
+    }
+}
+
+/// instructions executed since the last write (or the start) wrapped to a byte
+pub struct CycleCounter{
+    start : usize
+}
+
+impl CycleCounter{
+    pub fn create()->Self{
+        CycleCounter{start:0}
+    }
+}
+
+impl Device for CycleCounter{
+    fn name(&self)->&str{"cycles"}
+
+    fn read(&mut self, _port : u8, cycles : usize)->u8{
+        (cycles - self.start.min(cycles)) as u8
+    }
+
+    fn write(&mut self, _port : u8, _data : u8, cycles : usize){
+        self.start = cycles;
+    }
+}
+
+/// create one of the built in devices by name
+/// the console devices use stdin and stdout
+pub fn create(name : &str)->Option<Box<dyn Device>>{
+    match name{
+        "out"    =>{Some(Box::new(ConsoleOut::create(Box::new(io::stdout()))))},
+        "in"     =>{Some(Box::new(ConsoleIn::create(Box::new(io::stdin()))))},
+        "random" =>{Some(Box::new(Random::create(0)))},
+        "cycles" =>{Some(Box::new(CycleCounter::create()))},
+        _=>{None}
+    }
+}
@@ -0,0 +1,10 @@
+pub mod compiler;
+pub mod cli;
+pub mod vm;
+pub mod debugger;
+pub mod devices;
+pub mod trace;
+pub mod coverage;
+pub mod snapshot;
+pub mod machine;
+pub mod testing;
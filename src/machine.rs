@@ -0,0 +1,427 @@
+use std::sync::Arc;
+
+use crate::compiler::{self, Ops, Program, Register};
+use crate::compiler::isa::{Environment, Extension, Flag, Isa, Target, Var};
+use crate::snapshot::{Field, Snapshot};
+use crate::vm::{ALU, FlagProfile, RunStatus};
+
+/// what a single step did
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum StepResult{
+    Running,    // an instruction ran and the program goes on
+    Halted      // the machine is halted, either by this instruction or before it
+}
+
+/// the state an instruction reads and changes
+/// the Machine keeps it as plain data, the VirtualMachine adds devices, watchpoints and the undo log
+pub trait State{
+    fn get_register(&self, register : Register)->u8;
+    fn set_register(&mut self, register : Register, data : u8);
+    fn get_flags(&self)->u8;    // packed with Flags::pack
+    fn set_flags(&mut self, flags : u8);
+    fn get_pc(&self)->u8;
+    fn is_halted(&self)->bool;
+    fn set_halted(&mut self, halt : bool);
+    /// instruction bytes and immediates, read without side effects
+    fn fetch(&self, addr : u8)->u8;
+    /// a ram read by ld or an isa extension
+    fn read(&self, addr : u8)->u8;
+    /// a ram write by st or an isa extension
+    fn write(&mut self, addr : u8, data : u8);
+
+    fn get_flag(&self, flag : Flag)->bool{
+        self.get_flags() & flag_bit(flag) != 0
+    }
+
+    fn set_flag(&mut self, flag : Flag, f : bool){
+        let flags = self.get_flags() & !flag_bit(flag);
+        self.set_flags(if f {flags | flag_bit(flag)} else {flags});
+    }
+}
+
+/// the bit of the flag in Flags::pack
+fn flag_bit(flag : Flag)->u8{
+    1 << Flag::ALL.iter().position(|f| *f == flag).unwrap()
+}
+
+/// run the instruction at the pc, the only place the instruction set is executed
+/// @return the address of the next instruction
+pub fn execute<S : State>(state : &mut S, isa : &Isa, profile : FlagProfile)->u8{
+    let pc = state.get_pc();
+    let instruction = state.fetch(pc);
+    let imm = state.fetch(pc.wrapping_add(1));
+    let left  = Register::from_bits((instruction & 0b0000_1100) >> 2);
+    let right = Register::from_bits(instruction & 0b0000_0011);
+    let x = state.get_register(left);
+    let y = state.get_register(right);
+
+    // where the pc goes unless the instruction jumps
+    let mut next = pc.wrapping_add(1);
+
+    match instruction{
+        0b0000_0000 =>{},                               // NOOP
+        0b0000_0001 => state.set_halted(true),          // HALT
+        0b0100_0000..=0b0100_0101 =>{                   // JUMP IMMEDIATE (IF FLAG)
+            let taken = match instruction & 0b111{
+                0 => true,
+                1 => state.get_flag(Flag::Less),
+                2 => state.get_flag(Flag::Overflow),
+                3 => state.get_flag(Flag::Sign),
+                4 => state.get_flag(Flag::Carry),
+                _ => state.get_flag(Flag::Zero)
+            };
+            next = if taken {imm} else {pc.wrapping_add(2)};
+        },
+        0b0110_0000..=0b0110_1011 =>{                   // JUMP TO Y IF LESS, OVERFLOW, SIGN
+            let flag = [Flag::Less,Flag::Overflow,Flag::Sign][((instruction & 0b0000_1100) >> 2) as usize];
+            if state.get_flag(flag){
+                next = y;
+            }
+        },
+        0b0110_1100..=0b0110_1111 =>{                   // LOAD IMMEDIATE
+            state.set_register(right,imm);
+            next = pc.wrapping_add(2);
+        },
+        _=>{
+            if let Some(ext) = isa.decode(instruction){
+                next = run_extension(state,ext,instruction);
+            }else{
+                match instruction >> 4{
+                    0b0101 => state.set_register(left,y),       // COPY REGISTER X=Y
+                    0b0111 =>{                                  // LOAD X = RAM[Y]
+                        let data = state.read(y);
+                        state.set_register(left,data);
+                    },
+                    0b1000 => alu(state,profile,Ops::Add,left,right),
+                    0b1001 => alu(state,profile,Ops::Sub,left,right),
+                    0b1010 => alu(state,profile,Ops::RightShift,left,right),
+                    0b1011 =>{
+                        match instruction & 0b0000_0011{
+                            0 => alu(state,profile,Ops::Not,left,left),
+                            1 => next = x,
+                            2 => if state.get_flag(Flag::Carry) {next = x;},
+                            _ => if state.get_flag(Flag::Zero) {next = x;}
+                        }
+                    },
+                    0b1100 => alu(state,profile,Ops::And,left,right),
+                    0b1101 => alu(state,profile,if left == right {Ops::Increment} else {Ops::Or},left,right),
+                    0b1110 => alu(state,profile,if left == right {Ops::Decrement} else {Ops::Compare},left,right),
+                    0b1111 => state.write(y,x),                 // STORE RAM[Y] = X
+                    _=>{}
+                }
+            }
+        }
+    }
+    next
+}
+
+fn alu<S : State>(state : &mut S, profile : FlagProfile, op : Ops, left : Register, right : Register){
+    let (result,flags) = ALU::evaluate(op,state.get_register(left),state.get_register(right),state.get_flags(),profile)
+        .expect("not an alu operation");
+    state.set_flags(flags);
+    state.set_register(left,result);
+}
+
+/// execute the micro-ops of an isa extension
+/// @return the next pc
+fn run_extension<S : State>(state : &mut S, ext : &Extension, instruction : u8)->u8{
+    let pc = state.get_pc();
+    let (x,y) = ext.decode(instruction);
+    let imm = if ext.get_byte_count() == 2 {state.fetch(pc.wrapping_add(1))} else {0};
+    let mut temps : Vec<isize> = Vec::new();
+    let mut next_pc : Option<u8> = None;
+
+    for micro_op in &ext.micro_ops{
+        let (value,addr) = {
+            let context = MicroContext{state:&*state,x,y,imm,pc,temps:&temps};
+            let addr = match &micro_op.target{
+                Target::Ram(addr) => Some(addr.evaluate(&context) as u8),
+                Target::Var(_) => None
+            };
+            (micro_op.value.evaluate(&context),addr)
+        };
+        if let Some(addr) = addr{
+            state.write(addr,value as u8);
+        }else if let Target::Var(var) = &micro_op.target{
+            match var{
+                Var::X => state.set_register(x,value as u8),
+                Var::Y => state.set_register(y,value as u8),
+                Var::Reg(r) => state.set_register(*r,value as u8),
+                Var::Flag(flag) => state.set_flag(*flag,value != 0),
+                Var::Pc => next_pc = Some(value as u8),
+                Var::Halt => state.set_halted(value != 0),
+                Var::Temp(index) =>{
+                    if *index >= temps.len(){
+                        temps.resize(*index+1,0);
+                    }
+                    temps[*index] = value;
+                },
+                Var::Imm => {}
+            }
+        }
+    }
+
+    next_pc.unwrap_or(pc.wrapping_add(ext.get_byte_count()))
+}
+
+/// everything that decides what the program does next: pc, registers, flags and ram
+pub type LoopState = (u8,[u8;4],u8,[u8;256]);
+
+/// brent's cycle detection: compare every state with a checkpoint that
+/// moves forward at powers of two
+pub struct LoopDetector{
+    checkpoint : LoopState,
+    power      : usize,
+    length     : usize
+}
+
+impl LoopDetector{
+
+    pub fn create(start : LoopState)->Self{
+        LoopDetector{checkpoint:start,power:1,length:0}
+    }
+
+    /// @return true if the state came back, the program can never halt
+    pub fn repeats(&mut self, state : LoopState)->bool{
+        if state == self.checkpoint{
+            return true;
+        }
+        self.length+=1;
+        if self.length == self.power{
+            self.checkpoint = state;
+            self.power*=2;
+            self.length = 0;
+        }
+        false
+    }
+}
+
+/// a virtual machine made only of plain data
+/// it is Send, Sync and Clone so many programs can run on a worker pool at once
+/// there are no devices, traces or debugger hooks, the VirtualMachine has those
+#[derive(Debug,Clone,PartialEq)]
+pub struct Machine{
+    pub pc                : u8,
+    pub registers         : [u8;4],
+    pub flags             : u8,         // packed with Flags::pack
+    pub ram               : [u8;256],
+    pub halt              : bool,
+    pub instruction_count : usize,
+    program_edge          : u8,
+    flag_profile          : FlagProfile,
+    isa                   : Arc<Isa>,   // shared between clones
+    labels                : Vec<(String,u8)>
+}
+
+impl Machine{
+
+    pub fn create()->Self{
+        Machine{
+            pc                : 0,
+            registers         : [0;4],
+            flags             : 0,
+            ram               : [0;256],
+            halt              : false,
+            instruction_count : 0,
+            program_edge      : 0,
+            flag_profile      : FlagProfile::MANUAL,
+            isa               : Arc::new(Isa::create()),
+            labels            : Vec::new()
+        }
+    }
+
+    /// set the isa extensions the machine should understand
+    pub fn set_isa(&mut self, isa : Isa){
+        self.isa = Arc::new(isa);
+    }
+
+    /// which flags the alu operations update
+    pub fn set_flag_profile(&mut self, profile : FlagProfile){
+        self.flag_profile = profile;
+    }
+
+    pub fn load(&mut self, program : &Program)->Result<(),String>{
+        if program.instructions.len() > self.ram.len(){
+            return Err(format!("Too many instructions in program. Was [{}]bytes but only [{}]bytes of ram available.",program.instructions.len(),self.ram.len()));
+        }
+        for (i,instruction) in program.instructions.iter().enumerate(){
+            self.ram[i] = instruction.data;
+            self.program_edge = i as u8;
+        }
+        self.labels = program.labels.clone();
+        Ok(())
+    }
+
+    pub fn get_register(&self, register : Register)->u8{
+        self.registers[register.bits() as usize]
+    }
+
+    pub fn set_register(&mut self, register : Register, data : u8){
+        self.registers[register.bits() as usize] = data;
+    }
+
+    pub fn get_flag(&self, flag : Flag)->bool{
+        State::get_flag(self,flag)
+    }
+
+    pub fn set_flag(&mut self, flag : Flag, f : bool){
+        State::set_flag(self,flag,f);
+    }
+
+    pub fn get_field(&self, field : Field)->usize{
+        match field{
+            Field::Register(r) => self.get_register(r) as usize,
+            Field::Flag(f) => self.get_flag(f) as usize,
+            Field::Ram(addr) => self.ram[addr as usize] as usize,
+            Field::Pc => self.pc as usize,
+            Field::Steps => self.instruction_count
+        }
+    }
+
+    pub fn set_field(&mut self, field : Field, value : u8){
+        match field{
+            Field::Register(r) => self.set_register(r,value),
+            Field::Flag(f) => self.set_flag(f,value != 0),
+            Field::Ram(addr) => self.ram[addr as usize] = value,
+            Field::Pc => self.pc = value,
+            Field::Steps => self.instruction_count = value as usize
+        }
+    }
+
+    /// the closest label at or before the address, ex: loop+2
+    pub fn label_at(&self, addr : u8)->Option<String>{
+        compiler::label_at(&self.labels,self.program_edge,addr)
+    }
+
+    /// run at most limit instructions, stopping early at halt or once the
+    /// exact same state comes back (the program can never halt)
+    pub fn run(&mut self, limit : usize)->RunStatus{
+        let mut detector = LoopDetector::create(self.get_state());
+
+        for _ in 0..limit{
+            if self.step() == StepResult::Halted{
+                return RunStatus::Halted;
+            }
+            if detector.repeats(self.get_state()){
+                return RunStatus::InfiniteLoop{pc:self.pc,label:self.label_at(self.pc)};
+            }
+        }
+
+        if self.halt {RunStatus::Halted} else {RunStatus::BudgetExceeded{budget:limit}}
+    }
+
+    /// everything that decides what the program does next
+    fn get_state(&self)->LoopState{
+        (self.pc,self.registers,self.flags,self.ram)
+    }
+
+    /// run a single instruction
+    pub fn step(&mut self)->StepResult{
+        if self.halt{
+            return StepResult::Halted;
+        }
+
+        let isa = Arc::clone(&self.isa);
+        let next = execute(self,&isa,self.flag_profile);
+
+        self.pc = next;
+        self.instruction_count+=1;
+        if self.halt {StepResult::Halted} else {StepResult::Running}
+    }
+
+    /// same snapshot as the VirtualMachine so state files work with both
+    pub fn save_state(&self)->Snapshot{
+        Snapshot{
+            pc                : self.pc,
+            registers         : self.registers,
+            flags             : self.flags,
+            halt              : self.halt,
+            instruction_count : self.instruction_count,
+            mode              : 0,
+            program_edge      : self.program_edge,
+            ram               : self.ram
+        }
+    }
+
+    /// the mode of the snapshot is ignored, the machine never prints
+    pub fn restore_state(&mut self, snapshot : &Snapshot){
+        self.pc = snapshot.pc;
+        self.registers = snapshot.registers;
+        self.flags = snapshot.flags;
+        self.halt = snapshot.halt;
+        self.instruction_count = snapshot.instruction_count;
+        self.program_edge = snapshot.program_edge;
+        self.ram = snapshot.ram;
+    }
+}
+
+impl State for Machine{
+    fn get_register(&self, register : Register)->u8{
+        Machine::get_register(self,register)
+    }
+
+    fn set_register(&mut self, register : Register, data : u8){
+        Machine::set_register(self,register,data);
+    }
+
+    fn get_flags(&self)->u8{
+        self.flags
+    }
+
+    fn set_flags(&mut self, flags : u8){
+        self.flags = flags;
+    }
+
+    fn get_pc(&self)->u8{
+        self.pc
+    }
+
+    fn is_halted(&self)->bool{
+        self.halt
+    }
+
+    fn set_halted(&mut self, halt : bool){
+        self.halt = halt;
+    }
+
+    fn fetch(&self, addr : u8)->u8{
+        self.ram[addr as usize]
+    }
+
+    fn read(&self, addr : u8)->u8{
+        self.ram[addr as usize]
+    }
+
+    fn write(&mut self, addr : u8, data : u8){
+        self.ram[addr as usize] = data;
+    }
+}
+
+/// state visible to the micro-ops of an isa extension
+struct MicroContext<'a,S : State>{
+    state : &'a S,
+    x     : Register,
+    y     : Register,
+    imm   : u8,
+    pc    : u8,
+    temps : &'a [isize]
+}
+
+impl<'a,S : State> Environment for MicroContext<'a,S>{
+    fn value(&self, var : Var)->isize{
+        match var{
+            Var::X => self.state.get_register(self.x) as isize,
+            Var::Y => self.state.get_register(self.y) as isize,
+            Var::Imm => self.imm as isize,
+            Var::Pc => self.pc as isize,
+            Var::Temp(index) => self.temps.get(index).copied().unwrap_or(0),
+            Var::Reg(r) => self.state.get_register(r) as isize,
+            Var::Flag(flag) => self.state.get_flag(flag) as isize,
+            Var::Halt => self.state.is_halted() as isize
+        }
+    }
+
+    fn ram(&self, addr : u8)->u8{
+        self.state.read(addr)
+    }
+}
@@ -1,9 +1,7 @@
-mod compiler;
-mod cli;
-mod vm;
-
 use std::env;
 
+use ttpc::*;
+
 
 
 fn main() -> Result<(),String>{
@@ -44,6 +42,31 @@ fn main() -> Result<(),String>{
 }
 
 
+/// lex, parse and compile the source with the default isa
+#[cfg(test)]
+fn try_assemble(strict : bool, source : &str)->Result<compiler::Program,String>{
+    let isa = compiler::isa::Isa::create();
+    let tokens = compiler::lexer::Lexer::create().tokenize(strict,source)?;
+    let mut parser = compiler::parser::Parser::create(tokens);
+    compiler::Compiler::compile(strict,&isa,parser.generate()?)
+}
+
+#[cfg(test)]
+fn assemble(source : &str)->compiler::Program{
+    try_assemble(false,source).unwrap()
+}
+
+/// assemble the source and run it until it stops, without printing a trace
+#[cfg(test)]
+fn run(source : &str)->vm::VirtualMachine{
+    let vm = vm::VirtualMachine::create();
+    vm.trace.set(false);
+    vm.load(&assemble(source)).unwrap();
+    vm.run(false,-1);
+    vm
+}
+
+
 #[test]
 fn test_lexer_tokenization(){
     let mut lexer = compiler::lexer::Lexer::create();
@@ -152,3 +175,1411 @@ fn test_vm_ram(){
     vm.write(255,-3);
     assert_eq!(253, vm.read(255));
 }
+
+#[test]
+fn test_isa_extension(){
+    let isa = compiler::isa::Isa::parse("
+        // mul x,y - x = x * y
+        instr mul x,y
+            pattern 0001_xxyy
+            do x = x * y
+            do zero = x == 0
+        end
+
+        instr addi x,imm
+            pattern 0010_00xx
+            do x = x + imm
+        end
+    ").unwrap();
+
+    let mut lexer = compiler::lexer::Lexer::create();
+    lexer.set_isa(isa.clone());
+    let tokens = lexer.tokenize(false,"
+        ldi a, 6
+        ldi b, 7
+        mul a,b
+        addi a, 3
+        halt
+    ").unwrap();
+
+    let mut parser = compiler::parser::Parser::create(tokens);
+    parser.set_isa(isa.clone());
+    let program = compiler::Compiler::compile(false,&isa,parser.generate().unwrap()).unwrap();
+
+    assert_eq!(0b_0001_0001,program.instructions[4].data);
+    assert_eq!(0b_0010_0000,program.instructions[5].data);
+    assert_eq!(3,program.instructions[6].data);
+
+    let vm = vm::VirtualMachine::create();
+    vm.set_isa(isa.clone());
+    vm.load(&program).unwrap();
+    vm.run(false,-1);
+
+    assert_eq!(45,vm.get_register_data(compiler::Register::A));
+    assert!(!vm.flags.zero.get());
+
+    let mut machine = machine::Machine::create();
+    machine.set_isa(isa);
+    machine.load(&program).unwrap();
+    machine.run(100);
+    assert_eq!(vm.save_state(),machine.save_state());
+
+    // extensions can not take over built-in opcodes
+    assert!(compiler::isa::Isa::parse("instr bad x\n pattern 1111_00xx\n end").is_err());
+}
+
+#[test]
+fn test_program_from_image(){
+    let program = compiler::Program::from_image(b"v2.0 raw\n6C 05 3*00\n01").unwrap();
+    let bytes : Vec<u8> = program.instructions.iter().map(|i| i.data).collect();
+    assert_eq!(vec![0x6C,0x05,0,0,0,0x01],bytes);
+
+    // anything without the logisim header is a binary image
+    let program = compiler::Program::from_image(&[0x6C,0x05,0x01]).unwrap();
+    assert_eq!(3,program.instructions.len());
+
+    assert!(compiler::Program::from_image(b"v2.0 raw\nZZ").is_err());
+}
+
+#[test]
+fn test_debugger_commands(){
+    let isa = compiler::isa::Isa::create();
+    let program = assemble("
+        ldi a, 0
+        ldi b, 3
+        ldi c, 1
+    loop:
+        add a, c
+        cmp a, b
+        jli loop
+        halt
+    ");
+    assert_eq!(vec![(String::from("loop"),6)],program.labels);
+    assert_eq!(Some(String::from("loop+1")),program.label_at(7));
+    assert_eq!(None,program.label_at(0x20));
+
+    let vm = vm::VirtualMachine::create();
+    vm.load(&program).unwrap();
+    assert_eq!(program.label_at(7),vm.label_at(7));
+    let mut debugger = debugger::Debugger::create(vm,&program,isa);
+
+    // break on the label and continue twice through the loop
+    debugger.execute("break loop").unwrap();
+    debugger.execute("continue").unwrap();
+    assert_eq!(6,debugger.vm.get_program_counter());
+    assert_eq!(0,debugger.vm.get_register_data(compiler::Register::A));
+    debugger.execute("c").unwrap();
+    assert_eq!(1,debugger.vm.get_register_data(compiler::Register::A));
+
+    // an empty line repeats the last command
+    debugger.execute("").unwrap();
+    assert_eq!(2,debugger.vm.get_register_data(compiler::Register::A));
+
+    debugger.execute("set a 0x10").unwrap();
+    debugger.execute("step 2").unwrap();
+    assert_eq!(0x11,debugger.vm.get_register_data(compiler::Register::A));
+
+    debugger.execute("delete loop").unwrap();
+    debugger.execute("continue").unwrap();
+    assert!(debugger.vm.is_halted());
+
+    assert!(debugger.execute("print e").is_err());
+    assert_eq!(Ok(false),debugger.execute("quit"));
+
+    // patching code from the debugger is not a write by the program
+    let program = assemble("
+        ldi a, 1
+        nop
+        inc a
+        halt
+    ");
+    let vm = vm::VirtualMachine::create();
+    vm.trace.set(false);
+    vm.set_code_guard(vm::CodeGuard::Error);
+    vm.load(&program).unwrap();
+    let mut debugger = debugger::Debugger::create(vm,&program,compiler::isa::Isa::create());
+    debugger.execute("set ram 2 0xDF").unwrap();
+    debugger.execute("continue").unwrap();
+    assert!(debugger.vm.is_halted());
+    assert_eq!(None,debugger.vm.take_code_fault());
+    assert_eq!(0xDF,debugger.vm.get_ram()[2]);
+}
+
+#[test]
+fn test_watchpoints_and_conditional_breakpoints(){
+    let isa = compiler::isa::Isa::create();
+    let program = assemble("
+        ldi d, 0
+        ldi a, 3
+        ldi c, 1
+    push:
+        dec d
+        st (d), a
+        sub a, c
+        jzi done
+        jmpi push
+    done:
+        halt
+    ");
+
+    let vm = vm::VirtualMachine::create();
+    vm.load(&program).unwrap();
+
+    // the vm records the first watched access of an instruction
+    vm.add_watchpoint(vm::Watchpoint{addr:0xFE,read:false,write:true});
+    while vm.get_register_data(compiler::Register::D) != 0xFE{
+        vm.step();
+        assert_eq!(None,vm.take_watch_hit());
+    }
+    vm.step();
+    let hit = vm.take_watch_hit().unwrap();
+    assert_eq!(vm::Access::Write,hit.access);
+    assert_eq!(7,hit.pc);
+    assert_eq!(2,hit.new);
+    assert_eq!(None,vm.take_watch_hit());
+
+    let vm = vm::VirtualMachine::create();
+    vm.load(&program).unwrap();
+    let mut debugger = debugger::Debugger::create(vm,&program,isa);
+    debugger.execute("break push if a == 1").unwrap();
+    debugger.execute("continue").unwrap();
+    assert_eq!(6,debugger.vm.get_program_counter());
+    assert_eq!(1,debugger.vm.get_register_data(compiler::Register::A));
+
+    assert!(debugger.execute("break push if q == 1").is_err());
+}
+
+#[test]
+fn test_vm_step_back(){
+    let program = assemble("
+        ldi d, 0
+        ldi a, 3
+        ldi c, 1
+    push:
+        dec d
+        st (d), a
+        sub a, c
+        jzi done
+        jmpi push
+    done:
+        halt
+    ");
+
+    let vm = vm::VirtualMachine::create();
+    vm.set_recording(true);
+    vm.load(&program).unwrap();
+    vm.run(false,-1);
+    assert!(vm.is_halted());
+    assert_eq!(1,vm.read(0xFD));
+
+    // the last write to FD was 4 instructions ago by st (d),a
+    assert_eq!(Some(4),vm.steps_since_write(0xFD));
+    for _ in 0..4{
+        vm.step_back().unwrap();
+    }
+    assert_eq!(7,vm.get_program_counter());
+    assert_eq!(0,vm.read(0xFD));
+    assert!(!vm.is_halted());
+
+    // going all the way back restores the starting state
+    while vm.step_back().is_some(){}
+    assert_eq!(0,vm.get_program_counter());
+    assert_eq!(0,vm.get_instruction_count());
+    assert_eq!(0,vm.read(0xFF));
+    assert!(!vm.flags.zero.get());
+
+    // going back and forward again counts every instruction once
+    let vm = vm::VirtualMachine::create();
+    vm.trace.set(false);
+    vm.set_recording(true);
+    vm.load(&program).unwrap();
+    vm.run(false,-1);
+    let (profile,coverage) = (vm.profile_report(5),vm.get_coverage());
+    for _ in 0..8{
+        vm.step_back().unwrap();
+    }
+    vm.run(false,-1);
+    assert_eq!(profile,vm.profile_report(5));
+    assert_eq!(coverage,vm.get_coverage());
+
+    // the history keeps the newest instructions up to the limit
+    vm.set_history_limit(3);
+    assert!(vm.step_back().is_some());
+    vm.run(false,-1);
+    assert_eq!(3,std::iter::from_fn(|| vm.step_back()).count());
+
+    // the byte is no longer written once the st is undone
+    let program = assemble("
+        ldi a, 1
+        ldi b, buffer
+        st (b), a
+        jmpi buffer
+    buffer:
+        byte 0
+    ");
+    let vm = vm::VirtualMachine::create();
+    vm.trace.set(false);
+    vm.set_recording(true);
+    vm.set_code_guard(vm::CodeGuard::Error);
+    vm.load(&program).unwrap();
+    for _ in 0..3{
+        vm.step();
+    }
+    vm.step_back().unwrap();
+    vm.set_program_counter(7);
+    vm.step();
+    assert_eq!(None,vm.take_code_fault());
+}
+
+#[test]
+fn test_vm_memory_dump_and_diff(){
+    let program = assemble("
+        ldi d, 253
+        ldi a, 65
+        st (d), a
+    done:
+        halt
+    ");
+
+    let vm = vm::VirtualMachine::create();
+    vm.trace.set(false);
+    vm.load(&program).unwrap();
+    assert!(vm.diff_memory().contains("no changes"));
+    vm.run(false,-1);
+
+    let dump = vm.dump_memory();
+    let rows : Vec<&str> = dump.lines().collect();
+    assert_eq!(18,rows.len());
+    // the program ends at 05 and the stack pointer is at FD
+    assert!(rows[2].starts_with("00:   6F FD 6C 41 F3 01|00"));
+    assert!(rows[2].ends_with("done=05"));
+    assert!(rows[17].contains(">41"));
+    assert!(rows[17].contains(".A.."));
+
+    assert_eq!("MEMORY DIFF (load -> now):\n FD: 00 -> 41\n",vm.diff_memory());
+}
+
+#[test]
+fn test_vm_devices(){
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use devices::Device;
+
+    // shared buffer so the test can see what the console printed
+    struct Output(Rc<RefCell<Vec<u8>>>);
+    impl std::io::Write for Output{
+        fn write(&mut self, buf : &[u8])->std::io::Result<usize>{
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self)->std::io::Result<()>{Ok(())}
+    }
+
+    // echo two characters from the input to the output then read the cycle counter
+    let program = assemble("
+        ldi c, 254
+        ldi d, 255
+        ld a, (c)
+        st (d), a
+        ld a, (c)
+        st (d), a
+        dec c
+        ld b, (c)
+        halt
+    ");
+
+    let printed = Rc::new(RefCell::new(Vec::new()));
+    let vm = vm::VirtualMachine::create();
+    vm.trace.set(false);
+    vm.load(&program).unwrap();
+    vm.map_device(255,Box::new(devices::ConsoleOut::create(Box::new(Output(printed.clone()))))).unwrap();
+    vm.map_device(254,Box::new(devices::ConsoleIn::create(Box::new(std::io::Cursor::new(b"hi".to_vec()))))).unwrap();
+    vm.map_device(253,Box::new(devices::CycleCounter::create())).unwrap();
+    assert!(vm.map_device(254,devices::create("random").unwrap()).is_err());
+    vm.run(false,-1);
+
+    assert_eq!(b"hi".to_vec(),*printed.borrow());
+    assert_eq!(7,vm.get_register_data(compiler::Register::B));
+    // device addresses are not backed by ram
+    assert_eq!(0,vm.get_ram()[255]);
+
+    // a watched device write has no old value
+    let vm = vm::VirtualMachine::create();
+    vm.trace.set(false);
+    vm.load(&program).unwrap();
+    vm.write(255,0x41);
+    vm.map_device(255,Box::new(devices::ConsoleOut::create(Box::new(Output(printed.clone()))))).unwrap();
+    vm.add_watchpoint(vm::Watchpoint{addr:255,read:false,write:true});
+    vm.write(255,0x42);
+    let hit = vm.take_watch_hit().unwrap();
+    assert_eq!((None,0x42),(hit.old,hit.new));
+
+    let mut random = devices::Random::create(7);
+    let first = random.read(0,0);
+    random.write(0,7,0);
+    assert_eq!(first,random.read(0,0));
+}
+
+#[test]
+fn test_vm_trace_entries(){
+    let program = assemble("
+        ldi c, 200
+        ldi a, 5
+        st (c), a
+        ld b, (c)
+        add a, b
+        halt
+    ");
+
+    let vm = vm::VirtualMachine::create();
+    vm.load(&program).unwrap();
+    assert_eq!(None,vm.last_trace());
+    for _ in 0..3{
+        vm.step();
+    }
+    let store = vm.last_trace().unwrap();
+    assert_eq!(2,store.count);
+    assert_eq!(4,store.pc);
+    assert_eq!("st",store.mnemonic);
+    assert_eq!("(c),a",store.operands);
+    assert_eq!(vec![(200,5)],store.writes);
+    assert_eq!(5,store.next_pc);
+
+    vm.step();
+    let load = vm.last_trace().unwrap();
+    assert_eq!(vec![(200,5)],load.reads);
+    assert_eq!([5,0,200,0],load.registers_before);
+    assert_eq!([5,5,200,0],load.registers_after);
+    assert_eq!("3,5,118,ld,\"b,(c)\",5,0,200,0,5,5,200,0,0,0,0,0,0,6,200=5,",load.to_csv());
+    assert_eq!(trace::TraceEntry::csv_header().split(',').count(),load.to_csv().split(',').count() - 1);
+    assert!(load.to_json().starts_with("{\"count\":3,\"pc\":5,\"opcode\":118,\"mnemonic\":\"ld\",\"operands\":\"b,(c)\","));
+    assert!(load.to_json().ends_with("\"next_pc\":6,\"reads\":[{\"addr\":200,\"value\":5}],\"writes\":[]}"));
+
+    // the text trace keeps its original mnemonics and only shows the target of a taken jump
+    let vm = vm::VirtualMachine::create();
+    vm.load(&assemble("
+        ldi c, 200
+        ld b, (c)
+        jzi done
+    done:
+        halt
+    ")).unwrap();
+    vm.step();
+    assert_eq!("001 : PC[02]->(OP[load] B=00,C=c8) | B=00 | RAM_R[c8]=00 | FLAGS[ c=0 z=0 s=0 o=0 l=0 ]",vm.step());
+    assert_eq!("002 : PC[03]->(OP[jzi ]          ) |      |              | FLAGS[ c=0 z=0 s=0 o=0 l=0 ]",vm.step());
+
+    // a trace that can not be written stops the run on the first failure
+    struct Broken(std::rc::Rc<std::cell::Cell<usize>>);
+    impl std::io::Write for Broken{
+        fn write(&mut self, _ : &[u8])->std::io::Result<usize>{
+            self.0.set(self.0.get() + 1);
+            Err(std::io::Error::other("disk full"))
+        }
+        fn flush(&mut self)->std::io::Result<()>{
+            Ok(())
+        }
+    }
+    let attempts = std::rc::Rc::new(std::cell::Cell::new(0));
+    let vm = vm::VirtualMachine::create();
+    vm.trace.set(false);
+    vm.load(&program).unwrap();
+    vm.set_trace_writer(trace::TraceWriter::create(trace::TraceFormat::JsonLines,Box::new(Broken(attempts.clone()))).unwrap());
+    assert_eq!(vm::RunStatus::TraceFailed{message:String::from("disk full")},vm.run(false,-1));
+    assert_eq!(1,attempts.get());
+    assert_eq!(1,vm.get_instruction_count());
+}
+
+#[test]
+fn test_trace_diff(){
+    let program = assemble("
+        ldi a, 1
+        ldi b, 255
+        add a, b
+        halt
+    ");
+
+    // logisim style log, binary values and an unknown column
+    let reference = trace::ReferenceTrace::parse("PC\tA\tReg B\tclock\tcarry\tZ
+0000 0000\t0000 0000\t0000 0000\t0\t0\t0
+0000 0010\t0000 0001\t0000 0000\t1\t0\t0
+0000 0100\t0000 0001\t1111 1111\t0\t0\t0
+0000 0101\t0000 0000\t1111 1111\t1\t1\t1
+0000 0110\t0000 0000\t1111 1111\t0\t1\t1
+").unwrap();
+
+    let vm = vm::VirtualMachine::create();
+    vm.load(&program).unwrap();
+    assert_eq!(trace::DiffResult::Match{steps:5},reference.diff(&vm));
+
+    // the same run against a log where add did not set the carry
+    let reference = trace::ReferenceTrace::parse("pc,a,b,cf,zf
+0,0,0,0,0
+2,1,0,0,0
+4,1,255,0,0
+5,0,255,0,1
+").unwrap();
+    let vm = vm::VirtualMachine::create();
+    vm.load(&program).unwrap();
+    match reference.diff(&vm){
+        trace::DiffResult::Diverged{step,differences} =>{
+            assert_eq!(3,step);
+            assert_eq!(1,differences.len());
+            assert_eq!("carry",differences[0].column.name());
+            assert_eq!((0,1),(differences[0].expected,differences[0].actual));
+        },
+        other => panic!("expected a divergence, got {:?}",other)
+    }
+
+    assert!(trace::ReferenceTrace::parse("time,clock\n0,1\n").is_err());
+    assert!(trace::ReferenceTrace::parse("pc,a\n0,300\n").is_err());
+}
+
+#[test]
+fn test_vm_hang_detection(){
+    // waits on a flag that nothing ever changes
+    let vm = vm::VirtualMachine::create();
+    vm.trace.set(false);
+    vm.load(&assemble("
+        ldi a, 0
+        ldi b, 1
+    spin:
+        cmp a, b
+        jzi done
+        jmpi spin
+    done:
+        halt
+    ")).unwrap();
+    let status = vm.run(false,-1);
+    assert!(status.is_hang());
+    match &status{
+        vm::RunStatus::InfiniteLoop{pc,label} => assert!(*pc >= 4 && label.as_ref().unwrap().starts_with("spin")),
+        other => panic!("expected an infinite loop, got {:?}",other)
+    }
+    assert!(vm.get_instruction_count() < 20);
+
+    // counting forever never repeats a state within the budget
+    let vm = vm::VirtualMachine::create();
+    vm.trace.set(false);
+    vm.set_budget(Some(300));
+    vm.load(&assemble("
+        ldi c, 0
+    count:
+        inc c
+        jmpi count
+    ")).unwrap();
+    assert_eq!(vm::RunStatus::BudgetExceeded{budget:300},vm.run(false,-1));
+    assert_eq!("program did not halt within the budget of 300 instructions",format!("{}",vm.run(false,-1)));
+
+    let vm = vm::VirtualMachine::create();
+    vm.trace.set(false);
+    vm.load(&assemble("
+    spin:
+        jmpi spin
+    ")).unwrap();
+    assert_eq!("program is in an infinite loop at PC 0x00 (label `spin`)",format!("{}",vm.run(false,-1)));
+    assert_eq!(vm::RunStatus::Interrupted,{
+        let vm = vm::VirtualMachine::create();
+        vm.trace.set(false);
+        vm.run(true,10)
+    });
+}
+
+#[test]
+fn test_vm_profile(){
+    let source = "
+        ldi a, 7
+        ldi b, 6
+        ldi c, 0
+        ldi d, 1
+    mul:
+        add c, a
+        sub b, d
+        jzi done
+        jmpi mul
+    done:
+        halt
+    ";
+
+    let vm = run(source);
+    assert_eq!(42,vm.get_register_data(compiler::Register::C));
+
+    let report = vm.profile_report(2);
+    let lines : Vec<&str> = report.lines().collect();
+    assert_eq!("PROFILE: 28 instructions",lines[0]);
+    assert_eq!("mul                         23   82.1%",lines[3]);
+    assert_eq!("<start>                      4   14.3%",lines[4]);
+    // ties are listed by address
+    assert!(lines[8].starts_with("08             6   21.4%  add c,a         mul"));
+    assert!(lines[9].starts_with("09             6   21.4%  sub b,d         mul+1"));
+    assert!(report.ends_with("0C jmpi mul         -> 08 mul          repeated 5 times\n"));
+
+    // loading again starts a new profile
+    vm.load(&assemble(source)).unwrap();
+    assert!(vm.profile_report(2).starts_with("PROFILE: 0 instructions"));
+}
+
+#[test]
+fn test_vm_coverage(){
+    let source = "ldi c, value
+ld a, (c)
+cmp a, b
+jzi equal
+inc b
+equal:
+halt
+value:
+byte 3";
+    let isa = compiler::isa::Isa::create();
+    let program = assemble(source);
+    assert_eq!(compiler::LineInfo{line:9,addr:8,size:1,data:true},*program.lines.last().unwrap());
+
+    let run = |b : u8|{
+        let vm = vm::VirtualMachine::create();
+        vm.trace.set(false);
+        vm.load(&program).unwrap();
+        vm.set_register_data(compiler::Register::B,b);
+        vm.run(false,-1);
+        vm.get_coverage()
+    };
+
+    // b is not equal to the value so the jump falls through
+    let mut total = run(0);
+    let listing = total.listing(&isa,source,&program.lines);
+    let lines : Vec<&str> = listing.lines().collect();
+    assert_eq!("COVERAGE: 6 of 6 instructions executed (100.0%), 0 of 1 branches went both ways",lines[0]);
+    assert_eq!("        1:04:   4: jzi equal  // never taken",lines[4]);
+    assert_eq!("     data:08:   9: byte 3  // read",lines[9]);
+
+    // an equal value skips inc b, together both runs cover the branch
+    let other = run(3);
+    assert!(other.listing(&isa,source,&program.lines).contains("    #####:06:   5: inc b\n"));
+    total.merge(&other).unwrap();
+    let listing = total.listing(&isa,source,&program.lines);
+    assert!(listing.starts_with("COVERAGE: 6 of 6 instructions executed (100.0%), 1 of 1 branches went both ways"));
+    assert!(listing.contains("        2:04:   4: jzi equal\n"));
+
+    // the coverage file keeps everything
+    let parsed = coverage::Coverage::parse(total.serialize().as_str()).unwrap();
+    assert_eq!(total,parsed);
+    let mut different = coverage::Coverage::create(vec![1],[0;256],[0;256]);
+    assert!(different.merge(&total).is_err());
+}
+
+#[test]
+fn test_vm_save_and_restore_state(){
+    let program = assemble("
+        ldi d, 0
+        ldi a, 3
+        ldi c, 1
+    push:
+        dec d
+        st (d), a
+        sub a, c
+        jzi done
+        jmpi push
+    done:
+        halt
+    ");
+
+    let vm = vm::VirtualMachine::create();
+    vm.trace.set(false);
+    vm.load(&program).unwrap();
+    vm.run(true,6);
+    assert!(!vm.is_halted());
+
+    let state = vm.save_state();
+    let text = state.serialize();
+    assert!(text.starts_with("ttpc state\npc 0B\nregisters 02 00 01 FF\nflags 00\nhalt false\ncount 7\nmode 0\nedge 0D\nram\n"));
+    assert!(text.ends_with("00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 03\n"));
+    assert_eq!(state,snapshot::Snapshot::parse(text.as_str()).unwrap());
+
+    // finish from the saved state in a fresh vm
+    let other = vm::VirtualMachine::create();
+    other.trace.set(false);
+    other.load(&program).unwrap();
+    other.restore_state(&snapshot::Snapshot::parse(text.as_str()).unwrap());
+    other.run(false,-1);
+    vm.run(false,-1);
+    assert!(other.is_halted());
+    assert_eq!(vm.save_state(),other.save_state());
+
+    assert!(snapshot::Snapshot::parse("ttpc state\npc 00\nram\n00 01\n").is_err());
+    assert!(snapshot::Snapshot::parse("pc 00").is_err());
+}
+
+#[test]
+fn test_flag_profiles(){
+    use compiler::Register::{A, B};
+    use vm::{ALU, FlagProfile};
+
+    // run one alu operation on a and b starting from the given packed flags
+    // @return a and the packed flags [carry sign overflow less zero]
+    let run = |profile : FlagProfile, op : fn(&vm::VirtualMachine,compiler::Register,compiler::Register), a : u8, b : u8, flags : u8|{
+        let vm = vm::VirtualMachine::create();
+        vm.set_flag_profile(profile);
+        vm.set_register_data(A,a);
+        vm.set_register_data(B,b);
+        vm.flags.unpack(flags);
+        op(&vm,A,B);
+        (vm.get_register_data(A),vm.flags.pack())
+    };
+    let not = |vm : &vm::VirtualMachine, x : compiler::Register, _ : compiler::Register| ALU::not(vm,x);
+
+    for profile in FlagProfile::ALL.iter(){
+        // add: every flag, 0x80 + 0x80 = 0 with carry and overflow
+        assert_eq!((0,0b1_0111),run(*profile,ALU::add,0x80,0x80,0));
+        assert_eq!((3,0),run(*profile,ALU::add,1,2,0b1_1111));
+        // and, or and right shift: zero, less and sign only, carry and overflow kept
+        assert_eq!((0,0b1_0101),run(*profile,ALU::and,0x0F,0xF0,0b1_0100));
+        assert_eq!((0xFF,0b0_1010),run(*profile,ALU::or,0x80,0x7F,0));
+        assert_eq!((0,0b0_0011),run(*profile,ALU::right_shift,0x80,8,0));
+        assert_eq!((0x20,0b0_0010),run(*profile,ALU::right_shift,0x80,2,0b0_1010));
+        // sub and cmp: zero, less, overflow and sign do not depend on the profile
+        assert_eq!(0b0_0001,run(*profile,ALU::cmp,5,5,0b1_0000).1 & 0b0_1111);
+        assert_eq!(0b0_1010,run(*profile,ALU::sub,1,2,0).1 & 0b0_1111);
+    }
+
+    // not updates zero, less and sign in the manual but nothing in the 2021 circuit
+    assert_eq!((0x00,0b0_0011),run(FlagProfile::MANUAL,not,0xFF,0,0));
+    assert_eq!((0x00,0b1_0100),run(FlagProfile::LOGISIM_2021,not,0xFF,0,0b1_0100));
+
+    // the subtraction carry is a borrow in the manual and the adder carry in the circuit
+    assert_eq!((0xFF,0b1_1010),run(FlagProfile::MANUAL,ALU::sub,1,2,0));
+    assert_eq!((0xFF,0b0_1010),run(FlagProfile::LOGISIM_2021,ALU::sub,1,2,0));
+    assert_eq!((5,0b0_0001),run(FlagProfile::MANUAL,ALU::cmp,5,5,0));
+    assert_eq!((5,0b1_0001),run(FlagProfile::LOGISIM_2021,ALU::cmp,5,5,0));
+
+    assert_eq!(Some(FlagProfile::LOGISIM_2021),FlagProfile::from_name("logisim-2021"));
+    assert_eq!(None,FlagProfile::from_name("2019"));
+}
+
+#[test]
+fn test_machine(){
+    let source = "
+        ldi a, 0
+        ldi b, 5
+        ldi c, 1
+        ldi d, result
+    loop:
+        add a, b
+        sub b, c
+        jzi done
+        jmpi loop
+    done:
+        st (d), a
+        ld b, (d)
+        rsh b, c
+        not b
+        and b, a
+        or b, c
+        inc c
+        dec c
+        cmp b, a
+        ldi d, end
+        jl d
+        halt
+    end:
+        halt
+    result:
+        byte 0
+    ";
+    let program = assemble(source);
+
+    fn shareable<T : Send + Sync + Clone>(){}
+    shareable::<machine::Machine>();
+
+    // same final state as the VirtualMachine
+    let vm = run(source);
+
+    let mut machine = machine::Machine::create();
+    machine.load(&program).unwrap();
+    assert_eq!(vm::RunStatus::Halted,machine.run(1000));
+    assert_eq!(vm.save_state(),machine.save_state());
+    assert_eq!(15,machine.ram[program.instructions.len() - 1]);
+    assert_eq!(machine::StepResult::Halted,machine.step());
+
+    // clones run on their own threads without sharing state
+    let mut template = machine::Machine::create();
+    template.load(&program).unwrap();
+    let workers : Vec<_> = (0..4u8).map(|i|{
+        let mut worker = template.clone();
+        std::thread::spawn(move ||{
+            // past ldi b, 5
+            worker.step();
+            worker.step();
+            worker.set_register(compiler::Register::B,i + 1);
+            while worker.step() == machine::StepResult::Running {}
+            worker.get_register(compiler::Register::A)
+        })
+    }).collect();
+    let sums : Vec<u8> = workers.into_iter().map(|w| w.join().unwrap()).collect();
+    assert_eq!(vec![1,3,6,10],sums);
+    assert_eq!(0,template.instruction_count);
+
+    // the run limit and loop detection
+    let mut machine = template.clone();
+    assert_eq!(vm::RunStatus::BudgetExceeded{budget:5},machine.run(5));
+    machine.restore_state(&template.save_state());
+    machine.ram[0] = 64;    // jmpi 0
+    assert_eq!(vm::RunStatus::InfiniteLoop{pc:0,label:None},machine.run(100));
+}
+
+#[test]
+fn test_program_tests(){
+    let source = "
+    //! expect halted steps<=10
+    //! input a=3 b=4
+    //! expect a=7 ram[result]=7 zero=0
+    //! input a=250, b=10
+    //! expect a=4 carry=1 a<5 steps=4
+    //! input a=1 b=1
+    //! expect a!=2
+        add a, b
+        ldi c, result
+        st (c), a
+        halt
+    result:
+        byte 0
+    ";
+    let program = assemble(source);
+
+    let cases = testing::TestCase::parse(source,true,&program.labels).unwrap();
+    assert_eq!(3,cases.len());
+    assert_eq!("a=250, b=10",cases[1].input);
+    // the checks before the first input are shared by every case
+    assert_eq!(5,cases[0].expectations.len());
+
+    let mut machine = machine::Machine::create();
+    machine.load(&program).unwrap();
+    let results : Vec<testing::CaseResult> = cases.iter().map(|c| c.run(&machine,Some(100))).collect();
+    assert!(results[0].passed());
+    assert!(results[1].passed());
+    assert_eq!(vec![String::from("expected a!=2 but it was 2")],results[2].failures);
+
+    // a budget that is too small fails the halted check
+    let result = cases[0].run(&machine,Some(2));
+    assert_eq!(2,result.steps);
+    assert!(result.failures[0].starts_with("expected halted but the program did not halt"));
+
+    // sidecar files have the same directives without the prefix
+    let cases = testing::TestCase::parse("# comment\ninput d=-1\nexpect d=255 halted",false,&program.labels).unwrap();
+    assert!(cases[0].run(&machine,None).passed());
+
+    assert!(testing::TestCase::parse("//! expect q=1",true,&[]).is_err());
+    assert!(testing::TestCase::parse("//! input steps=1",true,&[]).is_err());
+    assert!(testing::TestCase::parse("//! expect ram[missing]=1",true,&[]).is_err());
+    assert!(testing::TestCase::parse("//! check a=1",true,&[]).is_err());
+}
+
+#[test]
+fn test_initial_state(){
+    use snapshot::Field;
+    let labels = vec![(String::from("list"),0x40)];
+
+    let assignments = snapshot::parse_assignments("a=5 zero=1 ram[0x80..0x84]=1,2,3,-1 ram[list+1]=7,8 ram[list..list+2]=0 pc=0x10",&labels).unwrap();
+    assert_eq!(vec![
+        (Field::Register(compiler::Register::A),5),
+        (Field::Flag(compiler::isa::Flag::Zero),1),
+        (Field::Ram(0x80),1),(Field::Ram(0x81),2),(Field::Ram(0x82),3),(Field::Ram(0x83),255),
+        (Field::Ram(0x41),7),(Field::Ram(0x42),8),
+        (Field::Ram(0x40),0),(Field::Ram(0x41),0),
+        (Field::Pc,0x10)
+    ],assignments);
+
+    assert!(snapshot::parse_assignments("ram[0x80..0x84]=1,2",&labels).is_err());
+    assert!(snapshot::parse_assignments("ram[0x84..0x80]=1",&labels).is_err());
+    assert!(snapshot::parse_assignments("ram[0xFF]=1,2",&labels).is_err());
+    assert!(snapshot::parse_assignments("a=1,2",&labels).is_err());
+    assert!(snapshot::parse_assignments("a=300",&labels).is_err());
+    assert!(snapshot::parse_assignments("ram[nowhere]=1",&labels).is_err());
+    assert!(snapshot::parse_assignments("steps=1",&labels).is_err());
+
+    // injected bytes are part of the starting ram
+    let program = assemble("
+        ldi c, list
+        ld a, (c)
+        inc c
+        ld b, (c)
+        add a, b
+        halt
+    list:
+        byte 0
+        byte 0
+    ");
+
+    let vm = vm::VirtualMachine::create();
+    vm.trace.set(false);
+    vm.load(&program).unwrap();
+    for (field,value) in snapshot::parse_assignments("ram[list..list+2]=30,12",&program.labels).unwrap(){
+        vm.set_field(field,value);
+    }
+    vm.run(false,-1);
+    assert_eq!(42,vm.get_register_data(compiler::Register::A));
+    assert!(vm.diff_memory().contains("no changes"));
+}
+
+#[test]
+fn test_cfg(){
+    use compiler::cfg::{Cfg, EdgeKind};
+    let isa = compiler::isa::Isa::create();
+    let program = assemble("
+        ldi a, 5
+        ldi b, 0
+    loop:
+        add b, a
+        dec a
+        jzi done
+        jmpi loop
+    done:
+        ldi c, back
+        inc c
+        dec c
+        jmp c
+    back:
+        halt
+    table:
+        byte 3
+    ");
+
+    let cfg = Cfg::build(&isa,&program);
+    let starts : Vec<u8> = cfg.blocks.iter().map(|b| b.start).collect();
+    assert_eq!(vec![0x00,0x04,0x08,0x0A,0x0F],starts);
+
+    let looped = cfg.block_at(0x04).unwrap();
+    assert_eq!(3,looped.instructions.len());
+    assert_eq!(EdgeKind::Taken,looped.successors[0].kind);
+    assert_eq!(0x0A,looped.successors[0].target);
+    assert_eq!(0x08,looped.successors[1].target);
+    assert_eq!(0x04,cfg.block_at(0x08).unwrap().successors[0].target);
+
+    // the register jump target is a known constant
+    let done = cfg.block_at(0x0A).unwrap();
+    assert!(!done.indirect);
+    assert_eq!(0x0F,done.successors[0].target);
+    assert!(cfg.block_at(0x0F).unwrap().halts());
+    assert!(cfg.block_at(0x0F).unwrap().successors.is_empty());
+    assert!(cfg.reachable().iter().all(|r| *r));
+
+    let dot = cfg.to_dot(&isa);
+    assert!(dot.starts_with("digraph cfg {"));
+    assert!(dot.contains("b04 [label=\"loop:\\l04: add b,a\\l"));
+    assert!(dot.contains("b04 -> b0A [label=\"zero\"]"));
+    assert!(dot.contains("b0A -> b0F;"));
+    assert!(!dot.contains("unknown"));
+
+    // a jump through a register loaded from ram has no known target
+    let image = compiler::Program::from_image(&[0x6C,0x10,0x70,0xB1,0x01]).unwrap();
+    let cfg = Cfg::build(&isa,&image);
+    assert!(cfg.blocks[0].indirect);
+    assert!(cfg.to_dot(&isa).contains("b00 -> unknown [style=dotted];"));
+}
+
+#[test]
+fn test_lint(){
+    let compile = |source : &str|{
+        let isa = compiler::isa::Isa::create();
+        let program = assemble(source);
+        compiler::lint::check(&isa,&program,&[])
+    };
+
+    // the return address is only known through ldi
+    assert!(compile("
+        ldi d, back
+        jmpi double
+    back:
+        halt
+    double:
+        add a, a
+        jmp d
+    ").is_empty());
+
+    let warnings = compile("
+        ldi a, 1
+        jzi end
+        jmpi over
+        ldi b, 2
+        add b, b
+    over:
+        inc a
+    table:
+        byte 3
+        byte 4
+    end:
+        jmpi end
+    ");
+    let messages : Vec<String> = warnings.iter().map(|w| w.to_string()).collect();
+    assert_eq!(vec![
+        "unreachable code at line:5 (0x06)",
+        "execution can fall through into data `table` at 0x0A at line:8 (0x09)",
+        "no path from the start of the program reaches halt at line:2 (0x00)"
+    ],messages);
+
+    assert_eq!("jump into the middle of an instruction at 0x01",compile("
+        jmpi 1
+        halt
+    ")[0].message);
+}
+
+#[test]
+fn test_stack_depth(){
+    let analyze = |source : &str|{
+        let isa = compiler::isa::Isa::create();
+        let program = assemble(source);
+        let cfg = compiler::cfg::Cfg::build(&isa,&program);
+        compiler::stack::analyze(&isa,&program,&cfg)
+    };
+
+    // the second call pushes an argument that the subroutine never pops
+    let report = analyze("
+        ldi b, back
+        dec d
+        st (d), b
+        jmpi double
+    back:
+        ldi b, done
+        dec d
+        st (d), b
+        dec d
+        st (d), a
+        jmpi double
+    done:
+        halt
+    double:
+        dec d
+        st (d), a
+        add a, a
+        ld b, (d)
+        inc d
+        ld c, (d)
+        inc d
+        jmp c
+    ").unwrap();
+    assert_eq!(Some(3),report.max_depth);
+    assert_eq!(1,report.warnings.len());
+    assert_eq!("unbalanced stack, 2 bytes are pushed for the call to `double` but it pops 1 at line:12 (0x0C)",report.warnings[0].to_string());
+
+    // a push inside a loop
+    let report = analyze("
+    loop:
+        dec d
+        st (d), a
+        dec a
+        jzi loop
+        halt
+    ").unwrap();
+    assert!(report.warnings[0].message.starts_with("unbalanced stack, `loop` is reached with 0 and 1 bytes pushed"));
+
+    let report = analyze("
+        ldi b, back
+        dec d
+        st (d), b
+        jmpi count
+    back:
+        halt
+    count:
+        ldi b, again
+        dec d
+        st (d), b
+        jmpi count
+    again:
+        ld c, (d)
+        inc d
+        jmp c
+    ").unwrap();
+    assert_eq!(None,report.max_depth);
+    assert!(report.warnings[0].message.starts_with("recursive call to `count`"));
+
+    // the stack starts too low and runs into the program
+    let report = analyze("
+    start:
+        ldi d, 4
+        dec d
+        st (d), a
+        halt
+        byte 0
+    ").unwrap();
+    assert_eq!(Some(253),report.max_depth);
+    assert!(report.warnings[0].message.ends_with("down to 0x03 (`start+3`) which overwrites the program"));
+
+    // d used as a plain register is not a stack
+    assert!(analyze("
+        ldi d, 3
+    loop:
+        dec d
+        jzi loop
+        halt
+    ").is_none());
+}
+
+#[test]
+fn test_constant_propagation(){
+    let build = |source : &str|{
+        let isa = compiler::isa::Isa::create();
+        let program = assemble(source);
+        (isa,program)
+    };
+
+    // c is set before the loop and the jump table entry is never written
+    let (isa,program) = build("
+        ldi c, exit
+        ldi b, table
+        ld b, (b)
+    loop:
+        dec a
+        jzi out
+        jmpi loop
+    out:
+        jmp c
+    exit:
+        jz b
+        halt
+    other:
+        halt
+    table:
+        byte other
+    ");
+    let cfg = compiler::cfg::Cfg::build(&isa,&program);
+    let out = cfg.block_at(0x0A).unwrap();
+    assert!(!out.indirect);
+    assert_eq!(0x0B,out.successors[0].target);
+    let exit = cfg.block_at(0x0B).unwrap();
+    assert_eq!(0x0D,exit.successors[0].target);
+    assert_eq!(Some([None,Some(0x0D),Some(0x0B),None]),cfg.constants.entry(0x05));
+    assert_eq!(Some(0x0D),cfg.constants.memory(0x0E));
+    assert!(compiler::lint::check(&isa,&program,&[]).is_empty());
+
+    // values that differ between paths are not constants
+    let (isa,program) = build("
+        ldi c, one
+        jzi skip
+        ldi c, two
+    skip:
+        jmp c
+    one:
+        halt
+    two:
+        halt
+    ");
+    let cfg = compiler::cfg::Cfg::build(&isa,&program);
+    assert!(cfg.block_at(0x06).unwrap().indirect);
+
+    // target falls through with a known but the unknown jump can get there too
+    let (isa,program) = build("
+        ldi a, 7
+        ldi c, target
+        jzi over
+    target:
+        cpr b, a
+        halt
+    over:
+        ldi a, 9
+        jzi go
+        ldi c, over
+    go:
+        jmp c
+    ");
+    let cfg = compiler::cfg::Cfg::build(&isa,&program);
+    assert!(cfg.block_at(0x0E).unwrap().indirect);
+    assert_eq!(Some([None;4]),cfg.constants.entry(0x06));
+
+    // a store through an unknown pointer can change any byte
+    let (isa,program) = build("
+        ldi b, table
+        st (a), b
+        ld b, (b)
+        halt
+    table:
+        byte 7
+    ");
+    let cfg = compiler::cfg::Cfg::build(&isa,&program);
+    assert_eq!(None,cfg.constants.memory(0x05));
+
+    let (isa,program) = build("
+        ldi b, 128
+        ld a, (b)
+        ldi c, 200
+        jmp c
+    ");
+    let messages : Vec<String> = compiler::lint::check(&isa,&program,&[]).iter().map(|w| w.message.clone()).collect();
+    assert_eq!(vec!["load from 0x80 which the program never writes","jump outside the program to 0xC8","no path from the start of the program reaches halt"],messages);
+    assert_eq!(2,compiler::lint::check(&isa,&program,&[128]).len());
+}
+
+#[test]
+fn test_code_writes(){
+    let guarded = |program : &compiler::Program, guard : vm::CodeGuard|{
+        let vm = vm::VirtualMachine::create();
+        vm.trace.set(false);
+        vm.set_code_guard(guard);
+        vm.load(program).unwrap();
+        vm.run(false,-1)
+    };
+
+    // a byte variable inside the program can be written, the instruction after it can not
+    let program = assemble("
+        ldi a, 1
+        ldi b, count
+        st (b), a
+        ldi b, patch
+        st (b), a
+    patch:
+        nop
+        halt
+    count:
+        byte 0
+    ");
+    assert_eq!(vm::RunStatus::CodeFault{pc:0x07,message:String::from("instruction at PC 0x07 writes over the program at 0x08 (label `patch`)")},
+               guarded(&program,vm::CodeGuard::Error));
+    assert_eq!(vm::RunStatus::Halted,guarded(&program,vm::CodeGuard::Off));
+
+    // code built at runtime in a data buffer
+    let program = assemble("
+        ldi a, 1
+        ldi b, buffer
+        st (b), a
+        jmpi buffer
+    buffer:
+        byte 0
+    ");
+    assert_eq!(vm::RunStatus::CodeFault{pc:0x07,message:String::from("instruction at PC 0x07 (label `buffer`) was written at runtime by the instruction at PC 0x04")},
+               guarded(&program,vm::CodeGuard::Error));
+    assert_eq!(vm::RunStatus::Halted,guarded(&program,vm::CodeGuard::Warn));
+
+    // restoring a state forgets the writes made after it
+    let vm = vm::VirtualMachine::create();
+    vm.trace.set(false);
+    vm.set_code_guard(vm::CodeGuard::Error);
+    vm.load(&program).unwrap();
+    let state = vm.save_state();
+    for _ in 0..3{
+        vm.step();
+    }
+    vm.restore_state(&state);
+    vm.set_program_counter(7);
+    vm.step();
+    assert_eq!(None,vm.take_code_fault());
+}
+
+#[test]
+fn test_optimizer(){
+    let optimize = |source : &str|{
+        let tokens = compiler::lexer::Lexer::create().tokenize(false,source).unwrap();
+        let mut parser = compiler::parser::Parser::create(tokens);
+        let isa = compiler::isa::Isa::create();
+        parser.generate().unwrap();
+        compiler::optimizer::optimize(false,&isa,&mut parser.root).map(|r| (r,compiler::Compiler::compile(false,&isa,&parser.root).unwrap()))
+    };
+
+    // numbers past the end of the program can not be code addresses
+    let (removed,program) = optimize("
+        ldi a, 100
+        ldi a, 200
+        cpr b, b
+        jmpi next
+    next:
+        inc c
+        dec c
+        ldi b, 50
+    loop:
+        dec a
+        jzi loop
+        ld b, (a)
+        halt
+    ").unwrap();
+    let expected = assemble("
+        ldi a, 200
+        ldi b, 50
+    loop:
+        dec a
+        jzi loop
+        ld b, (a)
+        halt
+    ");
+    let lines : Vec<u32> = removed.iter().map(|r| r.line).collect();
+    assert_eq!(vec![2,4,5,7,8],lines);
+    assert_eq!(7,removed.iter().map(|r| r.bytes).sum::<u8>());
+    assert_eq!(expected.dump(),program.dump());
+    assert_eq!(Some(&(String::from("loop"),4)),program.labels.iter().find(|(l,_)| l == "loop"));
+
+    // a label between inc and dec can be jumped to so they stay
+    let (removed,_) = optimize("
+        inc a
+    back:
+        dec a
+        jmpi back
+    ").unwrap();
+    assert!(removed.is_empty());
+
+    // a number would point somewhere else once code is removed
+    assert!(optimize("
+        cpr a, a
+        jmpi 3
+        halt
+    ").is_err());
+
+    // 7 is the second halt, it would be ldi b, 9 once cpr a, a is gone
+    let source = "
+        ldi a, 1
+        cpr a, a
+        ldi d, 7
+        jmp d
+        halt
+        halt
+        ldi b, 9
+        halt
+    ";
+    let (removed,program) = optimize(source).unwrap();
+    assert!(removed.is_empty());
+    assert_eq!(assemble(source).dump(),program.dump());
+    let vm = vm::VirtualMachine::create();
+    vm.trace.set(false);
+    vm.load(&program).unwrap();
+    vm.run(false,-1);
+    assert_eq!(0,vm.get_register_data(compiler::Register::B));
+
+    // only the code up to the highest such number stays
+    let (removed,_) = optimize("
+        ldi d, 3
+        cpr a, a
+        jmp d
+        cpr b, b
+        halt
+    ").unwrap();
+    assert_eq!(vec![5],removed.iter().map(|r| r.line).collect::<Vec<u32>>());
+}
+
+#[test]
+fn test_branch_relaxation(){
+    // c holds loop on every path to br, bz has no register for done
+    let program = assemble("
+        ldi a, 3
+        ldi c, loop
+        ldi b, 1
+    loop:
+        sub a, b
+        bz done
+        br loop
+    done:
+        halt
+    ").dump();
+    let expected = assemble("
+        ldi a, 3
+        ldi c, 6
+        ldi b, 1
+        sub a, b
+        jzi 10
+        jmp c
+        halt
+    ").dump();
+    assert_eq!(expected,program);
+
+    // c is 6, which is where end is only while bz takes 2 bytes
+    let program = assemble("
+        ldi c, 6
+        ldi b, 1
+        bz end
+    end:
+        halt
+    ").dump();
+    assert_eq!(assemble("ldi c, 6\nldi b, 1\njzi 6\nhalt").dump(),program);
+
+    // there is also reached by jmp d with c set to 0 so br keeps its address
+    let source = "
+        ldi c, back
+        ldi d, there
+        jzi over
+    there:
+        br back
+    back:
+        halt
+    over:
+        ldi c, 0
+        jzi go
+        ldi d, back
+    go:
+        jmp d
+    ";
+    assert_eq!(assemble(&source.replace("br back","jmpi back")).dump(),assemble(source).dump());
+
+    assert!(try_assemble(false,"bz c").is_err());
+}
+
+#[test]
+fn test_structured_control_flow(){
+    let registers = |vm : vm::VirtualMachine|{
+        assert!(vm.is_halted());
+        [compiler::Register::A,compiler::Register::B,compiler::Register::C,compiler::Register::D].iter().map(|r| vm.get_register_data(*r)).collect::<Vec<u8>>()
+    };
+
+    let source = "
+        ldi a, 5
+        ldi b, 0
+        ldi c, 3
+        loop c
+            inc b
+        endloop
+        ldi d, 1
+        cmp a, b
+        if l
+            ldi a, 100
+        else
+            ldi a, 50
+        endif
+        while nz
+            sub b, d
+        endw
+        halt
+    ";
+    assert_eq!(vec![50,0,0,1],registers(run(source)));
+    assert_eq!(assemble(source).dump(),try_assemble(true,source).unwrap().dump());
+
+    // a count of 0 skips the body, if without else
+    let source = "
+        ldi b, 7
+        ldi c, 0
+        loop c
+            inc b
+        endloop
+        and c, c
+        if z
+            inc a
+        endif
+        halt
+    ";
+    assert_eq!(vec![1,7,0,0],registers(run(source)));
+    assert!(assemble(source).labels.iter().any(|(l,_)| l == "_endif8"));
+
+    assert_eq!(Err(String::from("the if at line:1 has no endif.")),try_assemble(false,"if z\nhalt").map(|_| ()));
+    assert_eq!(Err(String::from("[endw] at line:3 has no matching block, the if at line:1 is still open.")),try_assemble(false,"if z\nhalt\nendw").map(|_| ()));
+    assert!(try_assemble(false,"loop 5\nendloop").is_err());
+}
+
+#[test]
+fn test_subcommands(){
+    let expand = |args : &[&str]| cli::expand_subcommand(args.iter().map(|a| String::from(*a)).collect());
+
+    assert_eq!(vec!["--test","cases"],expand(&["test","cases"]));
+    assert_eq!(vec!["--run","sum.ttpasm","-g","a=5"],expand(&["run","sum.ttpasm","-g","a=5"]));
+    assert_eq!(vec!["--debug","prog.ttpasm","-s"],expand(&["debug","prog.ttpasm","-s"]));
+    assert_eq!(vec!["--run","prog.ttpasm","--trace-diff","logisim.txt","-g","a=1"],expand(&["trace-diff","prog.ttpasm","logisim.txt","-g","a=1"]));
+    // only the first argument can be a subcommand
+    assert_eq!(vec!["-r","test"],expand(&["-r","test"]));
+}
+
+#[test]
+fn test_budget_paths(){
+    // about 1.2 million instructions before it halts
+    let source = "ldi c, 1\nldi d, 6\nouter:\nldi b, 0\nmiddle:\nldi a, 0\ninner:\nadd a, c\njzi next\njmpi inner\nnext:\nadd b, c\njzi again\njmpi middle\nagain:\nsub d, c\njzi done\njmpi outer\ndone:\nhalt";
+    let path = env::temp_dir().join(format!("ttpc_budget_{}.ttpasm",std::process::id()));
+    std::fs::write(&path,source).unwrap();
+    let file = path.to_str().unwrap();
+    let ttpc = |args : &[&str]| cli::handle_commands(&cli::parse_commands(args.iter().map(|a| String::from(*a)))?);
+
+    // -c -a has no limit unless -B gives one
+    assert_eq!(Ok(()),ttpc(&["-c",file,"-a"]));
+    assert_eq!(Err(String::from("program did not halt within the budget of 1000 instructions.")),ttpc(&["-c",file,"-a","-B","1000"]));
+    // -r stops at the default budget
+    assert_eq!(Err(String::from("program did not halt within the budget of 1000000 instructions.")),ttpc(&["-r",file]));
+
+    std::fs::remove_file(&path).unwrap();
+}
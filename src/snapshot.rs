@@ -0,0 +1,186 @@
+use crate::compiler::Register;
+use crate::compiler::isa::{self, Flag};
+
+const HEADER : &str = "ttpc state";
+
+/// the complete state of a virtual machine
+#[derive(Debug,Clone,PartialEq)]
+pub struct Snapshot{
+    pub pc                : u8,
+    pub registers         : [u8;4],
+    pub flags             : u8,         // packed with Flags::pack
+    pub halt              : bool,
+    pub instruction_count : usize,
+    pub mode              : u8,
+    pub program_edge      : u8,
+    pub ram               : [u8;256]
+}
+
+impl Snapshot{
+
+    /// text format, one field per line and the ram as 16 rows of 16 hex bytes
+    pub fn serialize(&self)->String{
+        let mut out = format!("{}\npc {:02X}\nregisters {:02X} {:02X} {:02X} {:02X}\nflags {:02X}\nhalt {}\ncount {}\nmode {}\nedge {:02X}\nram\n",
+                              HEADER,self.pc,self.registers[0],self.registers[1],self.registers[2],self.registers[3],
+                              self.flags,self.halt,self.instruction_count,self.mode,self.program_edge);
+        for row in self.ram.chunks(16){
+            let bytes : Vec<String> = row.iter().map(|b| format!("{:02X}",b)).collect();
+            out.push_str(bytes.join(" ").as_str());
+            out.push('\n');
+        }
+        out
+    }
+
+    pub fn parse(source : &str)->Result<Snapshot,String>{
+        let mut lines = source.lines().map(str::trim).filter(|l| !l.is_empty()).enumerate();
+        if lines.next().map(|(_,l)| l) != Some(HEADER){
+            return Err(String::from("not a ttpc state file."));
+        }
+
+        let hex = |text : &str, line : usize| u8::from_str_radix(text,16).map_err(|_| format!("[{}] is not a hex byte at line:{}",text,line + 1));
+        let mut snapshot = Snapshot{pc:0,registers:[0;4],flags:0,halt:false,instruction_count:0,mode:0,program_edge:0,ram:[0;256]};
+        let mut ram_bytes : Option<Vec<u8>> = None;
+
+        for (line,text) in lines{
+            if let Some(bytes) = ram_bytes.as_mut(){
+                for byte in text.split_whitespace(){
+                    bytes.push(hex(byte,line)?);
+                }
+                continue;
+            }
+
+            let (key,value) = text.split_once(' ').unwrap_or((text,""));
+            match key{
+                "pc"    => snapshot.pc = hex(value,line)?,
+                "flags" => snapshot.flags = hex(value,line)?,
+                "edge"  => snapshot.program_edge = hex(value,line)?,
+                "halt"  => snapshot.halt = value.parse::<bool>().map_err(|_| format!("[{}] is not true or false at line:{}",value,line + 1))?,
+                "count" => snapshot.instruction_count = value.parse::<usize>().map_err(|_| format!("[{}] is not a count at line:{}",value,line + 1))?,
+                "mode"  => snapshot.mode = value.parse::<u8>().map_err(|_| format!("[{}] is not a mode at line:{}",value,line + 1))?,
+                "registers" =>{
+                    let registers = value.split_whitespace().map(|r| hex(r,line)).collect::<Result<Vec<u8>,String>>()?;
+                    if registers.len() != 4{
+                        return Err(format!("expected 4 registers at line:{}",line + 1));
+                    }
+                    snapshot.registers.copy_from_slice(&registers);
+                },
+                "ram" => ram_bytes = Some(Vec::new()),
+                _=> return Err(format!("unknown state field [{}] at line:{}",key,line + 1))
+            }
+        }
+
+        let ram = ram_bytes.ok_or("state file is missing the ram.")?;
+        if ram.len() != 256{
+            return Err(format!("state file has {} bytes of ram instead of 256.",ram.len()));
+        }
+        snapshot.ram.copy_from_slice(&ram);
+        Ok(snapshot)
+    }
+}
+
+/// a piece of machine state that can be set before a run or checked after it
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Field{
+    Register(Register),
+    Flag(Flag),
+    Ram(u8),
+    Pc,
+    Steps       // instructions executed
+}
+
+impl Field{
+    /// a, zero, pc, steps, ram[0x80], ram[label] or ram[label+2]
+    pub fn parse(text : &str, labels : &[(String,u8)])->Result<Field,String>{
+        let name = text.trim().to_lowercase();
+        if let Some(addr) = name.strip_prefix("ram[").and_then(|a| a.strip_suffix(']')){
+            return Ok(Field::Ram(parse_address(addr,labels)?));
+        }
+
+        let mut chars = name.chars();
+        if let (Some(c),None) = (chars.next(),chars.next()){
+            if let Some(register) = Register::from_char(c){
+                return Ok(Field::Register(register));
+            }
+        }
+        match name.as_str(){
+            "pc"    => Ok(Field::Pc),
+            "steps" => Ok(Field::Steps),
+            _=> Flag::from_name(name.as_str()).map(Field::Flag).ok_or(format!("[{}] is not a register, flag, ram[addr], pc or steps.",text.trim()))
+        }
+    }
+}
+
+/// a number or a label with an optional offset, ex: 0x80, list or list+2
+pub fn parse_address(text : &str, labels : &[(String,u8)])->Result<u8,String>{
+    let text = text.trim();
+    let (name,offset) = match text.find(['+','-']){
+        Some(i) if i > 0 => (text[..i].trim(),isa::parse_number(&text[i..].replace(' ',"")).ok_or(format!("[{}] is not a valid offset.",&text[i..]))?),
+        _=> (text,0)
+    };
+    let base = match labels.iter().find(|(label,_)| label.eq_ignore_ascii_case(name)){
+        Some((_,addr)) => *addr as isize,
+        None => isa::parse_number(name).ok_or(format!("[{}] is not an address or label.",name))?
+    };
+    let addr = base + offset;
+    if (0..256).contains(&addr) {Ok(addr as u8)} else {Err(format!("[{}] is outside of ram.",text))}
+}
+
+/// a byte, negative numbers are stored as their two's complement
+pub fn parse_byte(text : &str)->Option<u8>{
+    isa::parse_number(text).filter(|v| (-128..256).contains(v)).map(|v| v as u8)
+}
+
+/// parse assignments separated by spaces, ex: a=5 zero=1 ram[0x80..0x84]=1,2,3,4
+/// a ram range takes one value per address or a single value to fill it,
+/// a ram address takes a list of values for the addresses that follow it
+/// @return every value to set, in order
+pub fn parse_assignments(text : &str, labels : &[(String,u8)])->Result<Vec<(Field,u8)>,String>{
+    let mut assignments = Vec::new();
+    for item in text.split_whitespace().map(|i| i.trim_matches(',')).filter(|i| !i.is_empty()){
+        let (target,values) = item.split_once('=').ok_or(format!("[{}] is not an assignment. ex: a=5 or ram[0x80..0x84]=1,2,3,4",item))?;
+        let values = values.split(',').filter(|v| !v.is_empty())
+            .map(|v| parse_byte(v).ok_or(format!("[{}] is not a byte in [{}].",v,item)))
+            .collect::<Result<Vec<u8>,String>>()?;
+        if values.is_empty(){
+            return Err(format!("[{}] is missing a value.",item));
+        }
+
+        let range = target.trim().to_lowercase().strip_prefix("ram[").and_then(|r| r.strip_suffix(']'))
+            .and_then(|r| r.split_once("..").map(|(start,end)| (String::from(start),String::from(end))));
+        let (start,count) = match range{
+            Some((start,end)) =>{
+                let start = parse_address(start.as_str(),labels)?;
+                let end = parse_address(end.as_str(),labels)? as usize;
+                if end <= start as usize{
+                    return Err(format!("[{}] is an empty range.",target));
+                }
+                let count = end - start as usize;
+                if values.len() != 1 && values.len() != count{
+                    return Err(format!("[{}] needs 1 or {} values but has {}.",target,count,values.len()));
+                }
+                (Field::Ram(start),count)
+            },
+            None =>{
+                let field = Field::parse(target,labels)?;
+                if field == Field::Steps{
+                    return Err(String::from("steps can not be set."));
+                }
+                if values.len() > 1 && !matches!(field,Field::Ram(_)){
+                    return Err(format!("[{}] takes a single value.",target));
+                }
+                (field,values.len())
+            }
+        };
+
+        for i in 0..count{
+            let value = if values.len() == 1 {values[0]} else {values[i]};
+            let field = match start{
+                Field::Ram(addr) if addr as usize + i > 255 => return Err(format!("[{}] runs past the end of ram.",item)),
+                Field::Ram(addr) => Field::Ram(addr + i as u8),
+                other => other
+            };
+            assignments.push((field,value));
+        }
+    }
+    Ok(assignments)
+}
@@ -0,0 +1,175 @@
+use std::fmt;
+
+use crate::compiler::isa;
+use crate::machine::Machine;
+use crate::snapshot::{self, Field};
+use crate::vm::RunStatus;
+
+/// prefix of the comment lines that hold test directives
+pub const DIRECTIVE : &str = "//!";
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Comparison{
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual
+}
+
+impl Comparison{
+    /// longest operators first so <= is not read as <
+    const SYMBOLS : [(&'static str,Comparison);7] = [
+        ("<=",Comparison::LessEqual),
+        (">=",Comparison::GreaterEqual),
+        ("!=",Comparison::NotEqual),
+        ("==",Comparison::Equal),
+        ("<",Comparison::Less),
+        (">",Comparison::Greater),
+        ("=",Comparison::Equal)
+    ];
+
+    fn holds(&self, actual : usize, expected : usize)->bool{
+        match self{
+            Comparison::Equal        => actual == expected,
+            Comparison::NotEqual     => actual != expected,
+            Comparison::Less         => actual < expected,
+            Comparison::LessEqual    => actual <= expected,
+            Comparison::Greater      => actual > expected,
+            Comparison::GreaterEqual => actual >= expected
+        }
+    }
+}
+
+/// one condition of an expect directive
+#[derive(Debug,Clone,PartialEq)]
+pub enum Expectation{
+    Halted,
+    Value{text : String, field : Field, comparison : Comparison, value : usize}
+}
+
+impl fmt::Display for Expectation{
+    fn fmt(&self, f : &mut fmt::Formatter)->fmt::Result{
+        match self{
+            Expectation::Halted => write!(f,"halted"),
+            Expectation::Value{text,..} => write!(f,"{}",text)
+        }
+    }
+}
+
+impl Expectation{
+    fn parse(text : &str, labels : &[(String,u8)])->Result<Expectation,String>{
+        if text == "halted"{
+            return Ok(Expectation::Halted);
+        }
+        let (position,symbol,comparison) = text.char_indices()
+            .find_map(|(i,_)| Comparison::SYMBOLS.iter().find(|(s,_)| text[i..].starts_with(s)).map(|(s,c)| (i,*s,*c)))
+            .ok_or(format!("[{}] is not a check. ex: a=42, ram[0x80]!=0, steps<=500 or halted",text))?;
+
+        let field = Field::parse(&text[..position],labels)?;
+        let value_text = &text[position + symbol.len()..];
+        let value = if field == Field::Steps{
+            isa::parse_number(value_text).filter(|v| *v >= 0).map(|v| v as usize)
+        }else{
+            snapshot::parse_byte(value_text).map(usize::from)
+        }.ok_or(format!("[{}] is not a valid value in [{}].",value_text,text))?;
+        Ok(Expectation::Value{text:String::from(text),field,comparison,value})
+    }
+
+    /// @return a description of the failure or None if it holds
+    fn check(&self, machine : &Machine, status : &RunStatus)->Option<String>{
+        match self{
+            Expectation::Halted =>{
+                if *status == RunStatus::Halted {None} else {Some(format!("expected halted but the {}",status))}
+            },
+            Expectation::Value{text,field,comparison,value} =>{
+                let actual = machine.get_field(*field);
+                if comparison.holds(actual,*value) {None} else {Some(format!("expected {} but it was {}",text,actual))}
+            }
+        }
+    }
+}
+
+/// initial values and expectations of one run of a program
+#[derive(Debug,Clone,PartialEq)]
+pub struct TestCase{
+    pub line         : usize,               // line of the input directive, 0 without one
+    pub input        : String,              // the inputs as written, ex: a=5 ram[list]=3,1,2
+    pub inputs       : Vec<(Field,u8)>,
+    pub expectations : Vec<Expectation>
+}
+
+/// how one test case went
+#[derive(Debug,Clone,PartialEq)]
+pub struct CaseResult{
+    pub status   : RunStatus,
+    pub steps    : usize,
+    pub failures : Vec<String>
+}
+
+impl CaseResult{
+    pub fn passed(&self)->bool{
+        self.failures.is_empty()
+    }
+}
+
+impl TestCase{
+
+    /// read the test directives from the lines of a source or sidecar file
+    /// `input a=5 ram[0x80..0x83]=1,2,3` starts a new case
+    /// `expect a=42 halted steps<=500` adds checks to the last case
+    /// checks before the first input apply to every case
+    /// @param prefixed only read lines that start with //!
+    pub fn parse(source : &str, prefixed : bool, labels : &[(String,u8)])->Result<Vec<TestCase>,String>{
+        let mut shared : Vec<Expectation> = Vec::new();
+        let mut cases : Vec<TestCase> = Vec::new();
+
+        for (number,line) in source.lines().enumerate(){
+            let line = line.trim();
+            let directive = match line.strip_prefix(DIRECTIVE){
+                Some(rest) => rest.trim(),
+                None if !prefixed && !line.starts_with("//") && !line.starts_with('#') => line,
+                None => continue
+            };
+            let error = |e : String| format!("{} at line:{}",e,number + 1);
+            let (keyword,rest) = directive.split_once(char::is_whitespace).unwrap_or((directive,""));
+
+            match keyword{
+                "" =>{},
+                "input" =>{
+                    let inputs = snapshot::parse_assignments(rest,labels).map_err(error)?;
+                    cases.push(TestCase{line:number + 1,input:String::from(rest.trim()),inputs,expectations:Vec::new()});
+                },
+                "expect" =>{
+                    let items = rest.split(|c : char| c.is_whitespace() || c == ',').filter(|i| !i.is_empty());
+                    let expectations = items.map(|i| Expectation::parse(i,labels)).collect::<Result<Vec<Expectation>,String>>().map_err(error)?;
+                    match cases.last_mut(){
+                        Some(case) => case.expectations.extend(expectations),
+                        None => shared.extend(expectations)
+                    }
+                },
+                _=> return Err(error(format!("unknown test directive [{}]. expected input or expect",keyword)))
+            }
+        }
+
+        if cases.is_empty() && !shared.is_empty(){
+            cases.push(TestCase{line:0,input:String::new(),inputs:Vec::new(),expectations:Vec::new()});
+        }
+        for case in cases.iter_mut(){
+            case.expectations.splice(0..0,shared.iter().cloned());
+        }
+        Ok(cases)
+    }
+
+    /// run a copy of the loaded machine with the inputs of the case
+    pub fn run(&self, machine : &Machine, budget : Option<usize>)->CaseResult{
+        let mut machine = machine.clone();
+        for (field,value) in &self.inputs{
+            machine.set_field(*field,*value);
+        }
+        let status = machine.run(budget.unwrap_or(usize::MAX));
+        let failures = self.expectations.iter().filter_map(|e| e.check(&machine,&status)).collect();
+        CaseResult{status,steps:machine.instruction_count,failures}
+    }
+}
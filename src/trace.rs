@@ -0,0 +1,238 @@
+use std::io::Write;
+
+use crate::compiler::Register;
+use crate::compiler::isa::{self, Flag};
+use crate::vm::VirtualMachine;
+
+/// everything one instruction did, for scripts comparing runs
+#[derive(Debug,Clone,PartialEq)]
+pub struct TraceEntry{
+    pub count            : usize,       // instructions executed before this one
+    pub pc               : u8,
+    pub opcode           : u8,
+    pub mnemonic         : String,
+    pub operands         : String,      // as written in assembly ex: a,(c)
+    pub registers_before : [u8;4],
+    pub registers_after  : [u8;4],
+    pub flags_before     : u8,          // packed with Flags::pack
+    pub flags_after      : u8,
+    pub next_pc          : u8,
+    pub reads            : Vec<(u8,u8)>,    // address and value of every ram read
+    pub writes           : Vec<(u8,u8)>
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum TraceFormat{
+    JsonLines,
+    Csv
+}
+
+impl TraceFormat{
+    /// csv for .csv files and json lines for everything else
+    pub fn from_path(path : &std::path::Path)->TraceFormat{
+        if path.extension().map(|e| e == "csv").unwrap_or(false){
+            TraceFormat::Csv
+        }else{
+            TraceFormat::JsonLines
+        }
+    }
+}
+
+/// streams trace entries to a file or any other writer
+pub struct TraceWriter{
+    format : TraceFormat,
+    output : Box<dyn Write>
+}
+
+impl TraceWriter{
+    /// writes the csv header right away
+    pub fn create(format : TraceFormat, mut output : Box<dyn Write>)->Result<Self,String>{
+        if format == TraceFormat::Csv{
+            writeln!(output,"{}",TraceEntry::csv_header()).map_err(|e| format!("{}",e))?;
+        }
+        Ok(TraceWriter{format,output})
+    }
+
+    pub fn write(&mut self, entry : &TraceEntry)->Result<(),String>{
+        let line = match self.format{
+            TraceFormat::JsonLines => entry.to_json(),
+            TraceFormat::Csv => entry.to_csv()
+        };
+        writeln!(self.output,"{}",line).map_err(|e| format!("{}",e))
+    }
+}
+
+impl TraceEntry{
+
+    pub fn csv_header()->String{
+        let mut columns = vec!["count","pc","opcode","mnemonic","operands","a_before","b_before","c_before","d_before","a","b","c","d"];
+        columns.extend(Flag::ALL.iter().map(|f| f.name()));
+        columns.extend(["next_pc","reads","writes"]);
+        columns.join(",")
+    }
+
+    /// one csv row, ram accesses are written as addr=value separated by spaces
+    pub fn to_csv(&self)->String{
+        let accesses = |list : &[(u8,u8)]| list.iter().map(|(a,v)| format!("{}={}",a,v)).collect::<Vec<String>>().join(" ");
+        let mut columns = vec![
+            format!("{}",self.count),
+            format!("{}",self.pc),
+            format!("{}",self.opcode),
+            self.mnemonic.clone(),
+            format!("\"{}\"",self.operands.replace('"',"\"\""))
+        ];
+        columns.extend(self.registers_before.iter().chain(self.registers_after.iter()).map(|r| format!("{}",r)));
+        columns.extend((0..Flag::ALL.len()).map(|bit| format!("{}",(self.flags_after >> bit) & 1)));
+        columns.push(format!("{}",self.next_pc));
+        columns.push(accesses(&self.reads));
+        columns.push(accesses(&self.writes));
+        columns.join(",")
+    }
+
+    /// one line of json
+    pub fn to_json(&self)->String{
+        let accesses = |list : &[(u8,u8)]| list.iter().map(|(a,v)| format!("{{\"addr\":{},\"value\":{}}}",a,v)).collect::<Vec<String>>().join(",");
+        format!("{{\"count\":{},\"pc\":{},\"opcode\":{},\"mnemonic\":\"{}\",\"operands\":\"{}\",\"before\":{},\"after\":{},\"next_pc\":{},\"reads\":[{}],\"writes\":[{}]}}",
+                self.count,
+                self.pc,
+                self.opcode,
+                escape(&self.mnemonic),
+                escape(&self.operands),
+                TraceEntry::state_json(&self.registers_before,self.flags_before),
+                TraceEntry::state_json(&self.registers_after,self.flags_after),
+                self.next_pc,
+                accesses(&self.reads),
+                accesses(&self.writes))
+    }
+
+    fn state_json(registers : &[u8;4], flags : u8)->String{
+        let flags : Vec<String> = Flag::ALL.iter().enumerate().map(|(bit,f)| format!("\"{}\":{}",f.name(),(flags >> bit) & 1 != 0)).collect();
+        format!("{{\"a\":{},\"b\":{},\"c\":{},\"d\":{},\"flags\":{{{}}}}}",registers[0],registers[1],registers[2],registers[3],flags.join(","))
+    }
+}
+
+fn escape(text : &str)->String{
+    text.replace('\\',"\\\\").replace('"',"\\\"")
+}
+
+/// a piece of machine state a reference trace can log
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Column{
+    Pc,
+    Register(Register),
+    Flag(Flag)
+}
+
+impl Column{
+    /// match a log header, ex: PC, A, reg_b, zero, ZF, carry
+    /// c is register c so the carry flag needs its full name or CF
+    fn from_header(header : &str)->Option<Column>{
+        let name : String = header.chars().filter(|c| c.is_alphanumeric()).collect::<String>().to_lowercase();
+        let name = name.strip_prefix("reg").filter(|r| r.len() == 1).unwrap_or(name.as_str());
+        match name{
+            "pc" =>{Some(Column::Pc)},
+            "a" | "b" | "c" | "d" =>{Register::from_char(name.chars().next().unwrap()).map(Column::Register)},
+            "z" | "zf" =>{Some(Column::Flag(Flag::Zero))},
+            "l" | "lf" | "lt" =>{Some(Column::Flag(Flag::Less))},
+            "o" | "v" | "of" | "vf" =>{Some(Column::Flag(Flag::Overflow))},
+            "s" | "n" | "sf" | "nf" =>{Some(Column::Flag(Flag::Sign))},
+            "cf" =>{Some(Column::Flag(Flag::Carry))},
+            _=>{Flag::from_name(name).map(Column::Flag)}
+        }
+    }
+
+    pub fn name(&self)->String{
+        match self{
+            Column::Pc => String::from("pc"),
+            Column::Register(r) => format!("{:?}",r).to_lowercase(),
+            Column::Flag(f) => String::from(f.name())
+        }
+    }
+
+    fn value(&self, vm : &VirtualMachine)->u8{
+        match self{
+            Column::Pc => vm.get_program_counter(),
+            Column::Register(r) => vm.get_register_data(*r),
+            Column::Flag(f) => vm.flags.get(*f) as u8
+        }
+    }
+}
+
+/// one differing column at the first divergent step
+#[derive(Debug,Clone,PartialEq)]
+pub struct Difference{
+    pub column   : Column,
+    pub expected : u8,
+    pub actual   : u8
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum DiffResult{
+    Match{steps : usize},
+    Diverged{step : usize, differences : Vec<Difference>}
+}
+
+/// a log of the machine state per clock, ex: exported from the logisim logging module
+/// each row is the state before the instruction of that step runs
+pub struct ReferenceTrace{
+    pub columns : Vec<Column>,
+    rows        : Vec<Vec<u8>>
+}
+
+impl ReferenceTrace{
+
+    /// parse a tab or comma separated log with a header row
+    /// unknown columns are ignored
+    pub fn parse(source : &str)->Result<ReferenceTrace,String>{
+        let mut lines = source.lines().enumerate().filter(|(_,l)| !l.trim().is_empty());
+        let (_,header) = lines.next().ok_or("reference trace is empty.")?;
+        let separator = if header.contains('\t') {'\t'} else {','};
+
+        let indexed : Vec<(usize,Column)> = header.split(separator).enumerate()
+            .filter_map(|(i,h)| Column::from_header(h).map(|c| (i,c))).collect();
+        if indexed.is_empty(){
+            return Err(format!("no known columns in the reference header [{}]. expected pc, a-d or flag names.",header.trim()));
+        }
+
+        let mut rows = Vec::new();
+        for (line,text) in lines{
+            let fields : Vec<&str> = text.split(separator).collect();
+            let mut row = Vec::new();
+            for (i,column) in &indexed{
+                let field = fields.get(*i).ok_or(format!("missing {} column at line:{}",column.name(),line + 1))?;
+                row.push(ReferenceTrace::parse_value(field).ok_or(format!("[{}] is not a valid {} value at line:{}",field.trim(),column.name(),line + 1))?);
+            }
+            rows.push(row);
+        }
+
+        Ok(ReferenceTrace{columns:indexed.into_iter().map(|(_,c)| c).collect(),rows})
+    }
+
+    /// decimal, 0x hex, 0b binary or 8 binary digits (logisim groups them as 0000 0101)
+    fn parse_value(field : &str)->Option<u8>{
+        let digits : String = field.chars().filter(|c| !c.is_whitespace()).collect();
+        let value = if digits.len() == 8 && digits.chars().all(|c| c == '0' || c == '1'){
+            isize::from_str_radix(digits.as_str(),2).ok()?
+        }else{
+            isa::parse_number(digits.as_str())?
+        };
+        if (0..256).contains(&value) {Some(value as u8)} else {None}
+    }
+
+    /// step the vm along the reference and stop at the first step where they differ
+    /// once the vm halts the remaining rows are compared against its final state
+    pub fn diff(&self, vm : &VirtualMachine)->DiffResult{
+        for (step,row) in self.rows.iter().enumerate(){
+            if step > 0 && !vm.is_halted(){
+                vm.step();
+            }
+            let differences : Vec<Difference> = self.columns.iter().zip(row.iter())
+                .map(|(column,expected)| Difference{column:*column,expected:*expected,actual:column.value(vm)})
+                .filter(|d| d.expected != d.actual).collect();
+            if !differences.is_empty(){
+                return DiffResult::Diverged{step,differences};
+            }
+        }
+        DiffResult::Match{steps:self.rows.len()}
+    }
+}
@@ -1,12 +1,119 @@
 use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt;
 use std::io::Write;
 
-use crate::compiler::{Program, Register};
+use crate::compiler::{Ops, Program, Register};
+use crate::compiler::{self, disassembler};
+use crate::compiler::disassembler::Decoded;
+use crate::compiler::cfg::Cfg;
+use crate::compiler::isa::{Environment, Flag, Isa, Var};
+use crate::coverage::{self, Coverage};
+use crate::devices::{Device, MappedDevice};
+use crate::machine::{self, LoopDetector, LoopState};
+use crate::snapshot::{Field, Snapshot};
+use crate::trace::{TraceEntry, TraceWriter};
+
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Access{
+    Read,
+    Write
+}
+
+/// stop when an instruction reads and/or writes the address
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct Watchpoint{
+    pub addr  : u8,
+    pub read  : bool,
+    pub write : bool
+}
+
+/// the first watched access made by the last instruction
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct WatchHit{
+    pub addr   : u8,
+    pub access : Access,
+    pub pc     : u8,        // instruction that made the access
+    pub old    : Option<u8>,    // None for devices, reading them could change them
+    pub new    : u8
+}
+
+
+/// machine state from before an instruction ran, used to step backwards
+#[derive(Debug,Clone,PartialEq)]
+pub struct UndoEntry{
+    pub pc         : u8,
+    pub registers  : [u8;4],
+    pub flags      : u8,                    // packed with Flags::pack
+    pub halt       : bool,
+    pub ram        : Vec<(u8,u8)>,          // address and old value of every byte written
+    pub written_by : Vec<(u8,Option<u8>)>,  // address and old writer of every byte written
+    pub coverage   : Vec<(u8,u8)>,          // address and old coverage bits of every byte it changed
+    pub back_edge  : Option<(u8,u8)>        // the jump backwards it counted as a loop repeat
+}
+
+/// default number of instructions the vm can step back over
+pub const DEFAULT_HISTORY : usize = 100_000;
 
+/// default instruction budget so a run that never halts still ends
+pub const DEFAULT_BUDGET : usize = 1_000_000;
+
+/// what the vm does when the program writes over its own instructions or runs a byte
+/// it wrote, almost always a stack that grew into the program
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum CodeGuard{
+    Off,
+    Warn,       // print a warning once per address and keep going
+    Error       // stop the run
+}
 
+impl CodeGuard{
+    pub fn from_name(name : &str)->Option<CodeGuard>{
+        match name{
+            "off"   => Some(CodeGuard::Off),
+            "warn"  => Some(CodeGuard::Warn),
+            "error" => Some(CodeGuard::Error),
+            _=> None
+        }
+    }
+}
+
+/// why a run stopped
+#[derive(Debug,Clone,PartialEq)]
+pub enum RunStatus{
+    Halted,
+    Interrupted,                                    // stopped by the interrupt count
+    BudgetExceeded{budget : usize},
+    InfiniteLoop{pc : u8, label : Option<String>},  // the exact same state came back
+    CodeFault{pc : u8, message : String},           // wrote over the program or ran a written byte
+    TraceFailed{message : String}                   // the trace file could not be written
+}
+
+impl RunStatus{
+    /// halting and being interrupted on purpose are fine, the rest means the program hangs
+    pub fn is_hang(&self)->bool{
+        matches!(self,RunStatus::BudgetExceeded{..} | RunStatus::InfiniteLoop{..})
+    }
+}
+
+impl fmt::Display for RunStatus{
+    fn fmt(&self, f : &mut fmt::Formatter)->fmt::Result{
+        match self{
+            RunStatus::Halted => write!(f,"program halted"),
+            RunStatus::Interrupted => write!(f,"program interrupted"),
+            RunStatus::BudgetExceeded{budget} => write!(f,"program did not halt within the budget of {} instructions",budget),
+            RunStatus::InfiniteLoop{pc,label:Some(label)} => write!(f,"program is in an infinite loop at PC 0x{:02X} (label `{}`)",pc,label),
+            RunStatus::InfiniteLoop{pc,label:None} => write!(f,"program is in an infinite loop at PC 0x{:02X}",pc),
+            RunStatus::CodeFault{message,..} => write!(f,"{}",message),
+            RunStatus::TraceFailed{message} => write!(f,"unable to write the trace: {}",message)
+        }
+    }
+}
 
 pub struct VirtualMachine{
     pub mode          : Cell<u8>,  // MODE FLAGS[? ? ? ? ? ? COLOR_FLAGS ENABLED]
+    pub trace         : Cell<bool>, // print every instruction while running
     instruction_count : Cell<usize>,
     program_counter   : Cell<u8>,
     register_a        : Cell<u8>,
@@ -16,7 +123,31 @@ pub struct VirtualMachine{
     ram               : RefCell<[u8;256]>,
     pub program_edge  : Cell<u8>,
     pub flags         : Flags,
-    halt              : Cell<bool>
+    halt              : Cell<bool>,
+    flag_profile      : Cell<FlagProfile>,
+    budget            : Cell<Option<usize>>,
+    isa               : RefCell<Isa>,
+    watchpoints       : RefCell<Vec<Watchpoint>>,
+    watch_hit         : Cell<Option<WatchHit>>,
+    devices           : RefCell<Vec<MappedDevice>>,
+    initial_ram       : RefCell<[u8;256]>,  // ram right after load for memory diffs
+    labels            : RefCell<Vec<(String,u8)>>,
+    recording         : Cell<bool>,
+    history           : RefCell<VecDeque<UndoEntry>>,
+    history_limit     : Cell<usize>,
+    pending_undo      : RefCell<Option<UndoEntry>>,
+    hits              : RefCell<[usize;256]>,   // times each address was executed since load
+    back_edges        : RefCell<Vec<(u8,u8,usize)>>,  // jumps to or before themselves: from, to, times taken
+    coverage          : RefCell<[u8;256]>,  // coverage access bits per address
+    accesses          : RefCell<Vec<(Access,u8,u8)>>,  // ram reads and writes of the current instruction
+    last_trace        : RefCell<Option<TraceEntry>>,
+    trace_writer      : RefCell<Option<TraceWriter>>,
+    trace_error       : RefCell<Option<String>>,  // why the trace writer was dropped
+    code              : RefCell<[bool;256]>,        // bytes that hold instructions of the program
+    written_by        : RefCell<[Option<u8>;256]>,  // pc of the last instruction that wrote each byte since load
+    code_guard        : Cell<CodeGuard>,
+    code_fault        : RefCell<Option<(u8,String)>>,
+    reported          : RefCell<Vec<(u8,bool)>>     // pc of instructions already warned about and if they ran a written byte
 
 }
 
@@ -61,6 +192,40 @@ impl Flags{
         self.carry.set(f);
     }
 
+    pub fn get(&self, flag : Flag)->bool{
+        match flag{
+            Flag::Zero      => self.zero.get(),
+            Flag::Less      => self.less_than.get(),
+            Flag::Overflow  => self.overflow.get(),
+            Flag::Sign      => self.sign.get(),
+            Flag::Carry     => self.carry.get()
+        }
+    }
+
+    /// pack the flags into the bits [? ? ? carry sign overflow less zero]
+    pub fn pack(&self)->u8{
+        self.zero.get() as u8 | (self.less_than.get() as u8) << 1 | (self.overflow.get() as u8) << 2 |
+        (self.sign.get() as u8) << 3 | (self.carry.get() as u8) << 4
+    }
+
+    pub fn unpack(&self, bits : u8){
+        self.set_zero(bits & 1 != 0);
+        self.set_less(bits & 2 != 0);
+        self.set_over(bits & 4 != 0);
+        self.set_sign(bits & 8 != 0);
+        self.set_carry(bits & 16 != 0);
+    }
+
+    pub fn set(&self, flag : Flag, f : bool){
+        match flag{
+            Flag::Zero      => self.set_zero(f),
+            Flag::Less      => self.set_less(f),
+            Flag::Overflow  => self.set_over(f),
+            Flag::Sign      => self.set_sign(f),
+            Flag::Carry     => self.set_carry(f)
+        }
+    }
+
     // pub fn reset(&self){
     //     self.zero.set(false);
     //     self.less_than.set(false);
@@ -70,176 +235,216 @@ impl Flags{
     // }
 }
 
+/// how the carry flag of sub and cmp is set
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum CarryRule{
+    Borrow,         // set when the right value is greater than the left value (unsigned)
+    NoBorrow        // carry out of the adder doing left + !right + 1, the inverse of borrow
+}
+
+/// which flags each alu operation updates, as Flags::pack masks
+/// circuit revisions do not agree on this so the vm can follow either
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct FlagProfile{
+    pub name            : &'static str,
+    pub add             : u8,
+    pub sub             : u8,
+    pub cmp             : u8,
+    pub and             : u8,
+    pub or              : u8,
+    pub not             : u8,
+    pub right_shift     : u8,
+    pub subtract_carry  : CarryRule
+}
+
+const ALL_FLAGS   : u8 = 0b1_1111;
+const LOGIC_FLAGS : u8 = 0b0_1011;  // sign less zero
+
+impl FlagProfile{
+    /// the assembler manual: logic operations update zero, less and sign
+    pub const MANUAL : FlagProfile = FlagProfile{
+        name            : "manual",
+        add             : ALL_FLAGS,
+        sub             : ALL_FLAGS,
+        cmp             : ALL_FLAGS,
+        and             : LOGIC_FLAGS,
+        or              : LOGIC_FLAGS,
+        not             : LOGIC_FLAGS,
+        right_shift     : LOGIC_FLAGS,
+        subtract_carry  : CarryRule::Borrow
+    };
+
+    /// the 2021 logisim circuit: not leaves the flags alone and
+    /// the subtraction carry comes straight out of the adder
+    pub const LOGISIM_2021 : FlagProfile = FlagProfile{
+        name            : "logisim-2021",
+        not             : 0,
+        subtract_carry  : CarryRule::NoBorrow,
+        ..FlagProfile::MANUAL
+    };
+
+    pub const ALL : [FlagProfile;2] = [FlagProfile::MANUAL,FlagProfile::LOGISIM_2021];
+
+    pub fn from_name(name : &str)->Option<FlagProfile>{
+        FlagProfile::ALL.iter().find(|p| p.name == name).copied()
+    }
+}
+
+/// every flag an operation could produce, the flag profile picks the ones that are kept
+struct FlagValues{
+    zero     : bool,
+    less     : bool,
+    overflow : bool,
+    sign     : bool,
+    carry    : bool
+}
+
+impl FlagValues{
+    /// same bits as Flags::pack
+    fn pack(&self)->u8{
+        self.zero as u8 | (self.less as u8) << 1 | (self.overflow as u8) << 2 |
+        (self.sign as u8) << 3 | (self.carry as u8) << 4
+    }
+}
+
 pub struct ALU{}
 impl ALU{
 
+    /// run an alu operation on plain values
+    /// @param flags the current flags packed with Flags::pack
+    /// @return the result and the new packed flags, None if op is not an alu operation
+    pub fn evaluate(op : Ops, left : u8, right : u8, flags : u8, profile : FlagProfile)->Option<(u8,u8)>{
+        let (result,mask,values) = match op{
+            Ops::Add        => (left.wrapping_add(right),profile.add,ALU::add_flags(left,right)),
+            Ops::Sub        => (left.wrapping_sub(right),profile.sub,ALU::compare_flags(left,right,profile.subtract_carry)),
+            Ops::Compare    => (left,profile.cmp,ALU::compare_flags(left,right,profile.subtract_carry)),
+            Ops::And        => (left & right,profile.and,ALU::logic_flags(left,right,left & right)),
+            Ops::Or         => (left | right,profile.or,ALU::logic_flags(left,right,left | right)),
+            // not only has one operand
+            Ops::Not        => (!left,profile.not,ALU::logic_flags(left,!left,!left)),
+            Ops::RightShift =>{
+                // shifting by 8 or more clears the value
+                let result = left.checked_shr(right as u32).unwrap_or(0);
+                (result,profile.right_shift,ALU::logic_flags(left,result,result))
+            },
+            // no flag change
+            Ops::Increment  => (left.wrapping_add(1),0,ALU::logic_flags(0,0,0)),
+            Ops::Decrement  => (left.wrapping_sub(1),0,ALU::logic_flags(0,0,0)),
+            _=> return None
+        };
+        Some((result,(flags & !mask) | (values.pack() & mask)))
+    }
+
+    /// run the operation on the registers of the vm, the result goes in left
+    fn apply(vm : &VirtualMachine, op : Ops, left : Register, right : Register){
+        let (result,flags) = ALU::evaluate(op,vm.get_register_data(left),vm.get_register_data(right),vm.flags.pack(),vm.flag_profile.get())
+            .expect("not an alu operation");
+        vm.flags.unpack(flags);
+        vm.set_register_data(left,result);
+    }
+
     /// add the values contained in the specified registers
     /// and store it in left register
     /// (updates flags) carry, sign, zero, overflow, less_than
     pub fn add(vm : &VirtualMachine, left : Register, right : Register){
+        ALU::apply(vm,Ops::Add,left,right);
+    }
 
-        let left_value  = vm.get_register_data(left) as u8;
-        let right_value = vm.get_register_data(right) as u8;
-
+    fn add_flags(left_value : u8, right_value : u8)->FlagValues{
         let result = left_value as isize + right_value as isize;
 
-
         let msb_x = (left_value >> 7)  != 0;
         let msb_y = (right_value >> 7) != 0;
         let msb_s = (result as u8 >> 7)  != 0;
 
-        vm.flags.set_over(if (msb_x && msb_y && !msb_s) || (!msb_x && !msb_y && msb_s) {true} else {false});
+        let overflow = (msb_x && msb_y && !msb_s) || (!msb_x && !msb_y && msb_s);
 
-        // set less than flag
-        vm.flags.set_less(if msb_s != vm.flags.overflow.get(){true} else {false});
-
-
-        if result > 255 {
-            vm.flags.set_carry(true);
-        }else{
-            vm.flags.set_carry(false);
+        FlagValues{
+            zero     : (result as u8) == 0,
+            less     : msb_s != overflow,
+            overflow,
+            sign     : (result as i8) < 0,
+            carry    : result > 255
         }
-
-        if (result as u8) == 0{
-            vm.flags.set_zero(true);
-        }else{
-            vm.flags.set_zero(false);
-        }
-
-        if (result as i8) < 0{
-            vm.flags.set_sign(true);
-        }else{
-            vm.flags.set_sign(false);
-        }
-
-
-        // store result
-        vm.set_register_data(left, result as  u8);
     }
 
     pub fn sub(vm : &VirtualMachine, left : Register, right: Register){
-
-        // perform same flag as compare so we reuse
-        ALU::cmp(vm,left,right);
-
-        let left_value  = vm.get_register_data(left) as i8;
-        let right_value = vm.get_register_data(right) as i8;
-        vm.set_register_data(left, (left_value as isize - right_value as isize) as u8)
+        ALU::apply(vm,Ops::Sub,left,right);
     }
 
+    /// only updates the flags, left keeps its value
     pub fn cmp(vm : &VirtualMachine, left : Register, right: Register){
-        let left_value  = vm.get_register_data(left) as i8;
-        let right_value = vm.get_register_data(right) as i8;
+        ALU::apply(vm,Ops::Compare,left,right);
+    }
 
+    /// flags of left - right, shared by sub and cmp
+    fn compare_flags(left_value : u8, right_value : u8, subtract_carry : CarryRule)->FlagValues{
         // subtract by upcasting to isize
         // this will allow us to check
         // for flags without having to go into
         // logisim implementation.
-        let result : isize = left_value as isize - right_value as isize ;
-
-        if result == 0 {
-            vm.flags.set_zero(true);
-        }else{
-            vm.flags.set_zero(false);
-        }
-
-        if (result as i8) < 0 {
-            vm.flags.set_sign(true);  // true if negative
-        }else{
-            vm.flags.set_sign(false);
-        }
+        let result : isize = left_value as i8 as isize - right_value as i8 as isize;
 
         //overflow
 
         let msb_x = (left_value >> 7)  != 0;
         let msb_y = (right_value >> 7) != 0;
         let msb_d = (result as u8 >> 7)  != 0;
+        let overflow = (msb_x && !msb_y && !msb_d) || (!msb_x && msb_y && msb_d);
+
+        // borrow: the right value is greater than the left value (unsigned)
+        let borrow = right_value > left_value;
+
+        FlagValues{
+            zero     : result == 0,
+            less     : overflow != msb_d,
+            overflow,
+            sign     : (result as i8) < 0,   // true if negative
+            carry    : match subtract_carry{
+                CarryRule::Borrow   => borrow,
+                CarryRule::NoBorrow => !borrow
+            }
+        }
+    }
 
-        vm.flags.set_over( if (msb_x && !msb_y && !msb_d) || (!msb_x && msb_y && msb_d){true} else {false});
-
-        //less than
-        vm.flags.set_less( if vm.flags.overflow.get() != msb_d {true} else {false} );
-
-        // the way we check carry is if we overflow
-        // or if the left value is greater than the right value
-        // this means that left value is a non negative value
-        if right_value as u8  > left_value as u8{
-            vm.flags.set_carry(true);
-        }else{
-            vm.flags.set_carry(false);
+    /// zero, sign and less flags of the logic operations
+    fn logic_flags(compare_left : u8, compare_right : u8, result : u8)->FlagValues{
+        FlagValues{
+            zero     : result == 0,
+            less     : (compare_left as i8) < (compare_right as i8),
+            overflow : false,
+            sign     : (result as i8) < 0,
+            carry    : false
         }
     }
 
     pub fn and(vm : &VirtualMachine, left : Register, right: Register){
-
-        let left_value  = vm.get_register_data(left);
-        let right_value = vm.get_register_data(right);
-
-        let result = left_value & right_value;
-
-        vm.flags.set_less(if (left_value as i8) < (right_value as i8) { true } else {false});
-        vm.flags.set_zero(if result == 0 {true} else {false});
-        vm.flags.set_sign(if (result as i8) < 0 {true} else {false});
-
-        vm.set_register_data(left, result);
-
+        ALU::apply(vm,Ops::And,left,right);
     }
 
     pub fn or(vm : &VirtualMachine, left : Register, right : Register){
-
-        let left_value  = vm.get_register_data(left);
-        let right_value = vm.get_register_data(right);
-
-        let result = left_value | right_value;
-
-        vm.flags.set_less(if (left_value as i8) < (right_value as i8) { true } else {false});
-        vm.flags.set_zero(if result == 0 {true} else {false});
-        vm.flags.set_sign(if (result as i8) < 0 {true} else {false});
-
-        vm.set_register_data(left, result);
+        ALU::apply(vm,Ops::Or,left,right);
     }
 
     /// not operation on the passed in x Register
     /// NOTE: in the Assembler manual it states that this should update
-    ///       the Zero, Less_Than, and Sign Flags but it might be a missprint
-    ///       since the operation doesnt seem to change any of the flag registers
-    ///       within logisim
+    ///       the Zero, Less_Than, and Sign Flags but the operation doesnt
+    ///       seem to change any of the flag registers within logisim
+    ///       so it depends on the flag profile
     pub fn not(vm : &VirtualMachine, x : Register){
-
-        let value = vm.get_register_data(x);
-        let result = !value;
-
-        // TODO: figure out if this is intended
-        // FIXME: ---^
-        vm.flags.set_less(if (value as i8) < (result as i8) {true} else{false});
-        vm.flags.set_zero(if result == 0 {true} else {false});
-        vm.flags.set_sign(if (result as i8) < 0 {true} else {false});
-
-        vm.set_register_data(x,result);
+        ALU::apply(vm,Ops::Not,x,x);
     }
 
     pub fn right_shift(vm : &VirtualMachine, operand : Register, amount : Register){
-        let operand_value = vm.get_register_data(operand);
-        let amount_value  = vm.get_register_data(amount);
-        let result = operand_value >> amount_value;
-
-
-        //
-        vm.flags.set_less(if (operand_value as i8) < (result as i8) {true} else{false});
-        vm.flags.set_zero(if result == 0 {true} else {false});
-        vm.flags.set_sign(if (result as i8) < 0 {true} else {false});
-
-        vm.set_register_data(operand,result);
+        ALU::apply(vm,Ops::RightShift,operand,amount);
     }
 
     pub fn inc(vm  : &VirtualMachine, x : Register){
-        let value = vm.get_register_data(x);
-        vm.set_register_data(x,(value as isize +1) as u8);
+        ALU::apply(vm,Ops::Increment,x,x);
     }
 
     pub fn dec(vm : &VirtualMachine, x : Register){
-        let value = vm.get_register_data(x);
-        vm.set_register_data(x,(value as isize -1) as u8);
+        ALU::apply(vm,Ops::Decrement,x,x);
     }
 
 }
@@ -250,6 +455,7 @@ impl VirtualMachine{
     pub fn create()->Self{
         VirtualMachine{
             mode              : Cell::new(0),
+            trace             : Cell::new(true),
             instruction_count : Cell::new(0),
             program_counter   : Cell::new(0),
             register_a        : Cell::new(0),
@@ -259,29 +465,313 @@ impl VirtualMachine{
             ram               : RefCell::new([0;256]),
             program_edge      : Cell::new(0),
             flags             : Flags::create(),
-            halt              : Cell::new(false)
+            halt              : Cell::new(false),
+            flag_profile      : Cell::new(FlagProfile::MANUAL),
+            budget            : Cell::new(Some(DEFAULT_BUDGET)),
+            isa               : RefCell::new(Isa::create()),
+            watchpoints       : RefCell::new(Vec::new()),
+            watch_hit         : Cell::new(None),
+            devices           : RefCell::new(Vec::new()),
+            initial_ram       : RefCell::new([0;256]),
+            labels            : RefCell::new(Vec::new()),
+            recording         : Cell::new(false),
+            history           : RefCell::new(VecDeque::new()),
+            history_limit     : Cell::new(DEFAULT_HISTORY),
+            pending_undo      : RefCell::new(None),
+            hits              : RefCell::new([0;256]),
+            back_edges        : RefCell::new(Vec::new()),
+            coverage          : RefCell::new([0;256]),
+            accesses          : RefCell::new(Vec::new()),
+            last_trace        : RefCell::new(None),
+            trace_writer      : RefCell::new(None),
+            trace_error       : RefCell::new(None),
+            code              : RefCell::new([false;256]),
+            written_by        : RefCell::new([None;256]),
+            code_guard        : Cell::new(CodeGuard::Warn),
+            code_fault        : RefCell::new(None),
+            reported          : RefCell::new(Vec::new())
+        }
+    }
+
+    /// which flags the alu operations update
+    pub fn set_flag_profile(&self, profile : FlagProfile){
+        self.flag_profile.set(profile);
+    }
+
+    /// what to do when the program writes over its instructions or runs a byte it wrote
+    pub fn set_code_guard(&self, guard : CodeGuard){
+        self.code_guard.set(guard);
+    }
+
+    /// the pc and description of a write to code or run of a written byte since
+    /// the last call, only kept when the guard is CodeGuard::Error
+    pub fn take_code_fault(&self)->Option<(u8,String)>{
+        self.code_fault.take()
+    }
+
+    /// @param executed the instruction ran a written byte rather than writing over code
+    fn report_code_fault(&self, pc : u8, executed : bool, message : String){
+        match self.code_guard.get(){
+            CodeGuard::Off =>{},
+            CodeGuard::Warn =>{
+                // once per instruction, a stack overflow writes a new address every push
+                if !self.reported.borrow().contains(&(pc,executed)){
+                    self.reported.borrow_mut().push((pc,executed));
+                    compiler::warning(message);
+                }
+            },
+            CodeGuard::Error =>{
+                if self.code_fault.borrow().is_none(){
+                    self.code_fault.replace(Some((pc,message)));
+                }
+            }
+        }
+    }
+
+    /// pc 0x12 (label `loop+2`)
+    fn location(&self, addr : u8)->String{
+        match self.label_at(addr){
+            Some(label) => format!("0x{:02X} (label `{}`)",addr,label),
+            None => format!("0x{:02X}",addr)
+        }
+    }
+
+    /// set the isa extensions the vm should understand
+    pub fn set_isa(&self, isa : Isa){
+        self.isa.replace(isa);
+    }
+
+    /// map a device to the addresses starting at start
+    pub fn map_device(&self, start : u8, device : Box<dyn Device>)->Result<(),String>{
+        let end = start as usize + device.size() as usize;
+        if device.size() == 0 || end > 256{
+            return Err(format!("device [{}] at {:02X} does not fit in ram.",device.name(),start));
+        }
+        let mut devices = self.devices.borrow_mut();
+        if let Some(other) = devices.iter().find(|d| (start..=(end - 1) as u8).any(|addr| d.contains(addr))){
+            return Err(format!("device [{}] at {:02X} overlaps device [{}] at {:02X}.",device.name(),start,other.device.name(),other.start));
         }
+        devices.push(MappedDevice{start,device});
+        Ok(())
     }
 
     /// write the provided data to the memory address
     pub fn write(&self, addr : isize, data : isize){
-        let mut arr = self.ram.borrow_mut();
-        arr[(addr as u8) as usize] = VirtualMachine::get_wrapped_value(data) as u8;
+        let new = VirtualMachine::get_wrapped_value(data) as u8;
+        let cycles = self.instruction_count.get();
+        // the ram under a device is never used so there is no old value to show
+        let old = if let Some(mapped) = self.devices.borrow_mut().iter_mut().find(|d| d.contains(addr as u8)){
+            mapped.device.write(addr as u8 - mapped.start,new,cycles);
+            None
+        }else{
+            let old = self.fetch(addr);
+            self.ram.borrow_mut()[(addr as u8) as usize] = new;
+            let pc = self.program_counter.get();
+            if let Some(entry) = self.pending_undo.borrow_mut().as_mut(){
+                entry.ram.push((addr as u8,old));
+                entry.written_by.push((addr as u8,self.written_by.borrow()[addr as u8 as usize]));
+            }
+            self.written_by.borrow_mut()[addr as u8 as usize] = Some(pc);
+            if self.code.borrow()[addr as u8 as usize]{
+                let message = format!("instruction at PC {} writes over the program at {}",self.location(pc),self.location(addr as u8));
+                self.report_code_fault(pc,false,message);
+            }
+            Some(old)
+        };
+        self.accesses.borrow_mut().push((Access::Write,addr as u8,new));
+        self.mark_coverage(addr as u8,coverage::WRITTEN);
+        self.check_watchpoints(addr as u8,Access::Write,old,new);
+    }
+
+    /// change a byte from outside the program (ex: the debugger), it does not count as a write
+    /// by the current instruction so it is not traced, watched, covered or undone
+    pub fn poke(&self, addr : u8, data : u8){
+        self.ram.borrow_mut()[addr as usize] = data;
+        self.written_by.borrow_mut()[addr as usize] = None;
     }
 
     pub fn read(&self, addr: isize)->u8{
+        let cycles = self.instruction_count.get();
+        let data = match self.devices.borrow_mut().iter_mut().find(|d| d.contains(addr as u8)){
+            Some(mapped) => mapped.device.read(addr as u8 - mapped.start,cycles),
+            None => self.fetch(addr)
+        };
+        self.accesses.borrow_mut().push((Access::Read,addr as u8,data));
+        self.mark_coverage(addr as u8,coverage::READ);
+        self.check_watchpoints(addr as u8,Access::Read,Some(data),data);
+        data
+    }
+
+    /// read memory without triggering watchpoints (instruction fetch)
+    fn fetch(&self, addr : isize)->u8{
         self.ram.borrow()[(addr as u8) as usize]
     }
 
-    pub fn run(&self,interrupt : bool, after : isize){
-        println!("TRACE:");
+    fn check_watchpoints(&self, addr : u8, access : Access, old : Option<u8>, new : u8){
+        if self.watch_hit.get().is_some(){
+            return;
+        }
+        let watched = self.watchpoints.borrow().iter().any(|w| w.addr == addr && match access{
+            Access::Read  => w.read,
+            Access::Write => w.write
+        });
+        if watched{
+            self.watch_hit.set(Some(WatchHit{addr,access,pc:self.program_counter.get(),old,new}));
+        }
+    }
+
+    /// watch an address, replacing any watchpoint already on it
+    pub fn add_watchpoint(&self, watchpoint : Watchpoint){
+        self.remove_watchpoint(watchpoint.addr);
+        self.watchpoints.borrow_mut().push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&self, addr : u8){
+        self.watchpoints.borrow_mut().retain(|w| w.addr != addr);
+    }
+
+    pub fn get_watchpoints(&self)->Vec<Watchpoint>{
+        self.watchpoints.borrow().clone()
+    }
+
+    /// add coverage bits to the address, remembering the old bits for step_back
+    fn mark_coverage(&self, addr : u8, bits : u8){
+        let old = self.coverage.borrow()[addr as usize];
+        if old | bits == old{
+            return;
+        }
+        if let Some(entry) = self.pending_undo.borrow_mut().as_mut(){
+            entry.coverage.push((addr,old));
+        }
+        self.coverage.borrow_mut()[addr as usize] = old | bits;
+    }
+
+    /// keep an undo entry for every instruction so the vm can step backwards
+    pub fn set_recording(&self, recording : bool){
+        self.recording.set(recording);
+        if !recording{
+            self.history.borrow_mut().clear();
+        }
+    }
+
+    /// how many instructions the vm can step back over, the oldest entries are dropped past it
+    pub fn set_history_limit(&self, limit : usize){
+        self.history_limit.set(limit);
+        let mut history = self.history.borrow_mut();
+        while history.len() > limit{
+            history.pop_front();
+        }
+    }
+
+    pub fn get_history_limit(&self)->usize{
+        self.history_limit.get()
+    }
+
+    /// undo the last recorded instruction
+    /// @return the undone entry or None if there is no history left
+    pub fn step_back(&self)->Option<UndoEntry>{
+        let entry = self.history.borrow_mut().pop_back()?;
+        {
+            let mut ram = self.ram.borrow_mut();
+            let mut written_by = self.written_by.borrow_mut();
+            for (addr,old) in entry.ram.iter().rev(){
+                ram[*addr as usize] = *old;
+            }
+            for (addr,old) in entry.written_by.iter().rev(){
+                written_by[*addr as usize] = *old;
+            }
+        }
+        {
+            // the instruction no longer ran so it is not counted or covered
+            let mut coverage = self.coverage.borrow_mut();
+            for (addr,old) in entry.coverage.iter().rev(){
+                coverage[*addr as usize] = *old;
+            }
+            let mut hits = self.hits.borrow_mut();
+            hits[entry.pc as usize] = hits[entry.pc as usize].saturating_sub(1);
+            if let Some((from,to)) = entry.back_edge{
+                let mut back_edges = self.back_edges.borrow_mut();
+                if let Some(i) = back_edges.iter().position(|(f,t,_)| *f == from && *t == to){
+                    back_edges[i].2-=1;
+                    if back_edges[i].2 == 0{
+                        back_edges.remove(i);
+                    }
+                }
+            }
+        }
+        self.program_counter.set(entry.pc);
+        self.set_register_data(Register::A,entry.registers[0]);
+        self.set_register_data(Register::B,entry.registers[1]);
+        self.set_register_data(Register::C,entry.registers[2]);
+        self.set_register_data(Register::D,entry.registers[3]);
+        self.flags.unpack(entry.flags);
+        self.halt.set(entry.halt);
+        self.instruction_count.set(self.instruction_count.get().saturating_sub(1));
+        Some(entry)
+    }
+
+    /// how many instructions back the address was last written
+    /// ex: 1 means the last instruction wrote it
+    pub fn steps_since_write(&self, addr : u8)->Option<usize>{
+        let history = self.history.borrow();
+        history.iter().rev().position(|entry| entry.ram.iter().any(|(a,_)| *a == addr)).map(|i| i + 1)
+    }
+
+    /// write a trace entry for every instruction from now on
+    pub fn set_trace_writer(&self, writer : TraceWriter){
+        self.trace_writer.replace(Some(writer));
+    }
+
+    /// why writing the trace failed since the last call, the writer is dropped on the first error
+    pub fn take_trace_error(&self)->Option<String>{
+        self.trace_error.take()
+    }
+
+    /// structured trace of the last instruction
+    pub fn last_trace(&self)->Option<TraceEntry>{
+        self.last_trace.borrow().clone()
+    }
+
+    fn get_registers(&self)->[u8;4]{
+        [self.register_a.get(),self.register_b.get(),self.register_c.get(),self.register_d.get()]
+    }
+
+    /// the watched access made by the last instruction if any
+    pub fn take_watch_hit(&self)->Option<WatchHit>{
+        self.watch_hit.take()
+    }
+
+    /// run until halt, the interrupt count, the instruction budget or an infinite loop
+    pub fn run(&self,interrupt : bool, after : isize)->RunStatus{
+        if self.trace.get(){
+            println!("TRACE:");
+        }
+
+        // brent's cycle detection: compare every state with a checkpoint that
+        // moves forward at powers of two. devices can change between two
+        // identical states (ex: polling the random port) so skip it with devices
+        let detect_loops = self.devices.borrow().is_empty();
+        let mut detector = LoopDetector::create(self.get_state());
+
+        let mut status = RunStatus::Halted;
         while !self.halt.get(){
 
             // interrupt if interrupt set
-            if interrupt && self.instruction_count.get() > after as usize {break;}
+            if interrupt && self.instruction_count.get() > after as usize {
+                status = RunStatus::Interrupted;
+                break;
+            }
 
+            if let Some(budget) = self.budget.get(){
+                if self.instruction_count.get() >= budget{
+                    status = RunStatus::BudgetExceeded{budget};
+                    break;
+                }
+            }
 
-            if self.mode.get() & 1 != 0 { // checker mode enabled
+            if !self.trace.get(){
+                self.run_instruction();
+            }else if self.mode.get() & 1 != 0 { // checker mode enabled
 
                 let dark = self.instruction_count.get() % 2 == 0;
                 print!("{}{}{}\n",if dark {"\x1b[48;5;245m\x1b[38;5;233m"}else{""},self.run_instruction(),"\x1b[0m");
@@ -291,7 +781,21 @@ impl VirtualMachine{
                 println!("{}{}","",self.run_instruction());
             }
 
+            if let Some((pc,message)) = self.take_code_fault(){
+                status = RunStatus::CodeFault{pc,message};
+                break;
+            }
 
+            if let Some(message) = self.take_trace_error(){
+                status = RunStatus::TraceFailed{message};
+                break;
+            }
+
+            if detect_loops && !self.halt.get() && detector.repeats(self.get_state()){
+                let pc = self.program_counter.get();
+                status = RunStatus::InfiniteLoop{pc,label:self.label_at(pc)};
+                break;
+            }
         }
 
         //NOTE: 4 == PRINT->STACK with register D as stackpointer
@@ -299,6 +803,68 @@ impl VirtualMachine{
             println!("\n{}",self.print_stack(Register::D));
         }
 
+        status
+    }
+
+    /// stop runs after this many instructions, None for no limit
+    pub fn set_budget(&self, budget : Option<usize>){
+        self.budget.set(budget);
+    }
+
+    /// everything that decides what the program does next
+    fn get_state(&self)->LoopState{
+        (self.program_counter.get(),self.get_registers(),self.flags.pack(),*self.ram.borrow())
+    }
+
+    /// the closest label at or before the address within the program, ex: loop+2
+    pub fn label_at(&self, addr : u8)->Option<String>{
+        compiler::label_at(&self.labels.borrow(),self.program_edge.get(),addr)
+    }
+
+    /// run a single instruction and return its trace line
+    pub fn step(&self)->String{
+        self.run_instruction()
+    }
+
+    pub fn get_program_counter(&self)->u8{
+        self.program_counter.get()
+    }
+
+    pub fn set_program_counter(&self, addr : u8){
+        self.program_counter.set(addr);
+    }
+
+    pub fn is_halted(&self)->bool{
+        self.halt.get()
+    }
+
+    pub fn set_halted(&self, halt : bool){
+        self.halt.set(halt);
+    }
+
+    pub fn get_instruction_count(&self)->usize{
+        self.instruction_count.get()
+    }
+
+    /// set a register, flag, ram byte or the pc before a run
+    /// ram is written directly so devices and watchpoints do not see it
+    /// and memory diffs count it as part of the starting ram
+    pub fn set_field(&self, field : Field, value : u8){
+        match field{
+            Field::Register(r) => self.set_register_data(r,value),
+            Field::Flag(f) => self.flags.set(f,value != 0),
+            Field::Ram(addr) =>{
+                self.ram.borrow_mut()[addr as usize] = value;
+                self.initial_ram.borrow_mut()[addr as usize] = value;
+            },
+            Field::Pc => self.program_counter.set(value),
+            Field::Steps => self.instruction_count.set(value as usize)
+        }
+    }
+
+    /// copy of the current ram contents
+    pub fn get_ram(&self)->[u8;256]{
+        *self.ram.borrow()
     }
 
     fn print_stack(&self, stack_pointer : Register)->String{
@@ -321,15 +887,74 @@ impl VirtualMachine{
             //         000:[FF]=FF
             //         000:[FF]=FF
             let ram_index = 256 - i;
-            let data = self.read(ram_index);
+            let data = self.fetch(ram_index);
             ret_string.push_str(format!("{}{:0^3}:[{:02X}]={:02X}\n",if ram_index == value as isize {" TOP >> "}else{"        "},ram_index as u8,ram_index as u8,data).as_str());
         }
 
         ret_string
     }
 
-    fn dump_memory(&self)->String{
-        format!("")
+    /// hexdump of all 256 bytes of ram with an ascii column
+    /// '|' marks the end of the program and '>' the stack pointer (register D)
+    pub fn dump_memory(&self)->String{
+        let ram = self.ram.borrow();
+        let labels = self.labels.borrow();
+        let color = (self.mode.get() & 2) != 0;
+        let edge = self.program_edge.get() as usize;
+        let stack_pointer = self.get_register_data(Register::D) as usize;
+
+        let mut ret_string = String::from("MEMORY:\n     ");
+        for column in 0..16{
+            ret_string.push_str(format!(" {:02X}",column).as_str());
+        }
+        ret_string.push('\n');
+
+        for row in 0..16{
+            let mut hex = String::new();
+            let mut ascii = String::new();
+            for column in 0..16{
+                let addr = row * 16 + column;
+                let data = ram[addr];
+                let marker = if addr == stack_pointer {'>'} else if addr == edge + 1 {'|'} else {' '};
+                let (start,end) = if color && addr == stack_pointer {("\x1b[7m","\x1b[0m")}
+                                  else if color && addr <= edge {("\x1b[38;5;45m","\x1b[0m")}
+                                  else {("","")};
+                hex.push_str(format!("{}{}{:02X}{}",marker,start,data,end).as_str());
+                ascii.push(if data.is_ascii_graphic() || data == b' ' {data as char} else {'.'});
+            }
+
+            let mut row_labels : Vec<String> = labels.iter().filter(|(_,a)| *a as usize / 16 == row)
+                .map(|(l,a)| format!("{}={:02X}",l,a)).collect();
+            row_labels.extend(self.devices.borrow().iter().filter(|d| d.start as usize / 16 == row)
+                .map(|d| format!("[{}]={:02X}",d.device.name(),d.start)));
+            ret_string.push_str(format!("{:02X}:  {}  {}  {}\n",row * 16,hex,ascii,row_labels.join(" ")).trim_end());
+            ret_string.push('\n');
+        }
+
+        ret_string
+    }
+
+    /// list every byte that changed since the program was loaded
+    pub fn diff_memory(&self)->String{
+        let ram = self.ram.borrow();
+        let initial = self.initial_ram.borrow();
+        let labels = self.labels.borrow();
+
+        let mut ret_string = String::from("MEMORY DIFF (load -> now):\n");
+        let mut changes = 0;
+        for addr in 0..256{
+            if ram[addr] != initial[addr]{
+                changes+=1;
+                let label = labels.iter().find(|(_,a)| *a as usize == addr).map(|(l,_)| format!(" <{}>",l)).unwrap_or_default();
+                ret_string.push_str(format!(" {:02X}: {:02X} -> {:02X}{}{}\n",addr,initial[addr],ram[addr],
+                                            if addr <= self.program_edge.get() as usize {" (program)"} else {""},label).as_str());
+            }
+        }
+        if changes == 0{
+            ret_string.push_str(" no changes\n");
+        }
+
+        ret_string
     }
 
     pub fn load(&self,program : &Program)->Result<(),String>{
@@ -344,6 +969,34 @@ impl VirtualMachine{
             self.program_edge.set(i as u8);
         }
 
+        self.initial_ram.replace(*ram);
+        self.labels.replace(program.labels.clone());
+        self.written_by.replace([None;256]);
+        self.reported.borrow_mut().clear();
+
+        // sources know which lines are instructions, images only have what the cfg reaches
+        let mut code = [false;256];
+        if program.lines.is_empty(){
+            let cfg = Cfg::build(&self.isa.borrow(),program);
+            for (block,_) in cfg.blocks.iter().zip(cfg.reachable()).filter(|(_,r)| *r){
+                for d in &block.instructions{
+                    for i in 0..d.size(){
+                        code[d.addr.wrapping_add(i) as usize] = true;
+                    }
+                }
+            }
+        }else{
+            for line in program.lines.iter().filter(|l| !l.data){
+                for i in 0..line.size{
+                    code[line.addr.wrapping_add(i) as usize] = true;
+                }
+            }
+        }
+        self.code.replace(code);
+        self.hits.replace([0;256]);
+        self.back_edges.borrow_mut().clear();
+        self.coverage.replace([0;256]);
+
         Ok(())
     }
 
@@ -366,413 +1019,64 @@ impl VirtualMachine{
     }
 
     fn run_instruction(&self)->String{
+        self.watch_hit.set(None);
+        if self.recording.get(){
+            self.pending_undo.replace(Some(UndoEntry{
+                pc         : self.program_counter.get(),
+                registers  : self.get_registers(),
+                flags      : self.flags.pack(),
+                halt       : self.halt.get(),
+                ram        : Vec::new(),
+                written_by : Vec::new(),
+                coverage   : Vec::new(),
+                back_edge  : None
+            }));
+        }
         let instruction_count = self.instruction_count.get();
         let pc_value = self.program_counter.get();
-        let instruction = self.read(pc_value as isize);
-
-        let left  = Register::from_bits((instruction & 0b0000_1100) >> 2);
-        let right = Register::from_bits(instruction & 0b0000_0011);
-
-        let mut left_str    = String::new();
-        let mut right_str   = String::new();
-        let mut reg_str     = String::new();
-        let mut ram_str     = String::new();
-        let mut op_str      = String::new();
-
-        match instruction{
-            0b0000_0000 =>{ // NOOP
-                //no_op;
-                op_str.push_str("nop");
-            },
-            0b0000_0001 =>{ // HALT
-                self.halt.set(true);
-                op_str.push_str("halt");
-            },
-            0b0100_0000 =>{ // JUMP IMMEDIATE
-
-                op_str.push_str("jmpi");
-                let jmp_location = self.read(pc_value as isize +1) as isize;
-                ram_str = format!("RAM_R[{:02x}]={:02x}",pc_value as isize +1,jmp_location);
-                self.program_counter.set((jmp_location - 1) as u8);
-            },
-            0b0100_0001 =>{ // JUMP IMMEDIATE IF LESS
-
-                op_str.push_str("jli");
-                let jmp_location = self.read(pc_value as isize +1) as isize;
-                if self.flags.less_than.get() {
-                    self.program_counter.set((jmp_location - 1) as u8);
-                    ram_str = format!("RAM_R[{:02x}]={:02x}",pc_value as isize +1,jmp_location);
-                }else{
-                    self.program_counter.set((pc_value as isize +1 ) as u8);
-                }
-            },
-            0b0100_0010 =>{ // JUMP IMMEDIATE IF OVERFLOW
-                op_str.push_str("joi");
-                let jmp_location = self.read(pc_value as isize +1) as isize;
-                if self.flags.overflow.get() {
-                    self.program_counter.set((jmp_location - 1) as u8);
-                    ram_str = format!("RAM_R[{:02x}]={:02x}",pc_value as isize +1,jmp_location);
-                }else{
-                    self.program_counter.set((pc_value as isize +1 ) as u8);
-                }
-            },
-            0b0100_0011 =>{ // JUMP IMMEDIATE IF SIGN
-
-                op_str.push_str("jsi");
-                let jmp_location = self.read(pc_value as isize +1) as isize;
-                if self.flags.sign.get() {
-                    self.program_counter.set((jmp_location - 1) as u8);
-                    ram_str = format!("RAM_R[{:02x}]={:02x}",pc_value as isize +1,jmp_location);
-                }else{
-                    self.program_counter.set((pc_value as isize +1 ) as u8);
-                }
-            },
-            0b0100_0100 =>{ // JUMP IMMEDIATE IF CARRY
-
-                op_str.push_str("jci");
-
-                let jmp_location = self.read(pc_value as isize +1) as isize;
-                if self.flags.carry.get() {
-                    self.program_counter.set((jmp_location - 1) as u8);
-                    ram_str = format!("RAM_R[{:02x}]={:02x}",pc_value as isize +1,jmp_location);
-                }else{
-                    self.program_counter.set((pc_value as isize +1 ) as u8);
-                }
-            },
-            0b0100_0101 =>{ // JUMP IMMEDIATE IF ZERO
-
-                op_str.push_str("jzi");
-
-
-                let jmp_location = self.read(pc_value as isize +1) as isize;
-                if self.flags.zero.get() {
-                    self.program_counter.set((jmp_location - 1) as u8);
-                    ram_str = format!("RAM_R[{:02x}]={:02x}",pc_value as isize +1,jmp_location);
-                }else{
-                    self.program_counter.set((pc_value as isize +1 ) as u8);
-                }
-
-            },
-            0b0110_0000 =>{ // JUMP TO A IF LESS
-
-
-                op_str.push_str("jl");
-
-                left_str = format!("{:?}={:02x}",right,self.get_register_data(right));
-                let jmp_location = self.get_register_data(right) as isize;
-                if self.flags.less_than.get() {
-                    self.program_counter.set((jmp_location - 1) as u8);
-                }
-            },
-            0b0110_0001 =>{ // JUMP TO B IF LESS
-
-                op_str.push_str("jl");
-
-                left_str = format!("{:?}={:02x}",right,self.get_register_data(right));
-
-                let jmp_location = self.get_register_data(right) as isize;
-                if self.flags.less_than.get() {
-                    self.program_counter.set((jmp_location - 1) as u8);
-                }
-            },
-            0b0110_0010 =>{ // JUMP TO C IF LESS
-
-                op_str.push_str("jl");
-
-                left_str = format!("{:?}={:02x}",right,self.get_register_data(right));
-                let jmp_location = self.get_register_data(right) as isize;
-                if self.flags.less_than.get() {
-                    self.program_counter.set((jmp_location - 1) as u8);
-                }
-            },
-            0b0110_0011 =>{ // JUMP TO D IF LESS
-
-                op_str.push_str("jl");
-
-                left_str = format!("{:?}={:02x}",right,self.get_register_data(right));
-
-
-                let jmp_location = self.get_register_data(right) as isize;
-                if self.flags.overflow.get() {
-                    self.program_counter.set((jmp_location - 1) as u8);
-                }
-
-            },
-            0b0110_0100 =>{ // JUMP TO A IF OVERFLOW
-
-                op_str.push_str("jo");
-
-                left_str = format!("{:?}={:02x}",right,self.get_register_data(right));
-                let jmp_location = self.get_register_data(right) as isize;
-                if self.flags.overflow.get() {
-                    self.program_counter.set((jmp_location - 1) as u8);
-                }
-            },
-            0b0110_0101 =>{ // JUMP TO B IF OVERFLOW
-
-                op_str.push_str("jo");
-
-                left_str = format!("{:?}={:02x}",right,self.get_register_data(right));
-                let jmp_location = self.get_register_data(right) as isize;
-                if self.flags.overflow.get() {
-                    self.program_counter.set((jmp_location - 1) as u8);
-                }
-            },
-            0b0110_0110 =>{ // JUMP TO C IF OVERFLOW
-
-                op_str.push_str("jo");
-
-                left_str = format!("{:?}={:02x}",right,self.get_register_data(right));
-                let jmp_location = self.get_register_data(right) as isize;
-                if self.flags.overflow.get() {
-                    self.program_counter.set((jmp_location - 1) as u8);
-                }
-            },
-            0b0110_0111 =>{ // JUMP TO D IF OVERFLOW
-
-                op_str.push_str("jo");
-
-                left_str = format!("{:?}={:02x}",right,self.get_register_data(right));
-                let jmp_location = self.get_register_data(right) as isize;
-                if self.flags.overflow.get() {
-                    self.program_counter.set((jmp_location - 1) as u8);
-                }
-            },
-            0b0110_1000 =>{ // JUMP TO A IF SIGN
-
-                op_str.push_str("js");
-
-                left_str = format!("{:?}={:02x}",right,self.get_register_data(right));
-                let jmp_location = self.get_register_data(right) as isize;
-                if self.flags.sign.get() {
-                    self.program_counter.set((jmp_location - 1) as u8);
-                }
-            },
-            0b0110_1001 =>{ // JUMP TO B IF SIGN
-
-                op_str.push_str("js");
-
-                left_str = format!("{:?}={:02x}",right,self.get_register_data(right));
-                let jmp_location = self.get_register_data(right) as isize;
-                if self.flags.sign.get() {
-                    self.program_counter.set((jmp_location - 1) as u8);
-                }
-            },
-            0b0110_1010 =>{ // JUMP TO C IF SIGN
-
-                op_str.push_str("js");
-
-                left_str = format!("{:?}={:02x}",right,self.get_register_data(right));
-                let jmp_location = self.get_register_data(right) as isize;
-                if self.flags.sign.get() {
-                    self.program_counter.set((jmp_location - 1) as u8);
-                }
-            },
-            0b0110_1011 =>{ // JUMP TO D IF SIGN
-
-                op_str.push_str("js");
-
-                left_str = format!("{:?}={:02x}",right,self.get_register_data(right));
-                let jmp_location = self.get_register_data(right) as isize;
-                if self.flags.sign.get() {
-                    self.program_counter.set((jmp_location - 1) as u8);
-                }
-            },
-            0b0110_1100 =>{ // LOAD IMMEDIATE TO A
-                op_str.push_str("ldi");
-                let data : u8 = self.read((pc_value as isize)+1);
-
-                ram_str = format!("RAM_R[{:02x}]={:02x}",(pc_value as isize +1) as u8,data);
-
-                self.program_counter.set((pc_value as isize +1) as u8);
-                left_str = format!("{:?}={:02x}",right,self.get_register_data(right));
-                reg_str = format!("{:?}={:02x}",right,data);
-                self.set_register_data(right, data);
-
-            },
-            0b0110_1101 =>{ // LOAD IMMEDIATE TO B
-                op_str.push_str("ldi");
-
-                let data : u8 = self.read((pc_value as isize)+1);
-                ram_str = format!("RAM_R[{:02x}]={:02x}",(pc_value as isize +1) as u8,data);
-                left_str = format!("{:?}={:02x}",right,self.get_register_data(right));
-                reg_str = format!("{:?}={:02x}",right,data);
-                self.program_counter.set((pc_value as isize +1) as u8);
-                self.set_register_data(right, data);
-            },
-            0b0110_1110 =>{ // LOAD IMMEDIATE TO C
-                op_str.push_str("ldi");
-
-                let data : u8 = self.read((pc_value as isize)+1);
-                self.program_counter.set((pc_value as isize +1) as u8);
-                ram_str = format!("RAM_R[{:02x}]={:02x}",(pc_value as isize +1) as u8,data);
-                left_str = format!("{:?}={:02x}",right,self.get_register_data(right));
-                reg_str = format!("{:?}={:02x}",right,data);
-                self.set_register_data(right, data);
-            },
-            0b0110_1111 =>{ // LOAD IMMEDIATE TO D
-                op_str.push_str("ldi");
-
-                let data : u8 = self.read((pc_value as isize)+1);
-                self.program_counter.set((pc_value as isize +1) as u8);
-                ram_str = format!("RAM_R[{:02x}]={:02x}",(pc_value as isize +1) as u8,data);
-                left_str = format!("{:?}={:02x}",right,self.get_register_data(right));
-                reg_str = format!("{:?}={:02x}",right,data);
-                self.set_register_data(right, data);
-            },
-            _=>{
-                let instruction_head = (instruction >> 4) as u8;
-                if instruction_head == 0b0111{ // LOAD X = RAM[Y]
-
-                    op_str.push_str("load");
-
-
-                    left_str = format!("{:?}={:02x}",left,self.get_register_data(left));
-                    right_str = format!(",{:?}={:02x}",right,self.get_register_data(right));
-
-                    let ram_addr = self.get_register_data(right) as isize;
-                    let data = self.read(ram_addr);
-                    ram_str = format!("RAM_R[{:02x}]={:02x}",self.get_register_data(right) as isize,data);
-                    self.set_register_data(left,data);
-
-                    reg_str = format!("{:?}={:02x}",left,data);
-
-
-                }else if instruction_head == 0b0101{ // COPY REGISTER X=Y
-                    op_str.push_str("cpr");
-
-                    left_str = format!("{:?}={:02x}",left,self.get_register_data(left));
-                    right_str = format!(",{:?}={:02x}",right,self.get_register_data(right));
-                    self.set_register_data(left,self.get_register_data(right));
-
-                    reg_str = format!("{:?}={:02x}",left,self.get_register_data(left));
-
-                }else if instruction_head == 0b1000 { // ADD X = X+Y
-                    op_str.push_str("add");
-
-                    left_str = format!("{:?}={:02x}",left,self.get_register_data(left));
-                    right_str = format!(",{:?}={:02x}",right,self.get_register_data(right));
-
-                    ALU::add(self,left,right);
-                    reg_str = format!("{:?}={:02x}",left,self.get_register_data(left));
-                }else if instruction_head == 0b1001 { // SUB X = X-Y
-                    op_str.push_str("sub");
-
-                    left_str = format!("{:?}={:02x}",left,self.get_register_data(left));
-                    right_str = format!(",{:?}={:02x}",right,self.get_register_data(right));
-
-                    ALU::sub(self,left,right);
-                    reg_str = format!("{:?}={:02x}",left,self.get_register_data(left));
-                }else if instruction_head == 0b1010 { // SHIFT RIGHT
-                    op_str.push_str("rsh");
-
-                    left_str = format!("{:?}={:02x}",left,self.get_register_data(left));
-                    right_str = format!(",{:?}={:02x}",right,self.get_register_data(right));
-
-                    ALU::right_shift(self,left , right);
-                    reg_str = format!("{:?}={:02x}",left,self.get_register_data(left));
-
-                }else if instruction_head == 0b1011 {
-
-                    let butt = (instruction & 0b0000_0011) as u8;
-
-                    if butt == 0 {           // NOT  X
-                        op_str.push_str("not");
-                        left_str = format!("{:?}={:02x}",left,self.get_register_data(left));
-                        ALU::not(self, left);
-                        reg_str = format!("{:?}={:02x}",left,self.get_register_data(left));
-                    }else if butt == 1 {     // JUMP TO X
-
-                        op_str.push_str("jmp");
-                        left_str = format!("{:?}={:02x}",left,self.get_register_data(left));
-                        let jmp_location = self.get_register_data(left) as isize;
-                        self.program_counter.set((jmp_location - 1) as u8);
-
-
-                    }else if butt == 2 {     // JUMP TO X IF CARRY
-
-                        op_str.push_str("jc");
-                        left_str = format!("{:?}={:02x}",left,self.get_register_data(left));
-                        let jmp_location = self.get_register_data(left) as isize;
-                        if self.flags.carry.get() {
-                            self.program_counter.set((jmp_location - 1) as u8);
-                        }
-
-                    }else if butt == 3 {     // JUMP TO X IF ZERO
-
-                        op_str.push_str("jz");
-                        left_str = format!("{:?}={:02x}",left,self.get_register_data(left));
-                        let jmp_location = self.get_register_data(left) as isize;
-                        if self.flags.zero.get() {
-                            self.program_counter.set((jmp_location - 1) as u8);
-                        }
-                    }
-
-                }else if instruction_head == 0b1100{  // AND X = X&Y
-                    op_str.push_str("and");
-
-                    left_str = format!("{:?}={:02x}",left,self.get_register_data(left));
-                    right_str = format!(",{:?}={:02x}",right,self.get_register_data(right));
-                    ALU::and(self,left,right);
-
-                    reg_str = format!("{:?}={:02x}",left,self.get_register_data(left));
-                }else if instruction_head == 0b1101{ //  OR  X = X|Y
-
-                    left_str = format!("{:?}={:02x}",left,self.get_register_data(left));
-                    if left == right{
-                        ALU::inc(self,left);
-                        op_str.push_str("inc");
-
-                    }else{
-                        right_str = format!(",{:?}={:02x}",right,self.get_register_data(right));
-                        op_str.push_str("or");
-                        ALU::or(self, left, right);
-                    }
-                    reg_str = format!("{:?}={:02x}",left,self.get_register_data(left));
-
-                }else if instruction_head == 0b1110{ // COMPARE X - Y
-
-
-                    if left == right { // DECREMENT
-                        op_str.push_str("dec");
-                        // no flag change
-                        left_str = format!("{:?}={:02x}",left,self.get_register_data(left));
-                        ALU::dec(self,left);
-                        reg_str = format!("{:?}={:02x}",left,self.get_register_data(left));
-
-                    }else{          // COMP CONT
-                        op_str.push_str("cmp");
-
-                        left_str = format!("{:?}={:02x}",left,self.get_register_data(left));
-                        right_str = format!(",{:?}={:02x}",right,self.get_register_data(right));
-
-                        ALU::cmp(self,left,right);
-                    }
-
-                }else if instruction_head == 0b1111{ // STORE RAM[Y]= X
-
-                    op_str.push_str("st");
-
-                    left_str = format!("{:?}={:02x}",right,self.get_register_data(right));
-                    right_str = format!(",{:?}={:02x}",left,self.get_register_data(left));
-
+        let instruction = self.fetch(pc_value as isize);
+
+        self.accesses.borrow_mut().clear();
+        let registers_before = self.get_registers();
+        let flags_before = self.flags.pack();
+        // decoded before running in case the instruction overwrites itself
+        let (decoded,text) = {
+            let isa = self.isa.borrow();
+            let decoded = disassembler::decode(&isa,&*self.ram.borrow(),pc_value);
+            let text = decoded.format(&isa,&self.labels.borrow());
+            (decoded,text)
+        };
+        let (op,size) = (decoded.op,decoded.size());
+        let writer = (0..size).find_map(|i| self.written_by.borrow()[pc_value.wrapping_add(i) as usize]);
+        if let Some(writer) = writer{
+            let message = format!("instruction at PC {} was written at runtime by the instruction at PC {}",self.location(pc_value),self.location(writer));
+            self.report_code_fault(pc_value,true,message);
+        }
 
-                    let store_addr = self.get_register_data(right) as isize;
-                    let data = self.get_register_data(left);
+        let next = {
+            let isa = self.isa.borrow();
+            let mut state = self;
+            machine::execute(&mut state,&isa,self.flag_profile.get())
+        };
 
-                    ram_str = format!("RAM_W[{:02x}]={:02x}",store_addr,data);
-                    self.write(store_addr, data as isize);
-                }
+        self.program_counter.set(next);
+        self.instruction_count.set(instruction_count +1);
 
+        self.record_profile(pc_value,op,size);
+        if let Some(entry) = self.pending_undo.borrow_mut().take(){
+            let mut history = self.history.borrow_mut();
+            if history.len() >= self.history_limit.get(){
+                history.pop_front();
+            }
+            if self.history_limit.get() > 0{
+                history.push_back(entry);
             }
-
-
         }
+        self.record_trace(instruction_count,pc_value,instruction,&text,registers_before,flags_before);
 
-        // increase pc -- wraps
-        self.program_counter.set((self.program_counter.get() as isize + 1) as u8);
-        self.instruction_count.set(instruction_count +1);
-
+        // the trace has always named ld load
+        let op_str = if op == Ops::Load {"load"} else {text.split(' ').next().unwrap_or("")};
+        let (left_str,right_str,reg_str,ram_str) = self.trace_operands(&decoded,registers_before);
 
         let c = self.flags.carry.get();
         let z = self.flags.zero.get();
@@ -837,6 +1141,200 @@ impl VirtualMachine{
 
 
 
+    /// count the instruction, which way a conditional jump went and any jump backwards (a loop iteration)
+    fn record_profile(&self, pc : u8, op : Ops, size : u8){
+        self.hits.borrow_mut()[pc as usize]+=1;
+        let next = self.program_counter.get();
+        if op.is_conditional_jump(){
+            let fall_through = next == pc.wrapping_add(size);
+            self.mark_coverage(pc,if fall_through {coverage::NOT_TAKEN} else {coverage::TAKEN});
+        }
+        if next <= pc && !self.halt.get(){
+            if let Some(entry) = self.pending_undo.borrow_mut().as_mut(){
+                entry.back_edge = Some((pc,next));
+            }
+            let mut back_edges = self.back_edges.borrow_mut();
+            match back_edges.iter_mut().find(|(from,to,_)| *from == pc && *to == next){
+                Some(edge) => edge.2+=1,
+                None => back_edges.push((pc,next,1))
+            }
+        }
+    }
+
+    /// copy of the complete machine state
+    pub fn save_state(&self)->Snapshot{
+        Snapshot{
+            pc                : self.program_counter.get(),
+            registers         : self.get_registers(),
+            flags             : self.flags.pack(),
+            halt              : self.halt.get(),
+            instruction_count : self.instruction_count.get(),
+            mode              : self.mode.get(),
+            program_edge      : self.program_edge.get(),
+            ram               : *self.ram.borrow()
+        }
+    }
+
+    /// put the machine back into a saved state
+    /// the undo history and runtime writes are dropped since they belong to another timeline
+    pub fn restore_state(&self, snapshot : &Snapshot){
+        self.program_counter.set(snapshot.pc);
+        self.set_register_data(Register::A,snapshot.registers[0]);
+        self.set_register_data(Register::B,snapshot.registers[1]);
+        self.set_register_data(Register::C,snapshot.registers[2]);
+        self.set_register_data(Register::D,snapshot.registers[3]);
+        self.flags.unpack(snapshot.flags);
+        self.halt.set(snapshot.halt);
+        self.instruction_count.set(snapshot.instruction_count);
+        self.mode.set(snapshot.mode);
+        self.program_edge.set(snapshot.program_edge);
+        self.ram.replace(snapshot.ram);
+        self.history.borrow_mut().clear();
+        // writes and warnings from the abandoned timeline no longer apply
+        self.written_by.replace([None;256]);
+        self.reported.borrow_mut().clear();
+    }
+
+    /// what happened to each byte of the loaded program since load
+    pub fn get_coverage(&self)->Coverage{
+        let program = self.initial_ram.borrow()[..=self.program_edge.get() as usize].to_vec();
+        Coverage::create(program,*self.hits.borrow(),*self.coverage.borrow())
+    }
+
+    /// execution counts per label, the hottest instructions and how often each loop repeated
+    /// @param top number of hottest instructions to list
+    pub fn profile_report(&self, top : usize)->String{
+        let hits = self.hits.borrow();
+        let total : usize = hits.iter().sum();
+        let percent = |count : usize| if total == 0 {0.0} else {count as f64 * 100.0 / total as f64};
+        let isa = self.isa.borrow();
+        let ram = self.ram.borrow();
+        let labels = self.labels.borrow();
+        let location = |addr : u8| self.label_at(addr).unwrap_or_default();
+
+        let mut ret_string = format!("PROFILE: {} instructions\n",total);
+
+        // every address counts towards the closest label before it
+        let mut sorted : Vec<&(String,u8)> = labels.iter().filter(|(_,a)| *a <= self.program_edge.get()).collect();
+        sorted.sort_by_key(|(_,a)| *a);
+        let mut per_label : Vec<(String,usize)> = Vec::new();
+        let start = sorted.first().map(|(_,a)| *a as usize).unwrap_or(256);
+        if hits[..start].iter().any(|h| *h > 0){
+            per_label.push((String::from("<start>"),hits[..start].iter().sum()));
+        }
+        for (i,(label,addr)) in sorted.iter().enumerate(){
+            let end = sorted.get(i + 1).map(|(_,a)| *a as usize).unwrap_or(256);
+            per_label.push((label.clone(),hits[*addr as usize..end].iter().sum()));
+        }
+        per_label.sort_by_key(|(_,count)| std::cmp::Reverse(*count));
+
+        ret_string.push_str(format!("\n{:<20}{:>10}{:>8}\n","LABEL","COUNT","%").as_str());
+        for (label,count) in per_label.iter().filter(|(_,c)| *c > 0){
+            ret_string.push_str(format!("{:<20}{:>10}{:>7.1}%\n",label,count,percent(*count)).as_str());
+        }
+
+        let mut hottest : Vec<(usize,usize)> = hits.iter().copied().enumerate().filter(|(_,h)| *h > 0).collect();
+        hottest.sort_by(|a,b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        ret_string.push_str(format!("\n{:<6}{:>10}{:>8}  {:<16}{}\n","ADDR","COUNT","%","INSTRUCTION","LABEL").as_str());
+        for (addr,count) in hottest.iter().take(top){
+            let text = disassembler::decode(&isa,&*ram,*addr as u8).format(&isa,&labels);
+            ret_string.push_str(format!("{:02X}    {:>10}{:>7.1}%  {:<16}{}\n",addr,count,percent(*count),text,location(*addr as u8)).as_str());
+        }
+
+        let back_edges = self.back_edges.borrow();
+        if !back_edges.is_empty(){
+            ret_string.push_str("\nLOOPS:\n");
+            for (from,to,taken) in back_edges.iter(){
+                let text = disassembler::decode(&isa,&*ram,*from).format(&isa,&labels);
+                ret_string.push_str(format!("{:02X} {:<16} -> {:02X} {:<12} repeated {} times\n",from,text,to,location(*to),taken).as_str());
+            }
+        }
+
+        ret_string
+    }
+
+    /// build the trace entry of the instruction that just ran and write it out
+    fn record_trace(&self, count : usize, pc : u8, opcode : u8, text : &str, registers_before : [u8;4], flags_before : u8){
+        let (mnemonic,operands) = text.split_once(' ').unwrap_or((text,""));
+        let accesses = self.accesses.borrow();
+        let of_kind = |kind : Access| accesses.iter().filter(|(a,_,_)| *a == kind).map(|(_,addr,value)| (*addr,*value)).collect();
+
+        let entry = TraceEntry{
+            count,
+            pc,
+            opcode,
+            mnemonic         : String::from(mnemonic),
+            operands         : String::from(operands),
+            registers_before,
+            registers_after  : self.get_registers(),
+            flags_before,
+            flags_after      : self.flags.pack(),
+            next_pc          : self.program_counter.get(),
+            reads            : of_kind(Access::Read),
+            writes           : of_kind(Access::Write)
+        };
+
+        let mut writer = self.trace_writer.borrow_mut();
+        if let Some(w) = writer.as_mut(){
+            if let Err(e) = w.write(&entry){
+                self.trace_error.replace(Some(e));
+                *writer = None;
+            }
+        }
+        self.last_trace.replace(Some(entry));
+    }
+
+    /// the registers the instruction names with their values before it ran, the register it set
+    /// and the ram it touched for the trace line
+    fn trace_operands(&self, decoded : &Decoded, before : [u8;4])->(String,String,String,String){
+        let isa = self.isa.borrow();
+        let (x,y) = (decoded.x,decoded.y);
+        let (operands,sets_x) : (Vec<Register>,bool) = match decoded.op{
+            Ops::NoOp | Ops::Halt | Ops::Byte | Ops::Jumpi | Ops::JumpLessi | Ops::JumpOverflowi |
+            Ops::JumpSigni | Ops::JumpCarryi | Ops::JumpZeroi => (Vec::new(),false),
+            Ops::JumpLess | Ops::JumpOverflow | Ops::JumpSign |
+            Ops::Jump | Ops::JumpCarry | Ops::JumpZero => (vec![x],false),
+            Ops::Loadi | Ops::Not | Ops::Increment | Ops::Decrement => (vec![x],true),
+            Ops::Compare | Ops::Store => (vec![x,y],false),
+            Ops::Custom{..} =>{
+                let operands = isa.get_extension(decoded.op).map(|ext| ext.operands.clone()).unwrap_or_default();
+                let registers = operands.iter().filter_map(|o| match o{
+                    Var::X => Some(x),
+                    Var::Y => Some(y),
+                    _=> None
+                }).collect();
+                (registers,operands.contains(&Var::X))
+            },
+            _=> (vec![x,y],true)
+        };
+
+        let register = |r : &Register, value : u8| format!("{:?}={:02x}",r,value);
+        let left_str = operands.first().map(|r| register(r,before[r.bits() as usize])).unwrap_or_default();
+        let right_str = operands.get(1).map(|r| format!(",{}",register(r,before[r.bits() as usize]))).unwrap_or_default();
+        let reg_str = if sets_x {register(&x,self.get_register_data(x))} else {String::new()};
+        // extensions only show the last byte they wrote, not what they read
+        let custom = matches!(decoded.op,Ops::Custom{..});
+        let accesses = self.accesses.borrow();
+        let ram_str = match accesses.iter().rev().find(|(access,_,_)| !custom || *access == Access::Write){
+            Some((Access::Write,addr,value)) => format!("RAM_W[{:02x}]={:02x}",addr,value),
+            Some((Access::Read,addr,value)) => format!("RAM_R[{:02x}]={:02x}",addr,value),
+            None =>{
+                // a conditional jump only shows its target when it is taken
+                let flags = &self.flags;
+                let taken = match decoded.op{
+                    Ops::JumpLessi     => flags.less_than.get(),
+                    Ops::JumpOverflowi => flags.overflow.get(),
+                    Ops::JumpSigni     => flags.sign.get(),
+                    Ops::JumpCarryi    => flags.carry.get(),
+                    Ops::JumpZeroi     => flags.zero.get(),
+                    _=> true
+                };
+                decoded.immediate.filter(|_| taken).map(|imm| format!("RAM_R[{:02x}]={:02x}",decoded.addr.wrapping_add(1),imm)).unwrap_or_default()
+            }
+        };
+        (left_str,right_str,reg_str,ram_str)
+    }
+
     fn get_wrapped_value(value: isize)->usize{
         let max = 256;
         let mut _result : usize = 0_usize;
@@ -852,3 +1350,63 @@ impl VirtualMachine{
         _result
     }
 }
+
+/// registers, flags and ram for breakpoint conditions
+impl Environment for VirtualMachine{
+    fn value(&self, var : Var)->isize{
+        match var{
+            Var::Reg(r) => self.get_register_data(r) as isize,
+            Var::Flag(flag) => self.flags.get(flag) as isize,
+            Var::Pc => self.program_counter.get() as isize,
+            Var::Halt => self.halt.get() as isize,
+            _=> 0
+        }
+    }
+
+    fn ram(&self, addr : u8)->u8{
+        self.fetch(addr as isize)
+    }
+}
+
+/// the execution core runs on the vm through its devices, watchpoints and undo log
+impl machine::State for &VirtualMachine{
+    fn get_register(&self, register : Register)->u8{
+        self.get_register_data(register)
+    }
+
+    fn set_register(&mut self, register : Register, data : u8){
+        self.set_register_data(register,data);
+    }
+
+    fn get_flags(&self)->u8{
+        self.flags.pack()
+    }
+
+    fn set_flags(&mut self, flags : u8){
+        self.flags.unpack(flags);
+    }
+
+    fn get_pc(&self)->u8{
+        self.program_counter.get()
+    }
+
+    fn is_halted(&self)->bool{
+        self.halt.get()
+    }
+
+    fn set_halted(&mut self, halt : bool){
+        self.halt.set(halt);
+    }
+
+    fn fetch(&self, addr : u8)->u8{
+        VirtualMachine::fetch(self,addr as isize)
+    }
+
+    fn read(&self, addr : u8)->u8{
+        VirtualMachine::read(self,addr as isize)
+    }
+
+    fn write(&mut self, addr : u8, data : u8){
+        VirtualMachine::write(self,addr as isize,data as isize);
+    }
+}